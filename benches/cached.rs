@@ -0,0 +1,26 @@
+extern crate notlex;
+
+mod common;
+
+use notlex::*;
+use notlex::charset::CachedCharSet;
+
+use common::time;
+
+// Tests a run of 10k spaces against a whitespace class, with and without caching the last result.
+fn main() {
+    let whitespace = CharSet::from_class_string("[\\t\\n\\r ]").unwrap();
+    let cached = CachedCharSet::new(whitespace.clone());
+    let input = vec![' '; 10_000];
+    let n = 1000;
+
+    let (uncached_matches, uncached_elapsed) = time(|| {
+        (0 .. n).map(|_| input.iter().filter(|c| whitespace.test(**c)).count()).sum::<usize>()
+    });
+    let (cached_matches, cached_elapsed) = time(|| {
+        (0 .. n).map(|_| input.iter().filter(|c| cached.test(**c)).count()).sum::<usize>()
+    });
+
+    assert_eq!(uncached_matches, cached_matches);
+    println!("{} chars, test: {:?}, cached: {:?}", n * input.len(), uncached_elapsed, cached_elapsed);
+}
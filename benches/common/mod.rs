@@ -0,0 +1,22 @@
+// Helpers shared by the benchmarks. Each benchmark includes this with `mod common;`.
+
+#![allow(dead_code)]
+
+use std::time::{Duration, Instant};
+
+use notlex::NFA;
+
+/// Returns the result of `f` and how long it took.
+pub fn time<T, F: FnOnce() -> T>(f: F) -> (T, Duration) {
+    let start = Instant::now();
+    let ret = f();
+    (ret, start.elapsed())
+}
+
+/// Resets and runs the automaton `n` times, cycling through `inputs`, and prints the time it took.
+pub fn time_runs(nfa: &mut NFA, inputs: &[&str], n: usize) {
+    let (matches, elapsed) = time(|| {
+        (0 .. n).filter(|i| { nfa.reset(); nfa.run(inputs[i % inputs.len()].chars()) }).count()
+    });
+    println!("{} inputs, {} matches, {:?} ({:?} per input)", n, matches, elapsed, elapsed / n as u32);
+}
@@ -0,0 +1,42 @@
+extern crate notlex;
+
+mod common;
+
+use notlex::*;
+
+use common::time;
+
+// Compares running an NFA, a lazy DFA built from it, and a fully determinized automaton on
+// email-like addresses.
+fn main() {
+    let word = Regex::plus(Regex::char_class(&[('a', 'z'), ('0', '9')], false));
+    let local = Regex::plus(Regex::char_class(&[('a', 'z'), ('0', '9'), ('.', '.'), ('_', '_')], false));
+    let domain = Regex::seq(word.clone(), Regex::plus(Regex::seq(Regex::literal("."), word)));
+    let regex = Regex::seq(local, Regex::seq(Regex::literal("@"), domain));
+
+    let inputs = ["john.smith@example.com", "jane_doe42@mail.example.co.uk", "not an address",
+                  "missing@tld", "a@b.c", "first.last@sub.domain.example.org"];
+    let n = 100_000;
+
+    let mut nfa = regex.to_nfa();
+    let (nfa_matches, nfa_elapsed) = time(|| {
+        (0 .. n).filter(|i| { nfa.reset(); nfa.run(inputs[i % inputs.len()].chars()) }).count()
+    });
+
+    let mut lazy = regex.to_nfa().determinize_lazy();
+    let (lazy_matches, lazy_elapsed) = time(|| {
+        (0 .. n).filter(|i| { lazy.reset(); lazy.run(inputs[i % inputs.len()].chars()) }).count()
+    });
+
+    let (mut dfa, determinize_elapsed) = time(|| regex.to_nfa().determinize());
+    let (dfa_matches, dfa_elapsed) = time(|| {
+        (0 .. n).filter(|i| { dfa.reset(); dfa.run(inputs[i % inputs.len()].chars()) }).count()
+    });
+
+    assert_eq!(nfa_matches, lazy_matches);
+    assert_eq!(nfa_matches, dfa_matches);
+    println!("{} inputs, {} matches", n, nfa_matches);
+    println!("NFA: {:?}", nfa_elapsed);
+    println!("lazy DFA: {:?} ({} states)", lazy_elapsed, lazy.num_states());
+    println!("full DFA: {:?} (+ {:?} to determinize)", dfa_elapsed, determinize_elapsed);
+}
@@ -0,0 +1,25 @@
+extern crate notlex;
+
+mod common;
+
+use std::char;
+
+use notlex::*;
+
+use common::time;
+
+// Compares testing chars against a `Diff` with a large `exclude` directly and via
+// `CharSet::to_intervals`.
+fn main() {
+    let exclude = CharSet::Union((0 .. 1000).map(|i| CharSet::SingleChar(char::from_u32(0x100 + i * 2).unwrap())).collect());
+    let diff = CharSet::Diff { include: Box::new(CharSet::AnyChar), exclude: Box::new(exclude) };
+    let chars: Vec<char> = (0 .. 0x1000).filter_map(char::from_u32).collect();
+
+    let (naive, naive_elapsed) = time(|| chars.iter().filter(|c| diff.test(**c)).count());
+    let (intervals, precompute_elapsed) = time(|| diff.to_intervals());
+    let (precomputed, precomputed_elapsed) = time(|| chars.iter().filter(|c| intervals.test(**c)).count());
+
+    assert_eq!(naive, precomputed);
+    println!("{} chars, naive: {:?}, intervals: {:?} (+ {:?} to precompute)",
+             chars.len(), naive_elapsed, precomputed_elapsed, precompute_elapsed);
+}
@@ -0,0 +1,12 @@
+extern crate notlex;
+
+mod common;
+
+use notlex::*;
+
+// Runs an NFA for a literal, which has no epsilon transitions, over many inputs. Stepping doesn't
+// need to compute epsilon closures for such automata.
+fn main() {
+    let mut nfa = Regex::literal("hello_world").to_nfa();
+    common::time_runs(&mut nfa, &["hello_world", "hello", "hello_worlds", "help"], 100_000);
+}
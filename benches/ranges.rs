@@ -0,0 +1,50 @@
+extern crate notlex;
+
+mod common;
+
+use std::char;
+use std::collections::HashMap;
+
+use notlex::*;
+
+use common::time;
+
+// Steps from a state with 100 disjoint range transitions, and compares it with finding the
+// targets by testing every transition's charset.
+fn main() {
+    let transitions: Vec<(CharSet, usize)> = (0 .. 100).map(|i| {
+        let lo = char::from_u32(0x100 + i * 16).unwrap();
+        let hi = char::from_u32(0x100 + i * 16 + 7).unwrap();
+        (CharSet::Range { lo: lo, hi: hi }, i as usize + 1)
+    }).collect();
+    let accepting: HashMap<usize, usize> = (1 .. 101).map(|s| (s, 0)).collect();
+    let mut trans_map = HashMap::new();
+    trans_map.insert(0, transitions.clone());
+    let mut nfa = NFA::new(trans_map, accepting);
+
+    let chars: Vec<char> = (0x100 .. 0x100 + 100 * 16).filter_map(char::from_u32).collect();
+    let n = 100;
+
+    let (indexed, indexed_elapsed) = time(|| {
+        let mut accepted = 0;
+        for _ in 0 .. n {
+            for c in &chars {
+                nfa.reset();
+                nfa.feed(*c);
+                if nfa.check_accepting() {
+                    accepted += 1;
+                }
+            }
+        }
+        accepted
+    });
+
+    let (naive, naive_elapsed) = time(|| {
+        (0 .. n).map(|_| chars.iter().filter(|c| transitions.iter().any(|&(ref cs, _)| cs.test(**c))).count())
+                .sum::<usize>()
+    });
+
+    assert_eq!(indexed, naive);
+    println!("{} steps, {} accepted, indexed: {:?}, testing every transition: {:?}",
+             n * chars.len(), indexed, indexed_elapsed, naive_elapsed);
+}
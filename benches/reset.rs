@@ -0,0 +1,12 @@
+extern crate notlex;
+
+mod common;
+
+use notlex::*;
+
+// Resets and runs an NFA on many tiny inputs, to measure the per-input overhead of `reset`.
+fn main() {
+    let regex = Regex::star(Regex::or(Regex::literal("ab"), Regex::literal("ac")));
+    let mut nfa = regex.to_nfa();
+    common::time_runs(&mut nfa, &["", "ab", "ac", "abac", "abc", "a"], 100_000);
+}
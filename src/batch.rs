@@ -0,0 +1,26 @@
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use NFA;
+
+impl<D: Clone + Send + Sync> NFA<D> {
+    /// Runs the automaton (from its start state) on each of the inputs. With the `parallel`
+    /// feature the inputs are run in parallel, each thread on its own copy of the automaton.
+    #[cfg(feature = "parallel")]
+    pub fn run_batch(&self, inputs: &[&str]) -> Vec<bool> {
+        inputs.par_iter().map_init(|| self.clone(), |nfa, input| run_one(nfa, input)).collect()
+    }
+
+    /// Runs the automaton (from its start state) on each of the inputs. With the `parallel`
+    /// feature the inputs are run in parallel, each thread on its own copy of the automaton.
+    #[cfg(not(feature = "parallel"))]
+    pub fn run_batch(&self, inputs: &[&str]) -> Vec<bool> {
+        let mut nfa = self.clone();
+        inputs.iter().map(|input| run_one(&mut nfa, input)).collect()
+    }
+}
+
+fn run_one<D>(nfa: &mut NFA<D>, input: &str) -> bool {
+    nfa.reset();
+    nfa.run(input.chars())
+}
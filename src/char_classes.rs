@@ -0,0 +1,159 @@
+use std::char;
+use std::collections::HashMap;
+
+use {CharSet, NFA};
+
+/// A partition of chars into classes that the transitions of an automaton can't tell apart: all
+/// chars in a class have the same transitions from every state. Classes are intervals, numbered
+/// from 0 in the order of their chars. See `NFA::build_class_table`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CharClasses {
+    // Sorted first chars of the classes, starting with '\0'
+    starts: Vec<char>,
+}
+
+impl CharClasses {
+    /// Returns the coarsest classes that none of the sets can tell apart. `Epsilon`s are ignored.
+    pub fn from_charsets<'a, I: Iterator<Item = &'a CharSet>>(sets: I) -> CharClasses {
+        let mut starts: Vec<char> = vec!['\0'];
+        for cs in sets {
+            if *cs == CharSet::Epsilon {
+                continue;
+            }
+            for (lo, hi) in cs.to_ranges() {
+                starts.push(lo);
+                // The char after `hi`, skipping surrogates
+                match hi as u32 {
+                    0xD7FF => starts.push('\u{E000}'),
+                    hi => if let Some(c) = char::from_u32(hi + 1) { starts.push(c) },
+                }
+            }
+        }
+        starts.sort();
+        starts.dedup();
+        CharClasses { starts: starts }
+    }
+
+    /// Returns the classes for the transitions of all of the automata, for running the same input
+    /// on them with `ClassifiedInput`.
+    pub fn from_nfas<D>(nfas: &[&NFA<D>]) -> CharClasses {
+        CharClasses::from_charsets(nfas.iter().flat_map(|nfa| nfa.transitions.values().flat_map(|ts| ts.iter().map(|&(ref cs, _)| cs))))
+    }
+
+    /// Number of classes.
+    pub fn num_classes(&self) -> usize {
+        self.starts.len()
+    }
+
+    pub fn class_of(&self, c: char) -> usize {
+        match self.starts.binary_search(&c) {
+            Ok(idx) => idx,
+            Err(idx) => idx - 1,
+        }
+    }
+
+    /// Returns a char in the class.
+    pub fn representative(&self, class: usize) -> char {
+        self.starts[class]
+    }
+
+    /// Returns the first and last chars of the class.
+    pub fn range(&self, class: usize) -> (char, char) {
+        let hi = match self.starts.get(class + 1) {
+            None => char::MAX,
+            Some(&'\u{E000}') => '\u{D7FF}',
+            Some(&next) => char::from_u32(next as u32 - 1).unwrap(),
+        };
+        (self.starts[class], hi)
+    }
+}
+
+/// Input with each char replaced by its class, so that the chars are classified once when the same
+/// input is run on several automata with `NFA::run_classified`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassifiedInput {
+    classes: Vec<usize>,
+}
+
+impl ClassifiedInput {
+    pub fn new(classes: &CharClasses, input: &str) -> ClassifiedInput {
+        ClassifiedInput { classes: input.chars().map(|c| classes.class_of(c)).collect() }
+    }
+}
+
+impl<D> NFA<D> {
+    /// Computes the char classes of the automaton, and a transition table indexed by the classes
+    /// for `step_class`. The table needs memory proportional to the number of states times the
+    /// number of classes.
+    pub fn build_class_table(&mut self) -> CharClasses {
+        let classes = CharClasses::from_nfas(&[&*self]);
+        self.build_class_table_for(&classes);
+        classes
+    }
+
+    /// Like `build_class_table`, but for the given classes, which need to be at least as fine as
+    /// the automaton's own classes (e.g. `CharClasses::from_nfas` with this automaton among the
+    /// automata). This is for sharing classes, and so classified input, between automata.
+    pub fn build_class_table_for(&mut self, classes: &CharClasses) {
+        let mut table = HashMap::with_capacity(self.range_index.len());
+        for (state, index) in self.range_index.iter() {
+            let targets: Vec<Vec<usize>> =
+                (0 .. classes.num_classes()).map(|class| index.targets(classes.representative(class)).to_vec()).collect();
+            table.insert(*state, targets);
+        }
+        self.class_table = Some(table);
+    }
+
+    /// Partitions the chars into equivalence classes: chars that every charset in the transitions
+    /// either contains or doesn't contain together. Unlike `CharClasses` the classes are not
+    /// necessarily intervals, e.g. with only `[a-z]` the chars before and after it are in the same
+    /// class. Returns the intervals of chars in order with their class ids, and the number of
+    /// classes. Classes are numbered in the order of their first chars.
+    pub fn alphabet_classes(&self) -> (Vec<(char, char, usize)>, usize) {
+        let sets: Vec<&CharSet> = self.transitions.values()
+            .flat_map(|ts| ts.iter().map(|&(ref cs, _)| cs))
+            .filter(|cs| **cs != CharSet::Epsilon)
+            .collect();
+        let intervals = CharClasses::from_charsets(sets.iter().cloned());
+
+        let mut class_ids: HashMap<Vec<bool>, usize> = HashMap::new();
+        let mut ranges = Vec::with_capacity(intervals.num_classes());
+        for interval in 0 .. intervals.num_classes() {
+            let c = intervals.representative(interval);
+            let signature: Vec<bool> = sets.iter().map(|cs| cs.test(c)).collect();
+            let next_id = class_ids.len();
+            let class = *class_ids.entry(signature).or_insert(next_id);
+            let (lo, hi) = intervals.range(interval);
+            ranges.push((lo, hi, class));
+        }
+
+        (ranges, class_ids.len())
+    }
+
+    /// Like `feed`, but takes the class of the char (as returned by `CharClasses::class_of`)
+    /// instead of the char, and finds the transitions with an index into the class table instead
+    /// of testing charsets. Panics if `build_class_table` wasn't called.
+    pub fn step_class(&mut self, class: usize) {
+        let mut new_states: Vec<usize> = Vec::with_capacity(self.cur_states.len());
+        {
+            let table = self.class_table.as_ref().expect("NFA::step_class: class table is not built");
+            for state in self.cur_states.iter() {
+                if let Some(targets) = table.get(state) {
+                    new_states.extend(&targets[class]);
+                }
+            }
+        }
+        self.cur_states.clear();
+        self.cur_states.extend(new_states);
+        self.take_epsilons();
+    }
+
+    /// Like `run`, but for classified input. Panics if the class table wasn't built (see
+    /// `build_class_table_for`).
+    pub fn run_classified(&mut self, input: &ClassifiedInput) -> bool {
+        for class in input.classes.iter() {
+            self.step_class(*class);
+        }
+        self.check_accepting()
+    }
+}
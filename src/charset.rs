@@ -1,4 +1,14 @@
-#[derive(Clone, Debug, PartialEq, Eq)]
+use std::cell::Cell;
+use std::char;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::Peekable;
+use std::ops::RangeInclusive;
+use std::str::CharIndices;
+use std::sync::Arc;
+
+use range_index::find_range;
+
 pub enum CharSet {
     SingleChar(char),
 
@@ -14,10 +24,164 @@ pub enum CharSet {
     Union(Vec<CharSet>),
 
     Epsilon,
+
+    /// Chars for which the function returns true. An escape hatch for sets that can't be written
+    /// with the other variants. Functions are opaque: two predicates are only equal when they're
+    /// clones of the same `Arc`.
+    Predicate(Arc<dyn Fn(char) -> bool + Send + Sync>),
+}
+
+// `Predicate` can't derive the traits below
+
+impl Clone for CharSet {
+    fn clone(&self) -> CharSet {
+        match self {
+            &CharSet::SingleChar(c) => CharSet::SingleChar(c),
+            &CharSet::Range { lo, hi } => CharSet::Range { lo: lo, hi: hi },
+            &CharSet::AnyChar => CharSet::AnyChar,
+            &CharSet::Diff { ref include, ref exclude } =>
+                CharSet::Diff { include: include.clone(), exclude: exclude.clone() },
+            &CharSet::Union(ref css) => CharSet::Union(css.clone()),
+            &CharSet::Epsilon => CharSet::Epsilon,
+            &CharSet::Predicate(ref f) => CharSet::Predicate(f.clone()),
+        }
+    }
+}
+
+impl PartialEq for CharSet {
+    fn eq(&self, other: &CharSet) -> bool {
+        match (self, other) {
+            (&CharSet::SingleChar(c1), &CharSet::SingleChar(c2)) => c1 == c2,
+            (&CharSet::Range { lo: lo1, hi: hi1 }, &CharSet::Range { lo: lo2, hi: hi2 }) =>
+                lo1 == lo2 && hi1 == hi2,
+            (&CharSet::AnyChar, &CharSet::AnyChar) => true,
+            (&CharSet::Diff { include: ref include1, exclude: ref exclude1 },
+             &CharSet::Diff { include: ref include2, exclude: ref exclude2 }) =>
+                include1 == include2 && exclude1 == exclude2,
+            (&CharSet::Union(ref css1), &CharSet::Union(ref css2)) => css1 == css2,
+            (&CharSet::Epsilon, &CharSet::Epsilon) => true,
+            (&CharSet::Predicate(ref f1), &CharSet::Predicate(ref f2)) => Arc::ptr_eq(f1, f2),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for CharSet {}
+
+/// Renders the set with `to_class_string`. `Epsilon` is rendered as `ε`.
+impl fmt::Display for CharSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &CharSet::Epsilon => write!(f, "ε"),
+            cs => write!(f, "{}", cs.to_class_string()),
+        }
+    }
+}
+
+/// Hashes the chars matched by the set (as in `to_ranges`), so that sets with the same canonical
+/// form (see `CharSet::canonical`) have the same hash. This is consistent with `==`, which is
+/// structural and so finer. Hashing needs to resolve `Diff`s and `Predicate`s into intervals.
+impl Hash for CharSet {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            // `to_ranges` treats it as `AnyChar`, but it's not a set of chars
+            &CharSet::Epsilon => state.write_u8(0),
+            cs => cs.to_ranges().hash(state),
+        }
+    }
+}
+
+impl fmt::Debug for CharSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &CharSet::SingleChar(c) => f.debug_tuple("SingleChar").field(&c).finish(),
+            &CharSet::Range { lo, hi } => f.debug_struct("Range").field("lo", &lo).field("hi", &hi).finish(),
+            &CharSet::AnyChar => f.write_str("AnyChar"),
+            &CharSet::Diff { ref include, ref exclude } =>
+                f.debug_struct("Diff").field("include", include).field("exclude", exclude).finish(),
+            &CharSet::Union(ref css) => f.debug_tuple("Union").field(css).finish(),
+            &CharSet::Epsilon => f.write_str("Epsilon"),
+            &CharSet::Predicate(_) => f.write_str("Predicate(..)"),
+        }
+    }
+}
+
+impl From<char> for CharSet {
+    fn from(c: char) -> CharSet {
+        CharSet::SingleChar(c)
+    }
+}
+
+impl From<(char, char)> for CharSet {
+    fn from((lo, hi): (char, char)) -> CharSet {
+        CharSet::Range { lo: lo, hi: hi }
+    }
+}
+
+/// An empty range (e.g. `'9' ..= '0'`) gives the empty set.
+impl From<RangeInclusive<char>> for CharSet {
+    fn from(range: RangeInclusive<char>) -> CharSet {
+        let (lo, hi) = (*range.start(), *range.end());
+        if lo > hi {
+            CharSet::Union(vec![])
+        } else {
+            CharSet::Range { lo: lo, hi: hi }
+        }
+    }
+}
+
+/// Errors of `CharSet::from_class_string`. Offsets are in bytes.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// Input ended before the closing `]`.
+    UnexpectedEnd,
+
+    /// A char that can't appear at the given offset, e.g. a missing `[` or trailing input after
+    /// the closing `]`.
+    UnexpectedChar { offset: usize, c: char },
+
+    /// A range whose lower bound is larger than its upper bound.
+    InvalidRange { offset: usize },
+
+    /// An unknown escape sequence, or a `\x`/`\u` escape that isn't a valid char.
+    InvalidEscape { offset: usize },
+
+    /// A backreference like `\1`. Backreferences can't be expressed with finite automata, so
+    /// they're rejected rather than treated as some other escape.
+    UnsupportedBackreference { offset: usize },
+}
+
+/// Errors of the charset grammar in `charset_parser`, reported as `lalrpop_util::ParseError::User`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum GrammarError {
+    /// A `\x` or `\u` escape that isn't a valid char, e.g. `\u{D800}`. `offset` is the byte
+    /// offset of the backslash, `ch` is the char after it.
+    InvalidEscape { offset: usize, ch: char },
+}
+
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &GrammarError::InvalidEscape { offset, ch } => write!(f, "invalid escape \\{} at offset {}", ch, offset),
+        }
+    }
+}
+
+/// Errors of `CharSet` constructors that take code points.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CharSetError {
+    /// A code point in the surrogate range `0xD800 ..= 0xDFFF`.
+    Surrogate(u32),
+
+    /// A code point larger than `0x10FFFF`.
+    OutOfRange(u32),
 }
 
 impl CharSet {
     pub fn test(&self, c: char) -> bool {
+        #[cfg(feature = "profile")]
+        ::profile::record_test(self);
+
         match self {
 
             &CharSet::SingleChar(c1) => c1 == c,
@@ -38,6 +202,700 @@ impl CharSet {
             }
 
             &CharSet::Epsilon => true,
+
+            &CharSet::Predicate(ref f) => f(c),
+        }
+    }
+
+    /// Explains the result of `test` for debugging: lists, for each member of a `Union` or `Diff`,
+    /// whether `c` is in it, recursively. E.g. for `a` or a digit and `'x'`:
+    /// `'x' in Union: not in SingleChar('a'); not in Range('0'-'9'); => false`. Unlike `test`
+    /// this doesn't stop at the first matching member of a `Union`.
+    pub fn explain(&self, c: char) -> String {
+        let result = self.test(c);
+        match self.explain_members(c) {
+            None => format!("{:?} in {} => {}", c, self.explain_set(c), result),
+            Some(steps) => {
+                let steps: String = steps.iter().map(|step| format!("{}; ", step)).collect();
+                format!("{:?} in {}: {}=> {}", c, self.explain_kind(), steps, result)
+            }
+        }
+    }
+
+    // Describes the set for `explain`: the variant, with the decisions for the members of a
+    // `Union` or `Diff` in parens.
+    fn explain_set(&self, c: char) -> String {
+        match self.explain_members(c) {
+            None => self.explain_kind(),
+            Some(steps) => format!("{}({})", self.explain_kind(), steps.join("; ")),
+        }
+    }
+
+    fn explain_kind(&self) -> String {
+        match self {
+            &CharSet::SingleChar(c) => format!("SingleChar({:?})", c),
+            &CharSet::Range { lo, hi } => format!("Range({:?}-{:?})", lo, hi),
+            &CharSet::AnyChar => "AnyChar".to_owned(),
+            &CharSet::Diff { .. } => "Diff".to_owned(),
+            &CharSet::Union(_) => "Union".to_owned(),
+            &CharSet::Epsilon => "Epsilon".to_owned(),
+            &CharSet::Predicate(_) => "Predicate".to_owned(),
+        }
+    }
+
+    // Decisions for the members of a `Union` or `Diff`, `None` for other sets.
+    fn explain_members(&self, c: char) -> Option<Vec<String>> {
+        let in_or_not = |cs: &CharSet| if cs.test(c) { "in" } else { "not in" };
+        match self {
+            &CharSet::Union(ref css) =>
+                Some(css.iter().map(|cs| format!("{} {}", in_or_not(cs), cs.explain_set(c))).collect()),
+            &CharSet::Diff { ref include, ref exclude } =>
+                Some(vec![format!("{} include {}", in_or_not(include), include.explain_set(c)),
+                          format!("{} exclude {}", in_or_not(exclude), exclude.explain_set(c))]),
+            _ => None,
+        }
+    }
+
+    /// Returns a `Predicate` set of the chars for which `f` returns true.
+    ///
+    /// `test` calls `f` once, but everything that resolves the set into ranges calls it on each of
+    /// the ~1.1M chars, every time: `to_ranges` and the methods built on it (e.g. `is_empty`,
+    /// `canonical` and `hash`), building an NFA with the set in a transition, and `NFA::encode`.
+    /// For a set that is used in more than one of those, resolve it once with
+    /// `CharSet::from_ranges(&cs.to_ranges())` and use the result instead.
+    pub fn predicate<F: Fn(char) -> bool + Send + Sync + 'static>(f: F) -> CharSet {
+        CharSet::Predicate(Arc::new(f))
+    }
+
+    /// Returns true if the set doesn't match any chars.
+    pub fn is_empty(&self) -> bool {
+        self.to_ranges().is_empty()
+    }
+
+    /// Returns true if the set matches all chars.
+    pub fn is_full(&self) -> bool {
+        self.to_ranges() == vec![('\0', char::MAX)]
+    }
+
+    /// Returns true if every char matched by `other` is also matched by `self`.
+    pub fn contains_set(&self, other: &CharSet) -> bool {
+        intersect_ranges(&other.to_ranges(), &complement_ranges(&self.to_ranges())).is_empty()
+    }
+
+    /// Returns the chars matched by the set as sorted, disjoint, non-adjacent inclusive ranges.
+    /// `Diff`s are resolved by subtracting the ranges of `exclude` from the ranges of `include`.
+    /// Like `test`, this treats `Epsilon` as matching everything. A `Predicate` is resolved by
+    /// testing every char, which is slow.
+    pub fn to_ranges(&self) -> Vec<(char, char)> {
+        match self {
+
+            &CharSet::SingleChar(c) => vec![(c, c)],
+
+            &CharSet::Range { lo, hi } => if lo <= hi { vec![(lo, hi)] } else { vec![] },
+
+            &CharSet::AnyChar | &CharSet::Epsilon => vec![('\0', char::MAX)],
+
+            &CharSet::Diff { ref include, ref exclude } =>
+                intersect_ranges(&include.to_ranges(), &complement_ranges(&exclude.to_ranges())),
+
+            &CharSet::Union(ref css) => {
+                let mut ranges = vec![];
+                for cs in css {
+                    ranges.extend(cs.to_ranges());
+                }
+                normalize_ranges(ranges)
+            }
+
+            &CharSet::Predicate(ref f) => {
+                let mut ranges: Vec<(char, char)> = vec![];
+                for c in (0 ..= char::MAX as u32).filter_map(char::from_u32).filter(|c| f(*c)) {
+                    if let Some(last) = ranges.last_mut() {
+                        if next_char(last.1) == Some(c) {
+                            last.1 = c;
+                            continue;
+                        }
+                    }
+                    ranges.push((c, c));
+                }
+                ranges
+            }
+        }
+    }
+
+    /// Returns an equivalent set (one that `test`s the same for every char) with redundant
+    /// structure removed:
+    ///
+    /// - Nested `Union`s are flattened, empty members are dropped, and a single member replaces
+    ///   the `Union`. A `Union` whose members together match all chars becomes `AnyChar`.
+    /// - `Diff`s with an empty `exclude` become their `include`, and ones with an empty `include`
+    ///   or full `exclude` become the empty set.
+    /// - `Diff { include: AnyChar, exclude }` becomes the complement of `exclude` as a `Union` of
+    ///   ranges.
+    ///
+    /// The empty set is represented as `Union(vec![])`. `Epsilon` is left unchanged.
+    pub fn simplify(&self) -> CharSet {
+        match self {
+
+            &CharSet::Range { lo, hi } => {
+                if lo > hi {
+                    CharSet::Union(vec![])
+                } else if lo == hi {
+                    CharSet::SingleChar(lo)
+                } else {
+                    self.clone()
+                }
+            }
+
+            &CharSet::Union(ref css) => {
+                let mut members = vec![];
+                for cs in css {
+                    match cs.simplify() {
+                        CharSet::Union(css) => members.extend(css),
+                        cs => members.push(cs),
+                    }
+                }
+                if members.len() == 1 {
+                    return members.pop().unwrap();
+                }
+                let union = CharSet::Union(members);
+                // Also covers members that are full on their own
+                if union.is_full() {
+                    CharSet::AnyChar
+                } else {
+                    union
+                }
+            }
+
+            &CharSet::Diff { ref include, ref exclude } => {
+                let include = include.simplify();
+                let exclude = exclude.simplify();
+                if exclude.is_empty() {
+                    include
+                } else if include.is_empty() || exclude.is_full() {
+                    CharSet::Union(vec![])
+                } else if include == CharSet::AnyChar {
+                    CharSet::from_ranges(&complement_ranges(&exclude.to_ranges()))
+                } else {
+                    CharSet::Diff { include: Box::new(include), exclude: Box::new(exclude) }
+                }
+            }
+
+            _ => self.clone(),
+        }
+    }
+
+    /// Renders the set as a character class like `[a-z0-9_]`, or as a negated class like `[^a-z]`
+    /// when that's shorter. `]`, `-`, `^`, `\` and `"` are escaped with a backslash, and control
+    /// chars and whitespace other than space are escaped as in `escape_char_for_display`. The
+    /// empty set is `[]` and the full set is `[^]`. `CharSet::from_class_string` parses the result
+    /// back.
+    pub fn to_class_string(&self) -> String {
+        let ranges = self.to_ranges();
+        let complement = complement_ranges(&ranges);
+
+        let mut s = String::from("[");
+        let ranges = if complement.len() < ranges.len() {
+            s.push('^');
+            complement
+        } else {
+            ranges
+        };
+        for (lo, hi) in ranges {
+            push_class_char(&mut s, lo);
+            if lo != hi {
+                if next_char(lo) != Some(hi) {
+                    s.push('-');
+                }
+                push_class_char(&mut s, hi);
+            }
+        }
+        s.push(']');
+        s
+    }
+
+    /// Parses a character class in the format generated by `to_class_string`: `[...]` or `[^...]`
+    /// with chars and `lo-hi` ranges inside. `-` is literal when it can't form a range, i.e. at the
+    /// beginning or end of the class, and `^` is literal when it's not the first char of the
+    /// class.
+    ///
+    /// Escapes `\t`, `\n`, `\r`, `\0`, `\xHH` and `\u{H...}` (up to 6 hex digits) give the
+    /// corresponding chars. A backslash followed by any other char that's not an ASCII letter or
+    /// digit makes that char literal. `\d`, `\w`, `\s` and their negations `\D`, `\W`, `\S` add
+    /// the sets given by `shorthand_class`, and can't be range bounds.
+    pub fn from_class_string(s: &str) -> Result<CharSet, ParseError> {
+        let mut chars = s.char_indices().peekable();
+
+        match chars.next() {
+            None => { return Err(ParseError::UnexpectedEnd); }
+            Some((_, '[')) => {}
+            Some((offset, c)) => { return Err(ParseError::UnexpectedChar { offset: offset, c: c }); }
+        }
+
+        let negated = match chars.peek() {
+            Some(&(_, '^')) => { chars.next(); true }
+            _ => false,
+        };
+
+        let mut members = vec![];
+        loop {
+            let (lo_offset, lo) = match chars.next() {
+                None => { return Err(ParseError::UnexpectedEnd); }
+                Some((_, ']')) => { break; }
+                Some((_, '\\')) => {
+                    if let Some(cs) = chars.peek().and_then(|&(_, c)| shorthand_class(c)) {
+                        chars.next();
+                        members.push(cs);
+                        continue;
+                    }
+                    parse_class_escape(&mut chars)?
+                }
+                Some(c) => c,
+            };
+
+            let is_range = match chars.peek() {
+                Some(&(_, '-')) => true,
+                _ => false,
+            };
+            if !is_range {
+                members.push(CharSet::SingleChar(lo));
+                continue;
+            }
+            chars.next(); // skip '-'
+
+            let hi = match chars.next() {
+                None => { return Err(ParseError::UnexpectedEnd); }
+                Some((_, ']')) => {
+                    // '-' at the end of the class
+                    members.push(CharSet::SingleChar(lo));
+                    members.push(CharSet::SingleChar('-'));
+                    break;
+                }
+                Some((_, '\\')) => parse_class_escape(&mut chars)?.1,
+                Some((_, c)) => c,
+            };
+            if lo > hi {
+                return Err(ParseError::InvalidRange { offset: lo_offset });
+            }
+            members.push(CharSet::Range { lo: lo, hi: hi });
+        }
+
+        if let Some((offset, c)) = chars.next() {
+            return Err(ParseError::UnexpectedChar { offset: offset, c: c });
+        }
+
+        let union = CharSet::Union(members);
+        if negated {
+            Ok(CharSet::Diff { include: Box::new(CharSet::AnyChar), exclude: Box::new(union) })
+        } else {
+            Ok(union)
+        }
+    }
+
+    /// Returns the canonical form of the set: sets that match the same chars have the same
+    /// canonical form. It's `AnyChar` for the full set, and `from_ranges` of the set's ranges
+    /// otherwise. `Epsilon` is left unchanged.
+    pub fn canonical(&self) -> CharSet {
+        if *self == CharSet::Epsilon {
+            return CharSet::Epsilon;
+        }
+        let ranges = self.to_ranges();
+        if ranges == vec![('\0', char::MAX)] {
+            CharSet::AnyChar
+        } else {
+            CharSet::from_ranges(&ranges)
+        }
+    }
+
+    /// Resolves the set into sorted intervals once, so that testing chars against it is a binary
+    /// search instead of a walk over the set. Useful for sets that are tested many times and are
+    /// expensive to `test` directly, e.g. a `Diff` with a large `Union` as `exclude`. (NFA steps
+    /// already work on intervals, see `NFA::feed`.) `Diff`s are resolved from the inside out, so a
+    /// `Diff` in an `exclude` adds back the chars it excludes.
+    pub fn to_intervals(&self) -> Intervals {
+        Intervals { ranges: self.to_ranges() }
+    }
+
+    /// Builds a set from inclusive ranges, as `SingleChar`s and `Range`s in a `Union` (or without
+    /// the `Union` when there's only one range).
+    pub fn from_ranges(ranges: &[(char, char)]) -> CharSet {
+        let mut css: Vec<CharSet> = ranges.iter().map(|&(lo, hi)| {
+            if lo == hi { CharSet::SingleChar(lo) } else { CharSet::Range { lo: lo, hi: hi } }
+        }).collect();
+        if css.len() == 1 {
+            css.pop().unwrap()
+        } else {
+            CharSet::Union(css)
+        }
+    }
+
+    /// Builds a set of the given chars, as a `Union` of `SingleChar`s.
+    pub fn one_of(chars: &[char]) -> CharSet {
+        CharSet::Union(chars.iter().map(|&c| CharSet::SingleChar(c)).collect())
+    }
+
+    /// Builds a `Range` from code points, failing if either of them is not a char (a surrogate or
+    /// larger than `0x10FFFF`). As with `Range`, `lo > hi` gives an empty set.
+    pub fn range_u32(lo: u32, hi: u32) -> Result<CharSet, CharSetError> {
+        Ok(CharSet::Range { lo: code_point_to_char(lo)?, hi: code_point_to_char(hi)? })
+    }
+
+    /// Builds the union of the sets. Members of `Union`s in `sets` are moved into the result (one
+    /// level deep) and empty sets are dropped, so that the result doesn't accumulate nesting. If
+    /// only one member remains it's returned without the `Union`, and if the members match all
+    /// chars the result is `AnyChar`.
+    pub fn union_all(sets: Vec<CharSet>) -> CharSet {
+        let mut members = vec![];
+        for cs in sets {
+            match cs {
+                CharSet::Union(css) => members.extend(css.into_iter().filter(|cs| !cs.is_empty())),
+                ref cs if cs.is_empty() => {}
+                cs => members.push(cs),
+            }
+        }
+        if members.len() == 1 {
+            return members.pop().unwrap();
+        }
+        let union = CharSet::Union(members);
+        if union.is_full() {
+            CharSet::AnyChar
+        } else {
+            union
+        }
+    }
+
+    /// Returns the chars of the set in ascending order, or `None` if the set has more than 256
+    /// chars.
+    pub fn iter_chars(&self) -> Option<Vec<char>> {
+        let mut chars = vec![];
+        for (lo, hi) in self.to_ranges() {
+            let mut c = lo;
+            loop {
+                if chars.len() == 256 {
+                    return None;
+                }
+                chars.push(c);
+                if c == hi {
+                    break;
+                }
+                c = next_char(c).unwrap();
+            }
+        }
+        Some(chars)
+    }
+
+    /// Returns the set extended with the upper and lower case variants of its chars, e.g. `[a-z]`
+    /// becomes `[A-Za-z]`. Case mappings to multiple chars are ignored, so e.g. `ß` (`SS` in upper
+    /// case) isn't extended. The result is normalized like in `from_ranges`.
+    pub fn case_fold(&self) -> CharSet {
+        if self.is_full() {
+            return CharSet::AnyChar;
+        }
+        let mut ranges = self.to_ranges();
+        // Only the chars that have case variants are walked, so large sets are cheap to fold
+        let mut folded = vec![];
+        for (lo, hi) in intersect_ranges(&ranges, CASED_RANGES) {
+            let mut c = lo;
+            loop {
+                push_single_char_case(&mut folded, c.to_lowercase());
+                push_single_char_case(&mut folded, c.to_uppercase());
+                if c == hi {
+                    break;
+                }
+                c = next_char(c).unwrap();
+            }
+        }
+        ranges.extend(folded);
+        CharSet::from_ranges(&normalize_ranges(ranges))
+    }
+}
+
+fn push_class_char(s: &mut String, c: char) {
+    match c {
+        ']' | '-' | '^' => s.push('\\'),
+        _ => {}
+    }
+    s.push_str(&escape_char_for_display(c));
+}
+
+/// Renders a char so that it's visible and can be embedded in double quotes: `\` and `"` are
+/// escaped with a backslash, `\t`, `\n`, `\r` and `\0` are rendered as such, other control
+/// chars as `\xHH` (ASCII) or `\u{H...}`, and whitespace other than space as `\u{H...}`. The
+/// escapes are the ones `CharSet::from_class_string` understands.
+pub fn escape_char_for_display(c: char) -> String {
+    match c {
+        '\\' | '"' => format!("\\{}", c),
+        '\t' => "\\t".to_owned(),
+        '\n' => "\\n".to_owned(),
+        '\r' => "\\r".to_owned(),
+        '\0' => "\\0".to_owned(),
+        c if c.is_control() && (c as u32) < 0x80 => format!("\\x{:02X}", c as u32),
+        c if c.is_control() || (c.is_whitespace() && c != ' ') => format!("\\u{{{:X}}}", c as u32),
+        c => c.to_string(),
+    }
+}
+
+/// Returns the set for a shorthand class escape, given the char after the backslash: `d` for ASCII
+/// digits, `w` for ASCII letters, digits and `_`, `s` for ASCII whitespace (space, `\t`, `\n`,
+/// `\r`, `\x0B`, `\x0C`), and the uppercase letters for the complements of those as
+/// `Diff { include: AnyChar, .. }`.
+pub fn shorthand_class(c: char) -> Option<CharSet> {
+    let class = match c.to_ascii_lowercase() {
+        'd' => CharSet::Range { lo: '0', hi: '9' },
+        'w' => CharSet::Union(vec![
+            CharSet::Range { lo: 'a', hi: 'z' },
+            CharSet::Range { lo: 'A', hi: 'Z' },
+            CharSet::Range { lo: '0', hi: '9' },
+            CharSet::SingleChar('_'),
+        ]),
+        's' => CharSet::one_of(&[' ', '\t', '\n', '\r', '\x0B', '\x0C']),
+        _ => { return None; }
+    };
+    if c.is_ascii_uppercase() {
+        Some(CharSet::Diff { include: Box::new(CharSet::AnyChar), exclude: Box::new(class) })
+    } else {
+        Some(class)
+    }
+}
+
+/// Returns the char of an escape sequence, including the backslash, e.g. `\x41` or `\u{1F600}`.
+/// Escapes are parsed as in `CharSet::from_class_string`. Returns `None` if the escape is invalid,
+/// e.g. for a code point that is not a char, or if there's input after it.
+pub fn escaped_char(escape: &str) -> Option<char> {
+    let mut chars = escape.char_indices().peekable();
+    if chars.next() != Some((0, '\\')) {
+        return None;
+    }
+    match parse_class_escape(&mut chars) {
+        Ok((_, c)) if chars.next().is_none() => Some(c),
+        _ => None,
+    }
+}
+
+// Intervals that cover the chars with upper or lower case variants (in the Unicode version of the
+// standard library), for `CharSet::case_fold`. Normalized.
+const CASED_RANGES: &[(char, char)] = &[
+    ('\u{41}', '\u{29E}'),
+    ('\u{345}', '\u{587}'),
+    ('\u{10A0}', '\u{10FF}'),
+    ('\u{13A0}', '\u{13FD}'),
+    ('\u{1C80}', '\u{1CBF}'),
+    ('\u{1D79}', '\u{1D8E}'),
+    ('\u{1E00}', '\u{1FFC}'),
+    ('\u{2126}', '\u{2184}'),
+    ('\u{24B6}', '\u{24E9}'),
+    ('\u{2C00}', '\u{2D2D}'),
+    ('\u{A640}', '\u{A69B}'),
+    ('\u{A722}', '\u{A7F6}'),
+    ('\u{AB53}', '\u{ABBF}'),
+    ('\u{FB00}', '\u{FB17}'),
+    ('\u{FF21}', '\u{FF5A}'),
+    ('\u{10400}', '\u{1044F}'),
+    ('\u{104B0}', '\u{104FB}'),
+    ('\u{10570}', '\u{105BC}'),
+    ('\u{10C80}', '\u{10CF2}'),
+    ('\u{10D50}', '\u{10D85}'),
+    ('\u{118A0}', '\u{118DF}'),
+    ('\u{16E40}', '\u{16ED3}'),
+    ('\u{1E900}', '\u{1E943}'),
+];
+
+fn push_single_char_case<I: Iterator<Item = char>>(ranges: &mut Vec<(char, char)>, mut case: I) {
+    if let (Some(c), None) = (case.next(), case.next()) {
+        ranges.push((c, c));
+    }
+}
+
+// Parses an escape sequence. The backslash is already consumed. Returns the offset of the
+// backslash and the escaped char.
+fn parse_class_escape(chars: &mut Peekable<CharIndices>) -> Result<(usize, char), ParseError> {
+    let (offset, c) = match chars.next() {
+        None => { return Err(ParseError::UnexpectedEnd); }
+        Some((offset, c)) => (offset - 1, c),
+    };
+    let invalid = ParseError::InvalidEscape { offset: offset };
+
+    let c = match c {
+        't' => '\t',
+        'n' => '\n',
+        'r' => '\r',
+        '0' => '\0',
+        'x' => {
+            let mut code = 0;
+            for _ in 0 .. 2 {
+                match chars.next() {
+                    None => { return Err(ParseError::UnexpectedEnd); }
+                    Some((_, c)) => {
+                        code = code * 16 + c.to_digit(16).ok_or(ParseError::InvalidEscape { offset: offset })?;
+                    }
+                }
+            }
+            char::from_u32(code).unwrap()
+        }
+        'u' => {
+            match chars.next() {
+                None => { return Err(ParseError::UnexpectedEnd); }
+                Some((_, '{')) => {}
+                Some(_) => { return Err(invalid); }
+            }
+            let mut code: u32 = 0;
+            let mut digits = 0;
+            loop {
+                match chars.next() {
+                    None => { return Err(ParseError::UnexpectedEnd); }
+                    Some((_, '}')) => { break; }
+                    Some((_, c)) => {
+                        digits += 1;
+                        if digits > 6 {
+                            return Err(invalid);
+                        }
+                        code = code * 16 + c.to_digit(16).ok_or(ParseError::InvalidEscape { offset: offset })?;
+                    }
+                }
+            }
+            if digits == 0 {
+                return Err(invalid);
+            }
+            char::from_u32(code).ok_or(invalid)?
+        }
+        '1' ..= '9' => { return Err(ParseError::UnsupportedBackreference { offset: offset }); }
+        // Reserve other letters and digits for future escapes
+        c if c.is_ascii_alphanumeric() => { return Err(invalid); }
+        c => c,
+    };
+
+    Ok((offset, c))
+}
+
+/// A `CharSet` resolved into intervals, see `CharSet::to_intervals`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Intervals {
+    // Normalized, as returned by `CharSet::to_ranges`
+    ranges: Vec<(char, char)>,
+}
+
+impl Intervals {
+    /// Same as `CharSet::test` on the set the intervals were built from.
+    pub fn test(&self, c: char) -> bool {
+        find_range(&self.ranges, c as u32, |&(lo, hi)| (lo as u32, hi as u32)).is_some()
+    }
+
+    pub fn ranges(&self) -> &[(char, char)] {
+        &self.ranges
+    }
+}
+
+/// A `CharSet` that remembers the result of its last `test`, for testing long runs of the same
+/// char (e.g. indentation) against a set that takes several comparisons to test. The cache is a
+/// `Cell`, so a `CachedCharSet` can't be shared between threads.
+#[derive(Debug, Clone)]
+pub struct CachedCharSet {
+    set: CharSet,
+    last: Cell<Option<(char, bool)>>,
+}
+
+impl CachedCharSet {
+    pub fn new(set: CharSet) -> CachedCharSet {
+        CachedCharSet { set: set, last: Cell::new(None) }
+    }
+
+    /// Same as `CharSet::test`.
+    pub fn test(&self, c: char) -> bool {
+        match self.last.get() {
+            Some((last_c, result)) if last_c == c => result,
+            _ => {
+                let result = self.set.test(c);
+                self.last.set(Some((c, result)));
+                result
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> CharSet {
+        self.set
+    }
+}
+
+// Interval arithmetic. Ranges are inclusive and the surrogate gap (U+D800 - U+DFFF) is skipped
+// when moving to the next or previous char, as those are not valid `char`s.
+
+fn code_point_to_char(code: u32) -> Result<char, CharSetError> {
+    match char::from_u32(code) {
+        Some(c) => Ok(c),
+        None if code > char::MAX as u32 => Err(CharSetError::OutOfRange(code)),
+        None => Err(CharSetError::Surrogate(code)),
+    }
+}
+
+fn next_char(c: char) -> Option<char> {
+    match c {
+        '\u{D7FF}' => Some('\u{E000}'),
+        char::MAX => None,
+        _ => char::from_u32(c as u32 + 1),
+    }
+}
+
+fn prev_char(c: char) -> Option<char> {
+    match c {
+        '\u{E000}' => Some('\u{D7FF}'),
+        '\0' => None,
+        _ => char::from_u32(c as u32 - 1),
+    }
+}
+
+// Sorts the ranges and merges overlapping and adjacent ones.
+fn normalize_ranges(mut ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    ranges.sort();
+    let mut ret: Vec<(char, char)> = Vec::with_capacity(ranges.len());
+    for (lo, hi) in ranges {
+        if let Some(last) = ret.last_mut() {
+            if next_char(last.1).map(|c| lo <= c).unwrap_or(true) {
+                if hi > last.1 {
+                    last.1 = hi;
+                }
+                continue;
+            }
+        }
+        ret.push((lo, hi));
+    }
+    ret
+}
+
+// Arguments of the functions below should be normalized. Results are normalized.
+
+fn complement_ranges(ranges: &[(char, char)]) -> Vec<(char, char)> {
+    let mut ret = vec![];
+    let mut lo = Some('\0');
+    for &(range_lo, range_hi) in ranges {
+        if let Some(lo) = lo {
+            if lo < range_lo {
+                ret.push((lo, prev_char(range_lo).unwrap()));
+            }
+        }
+        lo = next_char(range_hi);
+    }
+    if let Some(lo) = lo {
+        ret.push((lo, char::MAX));
+    }
+    ret
+}
+
+fn intersect_ranges(ranges1: &[(char, char)], ranges2: &[(char, char)]) -> Vec<(char, char)> {
+    let mut ret = vec![];
+    let mut i = 0;
+    let mut j = 0;
+    while i < ranges1.len() && j < ranges2.len() {
+        let (lo1, hi1) = ranges1[i];
+        let (lo2, hi2) = ranges2[j];
+        let lo = if lo1 > lo2 { lo1 } else { lo2 };
+        let hi = if hi1 < hi2 { hi1 } else { hi2 };
+        if lo <= hi {
+            ret.push((lo, hi));
+        }
+        if hi1 < hi2 {
+            i += 1;
+        } else {
+            j += 1;
         }
     }
+    ret
 }
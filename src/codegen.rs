@@ -0,0 +1,69 @@
+use std::fmt::Write;
+
+use NFA;
+
+impl<D> NFA<D> {
+    /// Generates the source of a Rust function `fn_name(input: &str) -> bool` that returns
+    /// whether the automaton (from its start state) accepts `input`, for embedding automata in
+    /// programs without building them at runtime. The function runs a determinized version of the
+    /// automaton (see `determinize`) as a `match` over the states and char ranges.
+    ///
+    /// For `ab?` the output is:
+    ///
+    /// ```text
+    /// fn ab(input: &str) -> bool {
+    ///     let mut state: usize = 0;
+    ///     for c in input.chars() {
+    ///         state = match (state, c) {
+    ///             (0, 'a') => 1,
+    ///             (1, 'b') => 2,
+    ///             _ => return false,
+    ///         };
+    ///     }
+    ///     match state {
+    ///         1 | 2 => true,
+    ///         _ => false,
+    ///     }
+    /// }
+    /// ```
+    pub fn to_rust_fn(&self, fn_name: &str) -> String {
+        let dfa = self.determinize();
+
+        let mut states: Vec<usize> = dfa.transitions.keys().cloned().collect();
+        states.sort();
+        let mut accepting: Vec<usize> = dfa.accepting.keys().cloned().collect();
+        accepting.sort();
+
+        // Writing to a `String` doesn't fail
+        let mut out = String::new();
+        writeln!(out, "fn {}(input: &str) -> bool {{", fn_name).unwrap();
+        writeln!(out, "    let mut state: usize = 0;").unwrap();
+        writeln!(out, "    for c in input.chars() {{").unwrap();
+        writeln!(out, "        state = match (state, c) {{").unwrap();
+        for state in states {
+            // Transitions of a determinized automaton are on disjoint unions of ranges
+            for &(ref cs, target) in &dfa.transitions[&state] {
+                let patterns: Vec<String> = cs.to_ranges().into_iter().map(|(lo, hi)| {
+                    if lo == hi {
+                        format!("({}, {:?})", state, lo)
+                    } else {
+                        format!("({}, {:?} ..= {:?})", state, lo, hi)
+                    }
+                }).collect();
+                writeln!(out, "            {} => {},", patterns.join(" | "), target).unwrap();
+            }
+        }
+        writeln!(out, "            _ => return false,").unwrap();
+        writeln!(out, "        }};").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "    match state {{").unwrap();
+        if !accepting.is_empty() {
+            let patterns: Vec<String> = accepting.iter().map(|state| state.to_string()).collect();
+            writeln!(out, "        {} => true,", patterns.join(" | ")).unwrap();
+        }
+        writeln!(out, "        _ => false,").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
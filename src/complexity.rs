@@ -0,0 +1,118 @@
+use visitor::RegexVisitor;
+use {CharSet, Regex};
+
+/// Regexes nested deeper than this are reported as risky.
+pub const MAX_SAFE_DEPTH: usize = 500;
+
+/// Result of `Regex::complexity_report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComplexityReport {
+    /// Depth of the regex tree. A single node has depth 1.
+    pub depth: usize,
+
+    /// Largest number of unbounded quantifiers (`*` and `+`) nested in each other. `a*` has 1,
+    /// `(a*b)*` has 2.
+    pub quantifier_nesting: usize,
+
+    pub is_risky: bool,
+
+    /// Why the regex is risky, when it is.
+    pub reason: Option<String>,
+}
+
+impl Regex {
+    /// Checks the structure of the regex for patterns that are likely to cause an explosion in
+    /// the number of NFA states or active states, without building the NFA. This is advisory:
+    /// a regex that's not reported can still be expensive.
+    pub fn complexity_report(&self) -> ComplexityReport {
+        let mut linter = Linter {
+            depth: 0,
+            quantifier_nesting: 0,
+            max_depth: 0,
+            max_quantifier_nesting: 0,
+        };
+        self.accept(&mut linter);
+
+        let reason = if linter.max_quantifier_nesting > 1 {
+            Some(format!("{} unbounded quantifiers are nested in each other", linter.max_quantifier_nesting))
+        } else if linter.max_depth > MAX_SAFE_DEPTH {
+            Some(format!("regex is nested {} levels deep (limit is {})", linter.max_depth, MAX_SAFE_DEPTH))
+        } else {
+            None
+        };
+
+        ComplexityReport {
+            depth: linter.max_depth,
+            quantifier_nesting: linter.max_quantifier_nesting,
+            is_risky: reason.is_some(),
+            reason: reason,
+        }
+    }
+}
+
+struct Linter {
+    // Current depth and number of enclosing unbounded quantifiers
+    depth: usize,
+    quantifier_nesting: usize,
+    max_depth: usize,
+    max_quantifier_nesting: usize,
+}
+
+impl Linter {
+    fn leaf(&mut self) {
+        if self.depth + 1 > self.max_depth {
+            self.max_depth = self.depth + 1;
+        }
+    }
+
+    fn node(&mut self, children: &[&Regex]) {
+        self.depth += 1;
+        for child in children {
+            child.accept(self);
+        }
+        self.depth -= 1;
+    }
+
+    fn unbounded(&mut self, r: &Regex) {
+        self.quantifier_nesting += 1;
+        if self.quantifier_nesting > self.max_quantifier_nesting {
+            self.max_quantifier_nesting = self.quantifier_nesting;
+        }
+        self.node(&[r]);
+        self.quantifier_nesting -= 1;
+    }
+}
+
+impl RegexVisitor for Linter {
+    fn visit_eps(&mut self) {
+        self.leaf();
+    }
+
+    fn visit_charset(&mut self, _cs: &CharSet) {
+        self.leaf();
+    }
+
+    fn visit_seq(&mut self, r1: &Regex, r2: &Regex) {
+        self.node(&[r1, r2]);
+    }
+
+    fn visit_or(&mut self, r1: &Regex, r2: &Regex) {
+        self.node(&[r1, r2]);
+    }
+
+    fn visit_star(&mut self, r: &Regex) {
+        self.unbounded(r);
+    }
+
+    fn visit_plus(&mut self, r: &Regex) {
+        self.unbounded(r);
+    }
+
+    fn visit_ques(&mut self, r: &Regex) {
+        self.node(&[r]);
+    }
+
+    fn visit_count_group(&mut self, _group: usize, r: &Regex) {
+        self.node(&[r]);
+    }
+}
@@ -0,0 +1,51 @@
+// Gzip-compressed version of the format in `encode`, for large automata assets.
+
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use {DecodeError, NFA};
+
+/// Errors of `NFA::read_compressed`.
+#[derive(Debug)]
+pub enum ReadCompressedError {
+    /// Reading failed, or the input is not valid gzip.
+    Io(io::Error),
+
+    /// The decompressed bytes are not a valid encoding.
+    Decode(DecodeError),
+}
+
+impl fmt::Display for ReadCompressedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ReadCompressedError::Io(ref err) => write!(f, "{}", err),
+            &ReadCompressedError::Decode(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl<D> NFA<D> {
+    /// Writes the encoding of the automaton (see `encode`), compressed with gzip. Encoding errors
+    /// are reported as `io::ErrorKind::InvalidInput`.
+    pub fn write_compressed<W: Write>(&self, w: W) -> io::Result<()> {
+        let mut encoder = GzEncoder::new(w, Compression::best());
+        let bytes = self.encode().map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        encoder.write_all(&bytes)?;
+        encoder.finish()?;
+        Ok(())
+    }
+}
+
+impl NFA {
+    /// Reads an automaton written by `write_compressed`.
+    pub fn read_compressed<R: Read>(r: R) -> Result<NFA, ReadCompressedError> {
+        let mut bytes = vec![];
+        GzDecoder::new(r).read_to_end(&mut bytes).map_err(ReadCompressedError::Io)?;
+        NFA::decode(&bytes).map_err(ReadCompressedError::Decode)
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::Chars;
+
+use NFA;
+
+// Counts of the groups on the path to each active state. Ordered so that the path picked in the
+// end doesn't depend on hashing.
+type Paths = BTreeMap<usize, HashMap<usize, usize>>;
+
+impl<D> NFA<D> {
+    /// Runs the automaton from its start state, and if the input is accepted, returns how many
+    /// times each `Regex::CountGroup` matched, by group id. Groups that didn't match are not in
+    /// the map. Doesn't update the current states.
+    ///
+    /// An NFA can match an input in more than one way, and the counts can be different in each.
+    /// The counts are along one of the accepting paths, which one is unspecified.
+    pub fn run_counts(&self, chars: Chars) -> Option<HashMap<usize, usize>> {
+        let mut paths = Paths::new();
+        paths.insert(self.start, HashMap::new());
+        self.counting_closure(&mut paths);
+
+        for c in chars {
+            let mut next_paths = Paths::new();
+            for (state, counts) in paths.iter() {
+                for target in self.char_targets(*state, c) {
+                    next_paths.entry(*target).or_insert_with(|| counts.clone());
+                }
+            }
+            if next_paths.is_empty() {
+                return None;
+            }
+            paths = next_paths;
+            self.counting_closure(&mut paths);
+        }
+
+        paths.into_iter().find(|&(state, _)| self.accepting.contains_key(&state)).map(|(_, counts)| counts)
+    }
+
+    // Adds the states reachable via epsilon transitions, keeping the first path found to each
+    // state
+    fn counting_closure(&self, paths: &mut Paths) {
+        let work = paths.keys().cloned().collect();
+        self.follow_epsilons(work, |&state| state, |&state, target| {
+            if paths.contains_key(&target) {
+                return None;
+            }
+            let mut counts = paths[&state].clone();
+            if let Some(group) = self.count_edges.get(&(state, target)) {
+                *counts.entry(*group).or_insert(0) += 1;
+            }
+            paths.insert(target, counts);
+            Some(target)
+        });
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use {CharClasses, CharSet, NFA, Regex};
+
+impl<D> NFA<D> {
+    /// Returns an automaton that accepts the strings that this automaton accepts but `other`
+    /// doesn't, e.g. identifiers that are not keywords. Both automata run from their start states,
+    /// and accepting states of the result have tag 0.
+    ///
+    /// The result is deterministic: its states are pairs of sets of states of the two automata,
+    /// so it can be exponentially larger than them.
+    pub fn difference<E>(&self, other: &NFA<E>) -> NFA {
+        let classes = CharClasses::from_charsets(
+            self.transitions.values().chain(other.transitions.values()).flat_map(|ts| ts.iter().map(|&(ref cs, _)| cs)));
+
+        let start = (epsilon_closure(self, vec![self.start].into_iter().collect()),
+                     epsilon_closure(other, vec![other.start].into_iter().collect()));
+        let mut ids: HashMap<(BTreeSet<usize>, BTreeSet<usize>), usize> = HashMap::new();
+        ids.insert(start.clone(), 0);
+        let mut work = vec![start];
+
+        let mut transitions = HashMap::new();
+        let mut accepting = HashMap::new();
+
+        while let Some(pair) = work.pop() {
+            let id = ids[&pair];
+            if accepts(self, &pair.0) && !accepts(other, &pair.1) {
+                accepting.insert(id, 0);
+            }
+
+            // Runs of consecutive classes leading to each target
+            let mut runs: BTreeMap<usize, Vec<(usize, usize)>> = BTreeMap::new();
+            for class in 0 .. classes.num_classes() {
+                let c = classes.representative(class);
+                let next_states = step_states(self, &pair.0, c);
+                // No need to follow `other` once this automaton is dead
+                if next_states.is_empty() {
+                    continue;
+                }
+                let next = (next_states, step_states(other, &pair.1, c));
+                let next_id = match ids.get(&next) {
+                    Some(next_id) => *next_id,
+                    None => {
+                        let next_id = ids.len();
+                        ids.insert(next.clone(), next_id);
+                        work.push(next);
+                        next_id
+                    }
+                };
+                let target_runs = runs.entry(next_id).or_default();
+                match target_runs.last_mut() {
+                    Some(run) if run.1 + 1 == class => { run.1 = class; }
+                    _ => { target_runs.push((class, class)); }
+                }
+            }
+
+            let ts: Vec<(CharSet, usize)> = runs.into_iter().map(|(target, runs)| {
+                let ranges: Vec<(char, char)> =
+                    runs.iter().map(|&(first, last)| (classes.range(first).0, classes.range(last).1)).collect();
+                (CharSet::from_ranges(&ranges), target)
+            }).collect();
+            if !ts.is_empty() {
+                transitions.insert(id, ts);
+            }
+        }
+
+        NFA::new(transitions, accepting)
+    }
+}
+
+impl<D> NFA<D> {
+    /// Returns an equivalent deterministic automaton: one with at most one transition on each char
+    /// from each state, and without epsilon transitions. States are numbered from 0 (the start
+    /// state) in the order they're found.
+    pub fn determinize(&self) -> NFA {
+        // Difference with an automaton that doesn't accept anything
+        self.difference(&NFA::new(HashMap::new(), HashMap::new()))
+    }
+}
+
+impl Regex {
+    /// Returns an automaton that accepts the strings that `self` matches but `other` doesn't. See
+    /// `NFA::difference`.
+    pub fn difference(&self, other: &Regex) -> NFA {
+        self.to_nfa().difference(&other.to_nfa())
+    }
+}
+
+fn epsilon_closure<D>(nfa: &NFA<D>, mut states: BTreeSet<usize>) -> BTreeSet<usize> {
+    let work = states.iter().cloned().collect();
+    nfa.follow_epsilons(work, |&state| state, |_, target| {
+        if states.insert(target) { Some(target) } else { None }
+    });
+    states
+}
+
+fn step_states<D>(nfa: &NFA<D>, states: &BTreeSet<usize>, c: char) -> BTreeSet<usize> {
+    let next_states = states.iter().flat_map(|state| nfa.char_targets(*state, c).iter().cloned()).collect();
+    epsilon_closure(nfa, next_states)
+}
+
+fn accepts<D>(nfa: &NFA<D>, states: &BTreeSet<usize>) -> bool {
+    states.iter().any(|state| nfa.accepting.contains_key(state))
+}
@@ -0,0 +1,350 @@
+// A compact binary format for NFAs, so that automata can be built ahead of time and shipped as
+// assets. All integers are little-endian `u32`s. The format is:
+//
+//     magic         b"NLXA"
+//     version       u8 (currently 5, older versions are rejected)
+//     start         start state
+//     accepting     count, then (state, tag, priority) triples
+//     transitions   count of states with transitions, then for each state:
+//                   state, count, then (charset, target) pairs
+//     loop edges    count, then (from, to, kind) triples, kind is a u8:
+//                   0: Entry, 1: PlusEntry, 2: Back (see `LoopEdge`)
+//     count edges   count, then (from, to, group) triples, for `run_counts`
+//
+// Charsets start with a tag byte:
+//
+//     0: SingleChar(char)
+//     1: Range(lo, hi)
+//     2: AnyChar
+//     3: Diff(include, exclude)
+//     4: Union(count, charsets)
+//     5: Epsilon
+//
+// Versions:
+//
+//     1: no start state, automata start at state 0
+//     2: adds `start`
+//     3: adds the priority of accepting states
+//     4: adds loop edges, for `run_with_repeat_cap`
+//     5: adds count edges
+
+use std::char;
+use std::collections::HashMap;
+use std::fmt;
+
+use repeat_cap::LoopEdge;
+use {CharSet, NFA};
+
+const MAGIC: &'static [u8] = b"NLXA";
+const VERSION: u8 = 5;
+
+// Limits recursion when decoding nested charsets, so that a malicious buffer can't overflow the
+// stack
+const MAX_CHARSET_DEPTH: usize = 1000;
+
+/// Errors of `NFA::encode`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    /// A state number, tag, priority or count doesn't fit in a `u32`.
+    TooLarge(usize),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &EncodeError::TooLarge(n) => write!(f, "{} doesn't fit in u32", n),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer ended in the middle of the encoding.
+    UnexpectedEnd,
+
+    /// The buffer doesn't start with the magic bytes.
+    BadMagic,
+
+    UnsupportedVersion(u8),
+
+    InvalidCharSetTag(u8),
+
+    /// A charset contains a value that's not a Unicode scalar value.
+    InvalidChar(u32),
+
+    /// The start state is not a state of the automaton.
+    InvalidStart(u32),
+
+    /// Charsets are nested deeper than the decoder allows.
+    TooDeep,
+
+    InvalidLoopEdgeKind(u8),
+
+    /// There are bytes left after the encoding.
+    TrailingBytes,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &DecodeError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            &DecodeError::BadMagic => write!(f, "not an encoded NFA"),
+            &DecodeError::UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            &DecodeError::InvalidCharSetTag(t) => write!(f, "invalid charset tag {}", t),
+            &DecodeError::InvalidChar(c) => write!(f, "invalid char {:#x}", c),
+            &DecodeError::InvalidStart(s) => write!(f, "invalid start state {}", s),
+            &DecodeError::TooDeep => write!(f, "charsets nested too deeply"),
+            &DecodeError::InvalidLoopEdgeKind(k) => write!(f, "invalid loop edge kind {}", k),
+            &DecodeError::TrailingBytes => write!(f, "trailing bytes after the NFA"),
+        }
+    }
+}
+
+impl<D> NFA<D> {
+    /// Encodes the automaton (but not its current states) into a compact binary format. Fails if
+    /// a state number doesn't fit in a `u32`.
+    ///
+    /// State data (see `NFABuilder::add_rule_with_data`) is not encoded, as `D` can be any type.
+    /// `decode` returns an `NFA<()>`, so the data has to be attached again after decoding.
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        write_usize(&mut buf, self.start)?;
+
+        // Sort states so that the encoding is deterministic
+        let mut accepting: Vec<(usize, usize)> = self.accepting.iter().map(|(s, t)| (*s, *t)).collect();
+        accepting.sort();
+        write_usize(&mut buf, accepting.len())?;
+        for (state, tag) in accepting {
+            write_usize(&mut buf, state)?;
+            write_usize(&mut buf, tag)?;
+            write_usize(&mut buf, self.accepting_priority(state).unwrap())?;
+        }
+
+        let mut states: Vec<&usize> = self.transitions.keys().collect();
+        states.sort();
+        write_usize(&mut buf, states.len())?;
+        for state in states {
+            let ts = &self.transitions[state];
+            write_usize(&mut buf, *state)?;
+            write_usize(&mut buf, ts.len())?;
+            for &(ref cs, target) in ts {
+                write_charset(&mut buf, cs)?;
+                write_usize(&mut buf, target)?;
+            }
+        }
+
+        let mut loop_edges: Vec<(&(usize, usize), &LoopEdge)> = self.loop_edges.iter().collect();
+        loop_edges.sort_by_key(|&(edge, _)| *edge);
+        write_usize(&mut buf, loop_edges.len())?;
+        for (&(from, to), kind) in loop_edges {
+            write_usize(&mut buf, from)?;
+            write_usize(&mut buf, to)?;
+            buf.push(match kind {
+                &LoopEdge::Entry => 0,
+                &LoopEdge::PlusEntry => 1,
+                &LoopEdge::Back => 2,
+            });
+        }
+
+        let mut count_edges: Vec<(&(usize, usize), &usize)> = self.count_edges.iter().collect();
+        count_edges.sort();
+        write_usize(&mut buf, count_edges.len())?;
+        for (&(from, to), group) in count_edges {
+            write_usize(&mut buf, from)?;
+            write_usize(&mut buf, to)?;
+            write_usize(&mut buf, *group)?;
+        }
+
+        Ok(buf)
+    }
+}
+
+impl NFA {
+    /// Decodes an automaton encoded with `encode`. The result has no state data.
+    pub fn decode(bytes: &[u8]) -> Result<NFA, DecodeError> {
+        let mut decoder = Decoder { bytes: bytes, pos: 0 };
+
+        if decoder.take(MAGIC.len())? != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = decoder.u8()?;
+        if version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let start = decoder.u32()?;
+
+        let mut accepting = HashMap::new();
+        let mut priorities = HashMap::new();
+        for _ in 0 .. decoder.u32()? {
+            let state = decoder.u32()? as usize;
+            let tag = decoder.u32()? as usize;
+            let priority = decoder.u32()? as usize;
+            accepting.insert(state, tag);
+            if priority != 0 {
+                priorities.insert(state, priority);
+            }
+        }
+
+        let mut transitions = HashMap::new();
+        for _ in 0 .. decoder.u32()? {
+            let state = decoder.u32()? as usize;
+            let n_transitions = decoder.u32()?;
+            // Don't trust the count for preallocation
+            let mut ts = Vec::new();
+            for _ in 0 .. n_transitions {
+                let cs = decoder.charset(0)?;
+                let target = decoder.u32()? as usize;
+                ts.push((cs, target));
+            }
+            transitions.insert(state, ts);
+        }
+
+        let mut loop_edges = HashMap::new();
+        for _ in 0 .. decoder.u32()? {
+            let from = decoder.u32()? as usize;
+            let to = decoder.u32()? as usize;
+            let kind = match decoder.u8()? {
+                0 => LoopEdge::Entry,
+                1 => LoopEdge::PlusEntry,
+                2 => LoopEdge::Back,
+                kind => return Err(DecodeError::InvalidLoopEdgeKind(kind)),
+            };
+            loop_edges.insert((from, to), kind);
+        }
+
+        let mut count_edges = HashMap::new();
+        for _ in 0 .. decoder.u32()? {
+            let from = decoder.u32()? as usize;
+            let to = decoder.u32()? as usize;
+            let group = decoder.u32()? as usize;
+            count_edges.insert((from, to), group);
+        }
+
+        if decoder.pos != bytes.len() {
+            return Err(DecodeError::TrailingBytes);
+        }
+
+        let mut nfa = NFA::new(transitions, accepting);
+        nfa.priorities = priorities;
+        nfa.loop_edges = loop_edges;
+        nfa.count_edges = count_edges;
+        if start != 0 {
+            if !nfa.has_state(start as usize) {
+                return Err(DecodeError::InvalidStart(start));
+            }
+            nfa.set_start(start as usize);
+        }
+        Ok(nfa)
+    }
+}
+
+fn write_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn write_usize(buf: &mut Vec<u8>, n: usize) -> Result<(), EncodeError> {
+    if n > u32::max_value() as usize {
+        return Err(EncodeError::TooLarge(n));
+    }
+    write_u32(buf, n as u32);
+    Ok(())
+}
+
+fn write_charset(buf: &mut Vec<u8>, cs: &CharSet) -> Result<(), EncodeError> {
+    match cs {
+        &CharSet::SingleChar(c) => {
+            buf.push(0);
+            write_u32(buf, c as u32);
+        }
+        &CharSet::Range { lo, hi } => {
+            buf.push(1);
+            write_u32(buf, lo as u32);
+            write_u32(buf, hi as u32);
+        }
+        &CharSet::AnyChar => {
+            buf.push(2);
+        }
+        &CharSet::Diff { ref include, ref exclude } => {
+            buf.push(3);
+            write_charset(buf, include)?;
+            write_charset(buf, exclude)?;
+        }
+        &CharSet::Union(ref css) => {
+            buf.push(4);
+            write_usize(buf, css.len())?;
+            for cs in css {
+                write_charset(buf, cs)?;
+            }
+        }
+        &CharSet::Epsilon => {
+            buf.push(5);
+        }
+        &CharSet::Predicate(_) => {
+            // Functions can't be encoded, encode the chars they match instead
+            write_charset(buf, &CharSet::from_ranges(&cs.to_ranges()))?;
+        }
+    }
+    Ok(())
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() - self.pos < n {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let ret = &self.bytes[self.pos .. self.pos + n];
+        self.pos += n;
+        Ok(ret)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn char(&mut self) -> Result<char, DecodeError> {
+        let n = self.u32()?;
+        char::from_u32(n).ok_or(DecodeError::InvalidChar(n))
+    }
+
+    fn charset(&mut self, depth: usize) -> Result<CharSet, DecodeError> {
+        if depth > MAX_CHARSET_DEPTH {
+            return Err(DecodeError::TooDeep);
+        }
+        match self.u8()? {
+            0 => Ok(CharSet::SingleChar(self.char()?)),
+            1 => {
+                let lo = self.char()?;
+                let hi = self.char()?;
+                Ok(CharSet::Range { lo: lo, hi: hi })
+            }
+            2 => Ok(CharSet::AnyChar),
+            3 => {
+                let include = self.charset(depth + 1)?;
+                let exclude = self.charset(depth + 1)?;
+                Ok(CharSet::Diff { include: Box::new(include), exclude: Box::new(exclude) })
+            }
+            4 => {
+                let mut css = Vec::new();
+                for _ in 0 .. self.u32()? {
+                    css.push(self.charset(depth + 1)?);
+                }
+                Ok(CharSet::Union(css))
+            }
+            5 => Ok(CharSet::Epsilon),
+            tag => Err(DecodeError::InvalidCharSetTag(tag)),
+        }
+    }
+}
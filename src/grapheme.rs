@@ -0,0 +1,35 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use NFA;
+
+/// Runs an NFA over the grapheme clusters (user-perceived characters) of the input instead of its
+/// `char`s.
+///
+/// This is approximate: a `CharSet` can only test a single `char`, so each cluster is fed to the
+/// NFA as its first `char`, and the rest of the cluster (combining marks, emoji modifiers, the
+/// second half of a flag) is ignored. For example `.` matches `"e\u{301}"` (an `e` with a
+/// combining acute accent) once, and `e` matches it too.
+pub struct GraphemeNFA {
+    nfa: NFA,
+}
+
+impl GraphemeNFA {
+    pub fn new(nfa: NFA) -> GraphemeNFA {
+        GraphemeNFA { nfa: nfa }
+    }
+
+    pub fn run(&mut self, input: &str) -> bool {
+        for cluster in input.graphemes(true) {
+            self.nfa.feed(cluster.chars().next().unwrap());
+        }
+        self.nfa.check_accepting()
+    }
+
+    pub fn reset(&mut self) {
+        self.nfa.reset();
+    }
+
+    pub fn into_inner(self) -> NFA {
+        self.nfa
+    }
+}
@@ -0,0 +1,166 @@
+// Building `Regex`s from JSON descriptions, for lexer specs generated by other tools. A regex is
+// one of:
+//
+//     "eps"
+//     {"lit": "abc"}
+//     {"charset": <charset>}
+//     {"seq": [<regex>, ...]}      (an empty sequence is "eps")
+//     {"or": [<regex>, ...]}       (needs at least one alternative)
+//     {"star": <regex>}, {"plus": <regex>}, {"ques": <regex>}
+//
+// and a charset is one of:
+//
+//     "any"
+//     {"char": "a"}
+//     {"range": ["a", "z"]}
+//     {"union": [<charset>, ...]}
+//     {"diff": {"include": <charset>, "exclude": <charset>}}
+
+use std::fmt;
+
+use serde_json::{self, Map, Value};
+
+use {CharSet, Regex};
+
+#[derive(Debug)]
+pub enum JsonError {
+    /// The input is not valid JSON.
+    Syntax(serde_json::Error),
+
+    /// The input is valid JSON, but doesn't describe a regex.
+    Schema(String),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &JsonError::Syntax(ref err) => write!(f, "{}", err),
+            &JsonError::Schema(ref msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Regex {
+    pub fn from_json(json: &str) -> Result<Regex, JsonError> {
+        let value = serde_json::from_str(json).map_err(JsonError::Syntax)?;
+        regex_from_value(&value)
+    }
+}
+
+fn schema_error<A>(msg: String) -> Result<A, JsonError> {
+    Err(JsonError::Schema(msg))
+}
+
+// Returns the only key and its value in a single-key object
+fn single_entry<'a>(value: &'a Value, what: &str) -> Result<(&'a str, &'a Value), JsonError> {
+    let obj: &Map<String, Value> = match value.as_object() {
+        Some(obj) => obj,
+        None => return schema_error(format!("expected a {}, found {}", what, value)),
+    };
+    if obj.len() != 1 {
+        return schema_error(format!("expected a {} with a single key, found {}", what, value));
+    }
+    let (key, value) = obj.iter().next().unwrap();
+    Ok((key, value))
+}
+
+fn array<'a>(value: &'a Value, key: &str) -> Result<&'a Vec<Value>, JsonError> {
+    match value.as_array() {
+        Some(values) => Ok(values),
+        None => schema_error(format!("expected an array for \"{}\", found {}", key, value)),
+    }
+}
+
+fn single_char(value: &Value, key: &str) -> Result<char, JsonError> {
+    if let Some(s) = value.as_str() {
+        let mut chars = s.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(c);
+        }
+    }
+    schema_error(format!("expected a single-char string for \"{}\", found {}", key, value))
+}
+
+fn regex_from_value(value: &Value) -> Result<Regex, JsonError> {
+    if value.as_str() == Some("eps") {
+        return Ok(Regex::Eps);
+    }
+
+    let (key, arg) = single_entry(value, "regex")?;
+    match key {
+        "lit" => {
+            match arg.as_str() {
+                Some(s) => Ok(Regex::literal(s)),
+                None => schema_error(format!("expected a string for \"lit\", found {}", arg)),
+            }
+        }
+        "charset" => Ok(Regex::CharSet(charset_from_value(arg)?)),
+        "seq" => {
+            let mut rs = array(arg, key)?.iter().rev();
+            let mut ret = match rs.next() {
+                Some(r) => regex_from_value(r)?,
+                None => return Ok(Regex::Eps),
+            };
+            for r in rs {
+                ret = Regex::seq(regex_from_value(r)?, ret);
+            }
+            Ok(ret)
+        }
+        "or" => {
+            let mut rs = array(arg, key)?.iter().rev();
+            let mut ret = match rs.next() {
+                Some(r) => regex_from_value(r)?,
+                None => return schema_error("\"or\" needs at least one alternative".to_owned()),
+            };
+            for r in rs {
+                ret = Regex::or(regex_from_value(r)?, ret);
+            }
+            Ok(ret)
+        }
+        "star" => Ok(Regex::star(regex_from_value(arg)?)),
+        "plus" => Ok(Regex::plus(regex_from_value(arg)?)),
+        "ques" => Ok(Regex::ques(regex_from_value(arg)?)),
+        _ => schema_error(format!("unknown regex \"{}\"", key)),
+    }
+}
+
+fn charset_from_value(value: &Value) -> Result<CharSet, JsonError> {
+    if value.as_str() == Some("any") {
+        return Ok(CharSet::AnyChar);
+    }
+
+    let (key, arg) = single_entry(value, "charset")?;
+    match key {
+        "char" => Ok(CharSet::SingleChar(single_char(arg, key)?)),
+        "range" => {
+            let bounds = array(arg, key)?;
+            if bounds.len() != 2 {
+                return schema_error(format!("expected [lo, hi] for \"range\", found {}", arg));
+            }
+            let lo = single_char(&bounds[0], key)?;
+            let hi = single_char(&bounds[1], key)?;
+            if lo > hi {
+                return schema_error(format!("invalid range {}", arg));
+            }
+            Ok(CharSet::Range { lo: lo, hi: hi })
+        }
+        "union" => {
+            let mut css = vec![];
+            for cs in array(arg, key)? {
+                css.push(charset_from_value(cs)?);
+            }
+            Ok(CharSet::Union(css))
+        }
+        "diff" => {
+            let (include, exclude) = match (arg.get("include"), arg.get("exclude")) {
+                (Some(include), Some(exclude)) => (include, exclude),
+                _ => return schema_error(format!("expected include and exclude for \"diff\", found {}", arg)),
+            };
+            Ok(CharSet::Diff {
+                include: Box::new(charset_from_value(include)?),
+                exclude: Box::new(charset_from_value(exclude)?),
+            })
+        }
+        _ => schema_error(format!("unknown charset \"{}\"", key)),
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::str::Chars;
+
+use char_classes::CharClasses;
+use NFA;
+
+/// A DFA built from an NFA on demand. DFA states are sets of NFA states, and they're computed
+/// (by stepping the NFA) the first time a transition is taken. Later runs reuse the cached
+/// transitions, so after warming up matching doesn't need to touch the NFA.
+///
+/// Transitions are cached per char class (see `CharClasses`) rather than per char. The number of
+/// cached states can be bounded with `set_cache_limit`.
+pub struct LazyDfa<D = ()> {
+    nfa: NFA<D>,
+    classes: CharClasses,
+    // DFA state id -> sorted NFA states. The start state is always the first one.
+    states: Vec<Vec<usize>>,
+    state_ids: HashMap<Vec<usize>, usize>,
+    accepting: Vec<bool>,
+    // (DFA state, char class) -> DFA state
+    transitions: HashMap<(usize, usize), usize>,
+    start: usize,
+    current: usize,
+    cache_limit: usize,
+    flushes: usize,
+    // Reused for sorting the NFA states of a new DFA state
+    scratch: Vec<usize>,
+}
+
+impl<D> LazyDfa<D> {
+    pub fn new(mut nfa: NFA<D>) -> LazyDfa<D> {
+        nfa.reset();
+        let mut dfa = LazyDfa {
+            classes: CharClasses::from_nfas(&[&nfa]),
+            nfa: nfa,
+            states: vec![],
+            state_ids: HashMap::new(),
+            accepting: vec![],
+            transitions: HashMap::new(),
+            start: 0,
+            current: 0,
+            cache_limit: usize::MAX,
+            flushes: 0,
+            scratch: vec![],
+        };
+        dfa.start = dfa.add_state();
+        dfa.current = dfa.start;
+        dfa
+    }
+
+    /// Sets the maximum number of cached DFA states. When a new state would go over the limit the
+    /// cache is flushed: all states other than the start state, and all transitions, are dropped
+    /// and computed again when needed. Unlimited by default.
+    pub fn set_cache_limit(&mut self, limit: usize) {
+        self.cache_limit = limit;
+    }
+
+    pub fn run(&mut self, mut chars: Chars) -> bool {
+        loop {
+            match chars.next() {
+                None => {
+                    return self.check_accepting();
+                }
+                Some(c) => {
+                    self.feed(c);
+                }
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.start;
+    }
+
+    pub fn feed(&mut self, c: char) {
+        let class = self.classes.class_of(c);
+        let current = self.current;
+        self.current = match self.transitions.get(&(current, class)) {
+            Some(next) => *next,
+            None => {
+                self.nfa.cur_states.clear();
+                self.nfa.cur_states.extend(&self.states[current]);
+                self.nfa.step(self.classes.representative(class));
+                let flushes = self.flushes;
+                let next = self.add_state();
+                // After a flush `current` may be a different state
+                if self.flushes == flushes {
+                    self.transitions.insert((current, class), next);
+                }
+                next
+            }
+        };
+    }
+
+    pub fn check_accepting(&self) -> bool {
+        self.accepting[self.current]
+    }
+
+    /// Number of DFA states computed so far.
+    pub fn num_states(&self) -> usize {
+        self.states.len()
+    }
+
+    /// Number of times the cache was flushed because of the cache limit.
+    pub fn num_flushes(&self) -> usize {
+        self.flushes
+    }
+
+    // Returns id of the DFA state for the NFA's current states, adding it if it's new.
+    fn add_state(&mut self) -> usize {
+        self.scratch.clear();
+        self.scratch.extend(self.nfa.cur_states.iter().cloned());
+        self.scratch.sort();
+        if let Some(id) = self.state_ids.get(&self.scratch) {
+            return *id;
+        }
+        if self.states.len() >= self.cache_limit && !self.states.is_empty() {
+            self.flush();
+        }
+        let id = self.states.len();
+        self.accepting.push(self.nfa.check_accepting());
+        self.states.push(self.scratch.clone());
+        self.state_ids.insert(self.scratch.clone(), id);
+        id
+    }
+
+    // Drops all states but the start state, and all transitions
+    fn flush(&mut self) {
+        self.states.truncate(1);
+        self.accepting.truncate(1);
+        self.state_ids.retain(|_, id| *id == 0);
+        self.transitions.clear();
+        self.flushes += 1;
+    }
+}
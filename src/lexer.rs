@@ -0,0 +1,215 @@
+use std::fmt;
+use std::io;
+use std::io::BufRead;
+use std::ops::Range;
+
+use {NFA, NFABuilder, Regex};
+
+/// A lexer that splits input into tokens using maximal munch: at each position the rule with the
+/// longest match wins, and when several rules match the same length the rule that comes first
+/// wins. Empty matches are ignored.
+///
+/// Skip rules (e.g. for whitespace and comments) take part in maximal munch like the token rules,
+/// but their matches are consumed without yielding a token.
+pub struct Lexer<T> {
+    nfa: NFA,
+    // Tokens of the token rules. Rule indices after the token rules are skip rules.
+    tokens: Vec<T>,
+    // When set, `Tokens` skips the char after an error instead of stopping
+    recover: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct LexError {
+    /// Byte offset of the first char that couldn't be lexed.
+    pub pos: usize,
+}
+
+impl<T: Clone> Lexer<T> {
+    pub fn new(rules: Vec<(Regex, T)>) -> Lexer<T> {
+        Lexer::new_with_skips(rules, vec![])
+    }
+
+    /// Like `new`, but also takes rules whose matches are skipped. When a token rule and a skip
+    /// rule match the same length the token rule wins.
+    pub fn new_with_skips(rules: Vec<(Regex, T)>, skip_rules: Vec<Regex>) -> Lexer<T> {
+        let mut regexes = Vec::with_capacity(rules.len() + skip_rules.len());
+        let mut tokens = Vec::with_capacity(rules.len());
+        for (regex, token) in rules {
+            regexes.push(regex);
+            tokens.push(token);
+        }
+        regexes.extend(skip_rules);
+        Lexer {
+            nfa: NFABuilder::build_rules(&regexes),
+            tokens: tokens,
+            recover: false,
+        }
+    }
+
+    /// In recovery mode the iterator returned by `tokenize` doesn't stop after an error: it yields
+    /// the error, skips the char at the error position, and continues lexing after it. This
+    /// reports all of the errors in the input in one pass. Off by default.
+    pub fn set_recover(&mut self, recover: bool) {
+        self.recover = recover;
+    }
+
+    /// Returns an iterator over the tokens of `input` and their byte ranges. The iterator stops
+    /// after the first error, unless in recovery mode (see `set_recover`).
+    pub fn tokenize<'a>(&'a self, input: &'a str) -> Tokens<'a, T> {
+        Tokens {
+            lexer: self,
+            nfa: self.nfa.clone(),
+            input: input,
+            pos: 0,
+            failed: false,
+        }
+    }
+
+    /// Returns, for each length (in bytes) of a prefix of `input` that some rule matches, the
+    /// indices of all the rules that match it, sorted. Skip rules are included, with indices after
+    /// the token rules. Unlike `tokenize` this doesn't pick a winner, for consumers that handle
+    /// ambiguous tokenizations themselves. Empty matches are ignored.
+    pub fn accepting_by_length(&self, input: &str) -> Vec<(usize, Vec<usize>)> {
+        let mut nfa = self.nfa.clone();
+        nfa.reset();
+        let mut ret = vec![];
+        for (i, c) in input.char_indices() {
+            nfa.feed(c);
+            if nfa.cur_states.is_empty() {
+                break;
+            }
+            let tags = nfa.accepting_tags();
+            if !tags.is_empty() {
+                ret.push((i + c.len_utf8(), tags));
+            }
+        }
+        ret
+    }
+}
+
+// Returns the length and the rule index of the longest match at the beginning of `input`.
+fn longest_match(nfa: &mut NFA, input: &str) -> Option<(usize, usize)> {
+    nfa.reset();
+    let mut longest = None;
+    for (i, c) in input.char_indices() {
+        nfa.feed(c);
+        if nfa.cur_states.is_empty() {
+            break;
+        }
+        if let Some(tag) = nfa.first_accepting_tag() {
+            longest = Some((i + c.len_utf8(), tag));
+        }
+    }
+    longest
+}
+
+pub struct Tokens<'a, T: 'a> {
+    lexer: &'a Lexer<T>,
+    nfa: NFA,
+    input: &'a str,
+    pos: usize,
+    failed: bool,
+}
+
+impl<'a, T: Clone> Iterator for Tokens<'a, T> {
+    type Item = Result<(T, Range<usize>), LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.failed || self.pos == self.input.len() {
+                return None;
+            }
+
+            match longest_match(&mut self.nfa, &self.input[self.pos ..]) {
+                Some((len, tag)) => {
+                    let start = self.pos;
+                    self.pos += len;
+                    match self.lexer.tokens.get(tag) {
+                        Some(token) => { return Some(Ok((token.clone(), start .. self.pos))); }
+                        None => { continue; } // skip rule
+                    }
+                }
+                None => {
+                    let pos = self.pos;
+                    if self.lexer.recover {
+                        self.pos += self.input[pos ..].chars().next().unwrap().len_utf8();
+                    } else {
+                        self.failed = true;
+                    }
+                    return Some(Err(LexError { pos: pos }));
+                }
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Lexes input line by line. Tokens can't span lines: a token left incomplete at the end of a line
+/// is reported as an error for that line.
+pub struct LineLexer<T> {
+    lexer: Lexer<T>,
+}
+
+#[derive(Debug)]
+pub enum LineLexError {
+    Io(io::Error),
+    Lex {
+        /// Index of the line, starting from 0.
+        line: usize,
+        error: LexError,
+    },
+}
+
+impl fmt::Display for LineLexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &LineLexError::Io(ref err) => write!(f, "{}", err),
+            &LineLexError::Lex { line, ref error } =>
+                write!(f, "can't lex line {} at offset {}", line, error.pos),
+        }
+    }
+}
+
+impl<T: Clone> LineLexer<T> {
+    pub fn new(lexer: Lexer<T>) -> LineLexer<T> {
+        LineLexer { lexer: lexer }
+    }
+
+    /// Lexes a single line. Token ranges are byte offsets in `line`.
+    pub fn lex_line(&self, line: &str) -> Result<Vec<(T, Range<usize>)>, LexError> {
+        self.lexer.tokenize(line).collect()
+    }
+
+    /// Returns an iterator over tokens of the lines of `source`. A lexing error in a line doesn't
+    /// stop the iteration.
+    pub fn lines<'a, R: BufRead>(&'a self, source: R) -> Lines<'a, T, R> {
+        Lines {
+            lexer: self,
+            lines: source.lines(),
+            line: 0,
+        }
+    }
+}
+
+pub struct Lines<'a, T: 'a, R> {
+    lexer: &'a LineLexer<T>,
+    lines: io::Lines<R>,
+    line: usize,
+}
+
+impl<'a, T: Clone, R: BufRead> Iterator for Lines<'a, T, R> {
+    type Item = Result<Vec<(T, Range<usize>)>, LineLexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = match self.lines.next() {
+            None => { return None; }
+            Some(Err(err)) => { return Some(Err(LineLexError::Io(err))); }
+            Some(Ok(line)) => line,
+        };
+        let line_idx = self.line;
+        self.line += 1;
+        Some(self.lexer.lex_line(&line).map_err(|err| LineLexError::Lex { line: line_idx, error: err }))
+    }
+}
@@ -1,350 +1,3561 @@
+#[cfg(feature = "compress")]
+extern crate flate2;
+#[cfg(feature = "stream")]
+extern crate futures;
+extern crate lalrpop_util;
+#[cfg(feature = "parallel")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+#[cfg(feature = "nfc")]
+extern crate unicode_normalization;
+#[cfg(feature = "grapheme")]
+extern crate unicode_segmentation;
+
+#[macro_use]
+mod macros;
+
+mod batch;
+pub mod char_classes;
 pub mod charset;
 pub mod charset_parser;
+mod codegen;
+pub mod complexity;
+#[cfg(feature = "compress")]
+pub mod compress;
+mod count_group;
+mod difference;
+pub mod encode;
+pub mod lazy_dfa;
+#[cfg(feature = "grapheme")]
+pub mod grapheme;
+#[cfg(feature = "serde")]
+pub mod json;
+pub mod lexer;
+pub mod literal_set;
+pub mod lookahead;
+#[cfg(feature = "nfc")]
+pub mod nfc;
+#[cfg(feature = "profile")]
+pub mod profile;
+mod range_index;
+mod repeat_cap;
+pub mod search;
+mod simplify;
+mod sink;
+pub mod sliding;
+mod state_elimination;
+#[cfg(feature = "stream")]
+pub mod stream;
+#[cfg(test)]
+mod test_util;
+pub mod visitor;
 
+use std::collections::BTreeSet;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::iter::FromIterator;
+use std::ops::{Add, BitOr, RangeInclusive};
 use std::str::Chars;
 
-pub use charset::CharSet;
+use range_index::RangeIndex;
+use repeat_cap::LoopEdge;
 
-pub struct NFA {
+pub use char_classes::{CharClasses, ClassifiedInput};
+pub use charset::CharSet;
+pub use complexity::ComplexityReport;
+#[cfg(feature = "compress")]
+pub use compress::ReadCompressedError;
+pub use encode::{DecodeError, EncodeError};
+#[cfg(feature = "serde")]
+pub use json::JsonError;
+pub use lazy_dfa::LazyDfa;
+pub use lexer::{Lexer, LineLexer};
+pub use literal_set::LiteralSet;
+pub use lookahead::Lookahead;
+#[cfg(feature = "profile")]
+pub use profile::TestCounts;
+pub use search::MatchPolicy;
+pub use sliding::SlidingMatcher;
+#[cfg(feature = "stream")]
+pub use stream::RunStream;
+pub use visitor::{RegexFolder, RegexVisitor};
+
+/// An NFA. `D` is the type of data that can be attached to states, see `NFA::state_data`.
+#[derive(Clone)]
+pub struct NFA<D = ()> {
     cur_states: HashSet<usize>,
+    // State that `reset` starts from
+    start: usize,
+    // Epsilon closure of `start`, so that `reset` doesn't need to compute it
+    initial_states: HashSet<usize>,
     transitions: HashMap<usize, Vec<(CharSet, usize)>>,
-    accepting: HashSet<usize>,
+    // Whether `transitions` has any epsilon transitions. When it doesn't `take_epsilons` can be
+    // skipped. Needs to be kept in sync with `transitions`.
+    has_epsilons: bool,
+    // Char transitions of each state, indexed for `step`. Needs to be kept in sync with
+    // `transitions`.
+    range_index: HashMap<usize, RangeIndex>,
+    // Maps accepting states to the index of the rule they belong to. Smaller indices have higher
+    // priority.
+    accepting: HashMap<usize, usize>,
+    // Priorities of accepting states within their rules. States reached through the left branch
+    // of an `Or` have smaller priorities than the ones reached through the right branch. States
+    // that are not in the map have priority 0.
+    priorities: HashMap<usize, usize>,
+    // When set, `feed` panics if the automaton is already dead
+    strict: bool,
+    state_data: HashMap<usize, D>,
+    // Epsilon edges of `Star` and `Plus` loops, for `run_with_repeat_cap`
+    loop_edges: HashMap<(usize, usize), LoopEdge>,
+    // Epsilon edges taken when a `CountGroup` matches, mapped to the group ids, for `run_counts`
+    count_edges: HashMap<(usize, usize), usize>,
+    // Targets of the char transitions of each state, indexed by char class, for `step_class`.
+    // Built by `build_class_table`.
+    class_table: Option<HashMap<usize, Vec<Vec<usize>>>>,
+}
+
+/// Result of feeding a char to an NFA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    /// Some states are still active.
+    Alive,
+
+    /// No states are active, so the automaton can't accept this input or any extension of it.
+    Dead,
+}
+
+/// Why `NFA::explain` rejected its input. Positions are byte offsets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatchFailure {
+    /// The char at `pos` can't appear there, and the input before it is not accepted.
+    UnexpectedChar { pos: usize, c: char },
+
+    /// The input is a prefix of an accepted string. `expected` are the sets of chars that could
+    /// come next, ordered by the states they leave from.
+    UnexpectedEnd { expected: Vec<CharSet> },
+
+    /// The input up to `pos` is accepted, but it can't be extended with the rest of the input.
+    TrailingInput { pos: usize },
+}
+
+/// Result of `NFA::status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchStatus {
+    /// The input so far is accepted.
+    Accepting,
+
+    /// The input so far is not accepted, but an extension of it may be.
+    Partial,
+
+    /// Neither the input so far nor any extension of it can be accepted.
+    Dead,
 }
 
 impl NFA {
-    pub fn new(transitions: HashMap<usize, Vec<(CharSet, usize)>>, accepting: HashSet<usize>) -> NFA {
+    pub fn new(transitions: HashMap<usize, Vec<(CharSet, usize)>>, accepting: HashMap<usize, usize>) -> NFA {
+        NFA::with_state_data(transitions, accepting, HashMap::new())
+    }
+}
+
+impl<D> NFA<D> {
+    /// Returns a DFA that computes its states from this NFA as they're needed.
+    pub fn determinize_lazy(self) -> LazyDfa<D> {
+        LazyDfa::new(self)
+    }
+
+    /// Like `new`, but also attaches data to states.
+    pub fn with_state_data(transitions: HashMap<usize, Vec<(CharSet, usize)>>,
+                           accepting: HashMap<usize, usize>,
+                           state_data: HashMap<usize, D>) -> NFA<D> {
+        let range_index = transitions.iter().map(|(s, ts)| (*s, RangeIndex::new(ts))).collect();
+        let has_epsilons = transitions.values().any(|ts| ts.iter().any(|&(ref cs, _)| *cs == CharSet::Epsilon));
         let mut nfa = NFA {
             cur_states: HashSet::new(),
+            start: 0,
+            initial_states: HashSet::new(),
             transitions: transitions,
+            has_epsilons: has_epsilons,
+            range_index: range_index,
             accepting: accepting,
+            priorities: HashMap::new(),
+            strict: false,
+            state_data: state_data,
+            loop_edges: HashMap::new(),
+            count_edges: HashMap::new(),
+            class_table: None,
         };
-        nfa.reset();
+        nfa.compute_initial_states();
         nfa
     }
 
-    pub fn run(&mut self, mut chars: Chars) -> bool {
-        loop {
-            match chars.next() {
-                None => {
-                    return self.check_accepting();
+    /// Sets the state that `reset` starts from, and resets. The start state is 0 by default.
+    /// Panics if the automaton has no state `start`.
+    pub fn set_start(&mut self, start: usize) {
+        assert!(self.has_state(start), "NFA::set_start: no state {}", start);
+        self.start = start;
+        self.compute_initial_states();
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Makes `state` accepting, for the rule with the given tag (see `accepting_tags`). If it's
+    /// already accepting its tag is replaced. E.g. marking the intermediate states of a regex
+    /// makes the automaton accept the prefixes of the regex. Panics if the automaton has no state
+    /// `state`.
+    pub fn add_accepting(&mut self, state: usize, tag: usize) {
+        assert!(self.has_state(state), "NFA::add_accepting: no state {}", state);
+        self.accepting.insert(state, tag);
+    }
+
+    /// Makes `state` non-accepting. Panics if the automaton has no state `state`.
+    pub fn remove_accepting(&mut self, state: usize) {
+        assert!(self.has_state(state), "NFA::remove_accepting: no state {}", state);
+        self.accepting.remove(&state);
+    }
+
+    // Whether the state has transitions, is the target of a transition, or is accepting
+    fn has_state(&self, state: usize) -> bool {
+        self.transitions.contains_key(&state) ||
+            self.accepting.contains_key(&state) ||
+            self.transitions.values().any(|ts| ts.iter().any(|&(_, target)| target == state))
+    }
+
+    /// Returns the data attached to `state`, if any.
+    pub fn state_data(&self, state: usize) -> Option<&D> {
+        self.state_data.get(&state)
+    }
+
+    pub fn run(&mut self, chars: Chars) -> bool {
+        self.run_with_lookbehind(chars, |_, _, _| true)
+    }
+
+    /// Like `run`, but feeds the chars with `step_with_lookbehind`, so `check` can block
+    /// transitions depending on the chars around the current position. This is the building block
+    /// for assertions like word boundaries: `check` tests the assertions attached to a state.
+    pub fn run_with_lookbehind<F>(&mut self, chars: Chars, mut check: F) -> bool
+        where F: FnMut(usize, Option<char>, char) -> bool
+    {
+        let mut prev = None;
+        for c in chars {
+            self.step_with_lookbehind(prev, c, &mut check);
+            prev = Some(c);
+        }
+        self.check_accepting()
+    }
+
+    /// Feeds `c`, which comes after `prev` (`None` at the start of the input). Only the current
+    /// states for which `check(state, prev, c)` returns true take their transitions on `c`.
+    pub fn step_with_lookbehind<F>(&mut self, prev: Option<char>, c: char, mut check: F)
+        where F: FnMut(usize, Option<char>, char) -> bool
+    {
+        self.step_states(c, &mut MatchStats::default(), |state| check(state, prev, c));
+    }
+
+    pub fn run_char_slice(&mut self, chars: &[char]) -> bool {
+        for c in chars {
+            self.step(*c);
+        }
+        self.check_accepting()
+    }
+
+    /// Returns the length (in chars) of the longest prefix of `chars` that is accepted. Like
+    /// `run`, starts from the current states.
+    pub fn find_char_slice(&mut self, chars: &[char]) -> Option<usize> {
+        let mut longest = if self.check_accepting() { Some(0) } else { None };
+        for (i, c) in chars.iter().enumerate() {
+            self.step(*c);
+            if self.cur_states.is_empty() {
+                break;
+            }
+            if self.check_accepting() {
+                longest = Some(i + 1);
+            }
+        }
+        longest
+    }
+
+    /// Returns the longest prefix of `input` that is accepted. Like `run`, starts from the current
+    /// states.
+    pub fn find_match<'a>(&mut self, input: &'a str) -> Option<&'a str> {
+        let mut longest = if self.check_accepting() { Some(0) } else { None };
+        for (i, c) in input.char_indices() {
+            self.step(c);
+            if self.cur_states.is_empty() {
+                break;
+            }
+            if self.check_accepting() {
+                longest = Some(i + c.len_utf8());
+            }
+        }
+        longest.map(|len| &input[.. len])
+    }
+
+    /// Resets the automaton and runs it over `input`. When the input is rejected, returns why, for
+    /// reporting to users.
+    pub fn explain(&mut self, input: &str) -> Result<(), MatchFailure> {
+        self.reset();
+        for (pos, c) in input.char_indices() {
+            let accepting = self.check_accepting();
+            self.step(c);
+            if self.cur_states.is_empty() {
+                return Err(if accepting {
+                    MatchFailure::TrailingInput { pos: pos }
+                } else {
+                    MatchFailure::UnexpectedChar { pos: pos, c: c }
+                });
+            }
+        }
+        if self.check_accepting() {
+            return Ok(());
+        }
+
+        // Visit the states in order, so that `expected` doesn't depend on the hash set's order
+        let states: BTreeSet<usize> = self.cur_states.iter().cloned().collect();
+        let mut expected: Vec<CharSet> = vec![];
+        for state in &states {
+            if let Some(ts) = self.transitions.get(state) {
+                for &(ref cs, _) in ts {
+                    if *cs != CharSet::Epsilon && !expected.contains(cs) {
+                        expected.push(cs.clone());
+                    }
                 }
+            }
+        }
+        Err(MatchFailure::UnexpectedEnd { expected: expected })
+    }
+
+    /// Resets both automata and runs them over `chars` in one pass. Returns whether each of them
+    /// accepts the input.
+    pub fn run_both<E>(&mut self, other: &mut NFA<E>, chars: Chars) -> (bool, bool) {
+        self.reset();
+        other.reset();
+        for c in chars {
+            self.step(c);
+            other.step(c);
+        }
+        (self.check_accepting(), other.check_accepting())
+    }
+
+    /// Like `run`, but also collects statistics about the cost of the run.
+    pub fn run_with_stats(&mut self, chars: Chars) -> (bool, MatchStats) {
+        let mut stats = MatchStats::default();
+        stats.max_active_states = self.cur_states.len();
+        for c in chars {
+            self.step_with_stats(c, &mut stats);
+        }
+        (self.check_accepting(), stats)
+    }
+
+    /// Like `run`, but calls `observer` after each char with the index of the char (in chars), the
+    /// char, and the states active after it. For instrumentation, e.g. finding the char where the
+    /// automaton died. Doesn't stop when the automaton dies.
+    pub fn run_with_observer<F: FnMut(usize, char, &HashSet<usize>)>(&mut self, chars: Chars, mut observer: F) -> bool {
+        for (i, c) in chars.enumerate() {
+            self.step(c);
+            observer(i, c, &self.cur_states);
+        }
+        self.check_accepting()
+    }
+
+    /// Like `run`, but also returns the number of chars consumed before the automaton died (i.e.
+    /// ended up with no active states). Returns the length of the input if it never dies.
+    pub fn run_progress<I: Iterator<Item = char>>(&mut self, chars: I) -> (bool, usize) {
+        let mut consumed = 0;
+        for c in chars {
+            self.step(c);
+            if self.cur_states.is_empty() {
+                break;
+            }
+            consumed += 1;
+        }
+        (self.check_accepting(), consumed)
+    }
+
+    /// Pulls chars from `next` and feeds them until the automaton dies or `next` returns `None`.
+    /// Returns the number of chars pulled (including the one that killed the automaton, if any),
+    /// and the length of the longest accepted prefix of them. Like `run`, starts from the current
+    /// states.
+    pub fn feed_until_dead<F: FnMut() -> Option<char>>(&mut self, mut next: F) -> (usize, Option<usize>) {
+        let mut pulled = 0;
+        let mut longest = if self.check_accepting() { Some(0) } else { None };
+        while !self.cur_states.is_empty() {
+            match next() {
+                None => break,
                 Some(c) => {
+                    pulled += 1;
                     self.step(c);
+                    if self.check_accepting() {
+                        longest = Some(pulled);
+                    }
+                }
+            }
+        }
+        (pulled, longest)
+    }
+
+    /// Merges (non-epsilon) transitions from the same state to the same target into a single
+    /// transition with a `Union` charset.
+    ///
+    /// Automata built from regexes don't have such transitions: each alternative of an `Or` ends in
+    /// its own state, to keep its priority (see `accepting_priority`). So this is for automata built
+    /// by hand with `NFA::new`.
+    pub fn merge_parallel_transitions(&mut self) {
+        // This doesn't change the targets of any char, so `range_index` stays valid
+        for ts in self.transitions.values_mut() {
+            // Charsets of transitions to each target, in order of first appearance
+            let mut merged: Vec<(Vec<CharSet>, usize)> = vec![];
+            let mut target_idxs: HashMap<usize, usize> = HashMap::new();
+            let mut epsilons = vec![];
+
+            for (cs, target) in ts.drain(..) {
+                if cs == CharSet::Epsilon {
+                    epsilons.push((cs, target));
+                    continue;
+                }
+                match target_idxs.entry(target) {
+                    Entry::Occupied(ent) => {
+                        merged[*ent.get()].0.push(cs);
+                    },
+                    Entry::Vacant(ent) => {
+                        ent.insert(merged.len());
+                        merged.push((vec![cs], target));
+                    },
+                }
+            }
+
+            for (mut css, target) in merged {
+                let cs = if css.len() == 1 { css.pop().unwrap() } else { CharSet::Union(css) };
+                ts.push((cs, target));
+            }
+            ts.extend(epsilons);
+        }
+    }
+
+    /// Returns the set of chars that appear in (non-epsilon) transitions, as merged ranges.
+    pub fn alphabet(&self) -> CharSet {
+        let mut css = vec![];
+        for ts in self.transitions.values() {
+            for &(ref cs, _) in ts {
+                if *cs != CharSet::Epsilon {
+                    css.push(cs.clone());
+                }
+            }
+        }
+        CharSet::from_ranges(&CharSet::Union(css).to_ranges())
+    }
+
+    /// Renders the transitions and accepting states as text that doesn't change with cosmetic
+    /// changes in state numbering, for snapshot tests. States are renumbered in BFS order from the
+    /// start state, visiting the transitions of a state ordered by their charsets, and charsets
+    /// are shown in canonical form. Unreachable states are omitted.
+    pub fn canonical_string(&self) -> String {
+        let sorted_transitions = |state: usize| -> Vec<(String, usize)> {
+            let mut ts: Vec<(String, usize)> = match self.transitions.get(&state) {
+                None => vec![],
+                Some(ts) => ts.iter().map(|&(ref cs, target)| (cs.to_string(), target)).collect(),
+            };
+            ts.sort_by(|t1, t2| t1.0.cmp(&t2.0));
+            ts
+        };
+
+        let mut numbers: HashMap<usize, usize> = HashMap::new();
+        let mut order: Vec<usize> = vec![];
+        let mut work: VecDeque<usize> = VecDeque::new();
+        numbers.insert(self.start, 0);
+        work.push_back(self.start);
+        while let Some(state) = work.pop_front() {
+            order.push(state);
+            for (_, target) in sorted_transitions(state) {
+                if !numbers.contains_key(&target) {
+                    numbers.insert(target, numbers.len());
+                    work.push_back(target);
                 }
             }
         }
+
+        let mut s = String::new();
+        for state in order {
+            for (cs, target) in sorted_transitions(state) {
+                s.push_str(&format!("{} -{}-> {}\n", numbers[&state], cs, numbers[&target]));
+            }
+            if let Some(tag) = self.accepting.get(&state) {
+                s.push_str(&format!("{} accepting {}\n", numbers[&state], tag));
+            }
+        }
+        s
     }
 
+    /// Resets the automaton to the start state. This reuses the memory of the current states, so
+    /// it doesn't allocate.
     pub fn reset(&mut self) {
         self.cur_states.clear();
-        self.cur_states.insert(0);
+        self.cur_states.extend(&self.initial_states);
+    }
+
+    // Computes `initial_states` from `start`. Also resets the automaton.
+    fn compute_initial_states(&mut self) {
+        self.cur_states.clear();
+        self.cur_states.insert(self.start);
         self.take_epsilons();
+        self.initial_states = self.cur_states.clone();
+    }
+
+    pub fn feed(&mut self, c: char) -> StepResult {
+        if self.strict {
+            assert!(!self.cur_states.is_empty(), "NFA::feed: automaton is dead");
+        }
+        self.step(c);
+        if self.cur_states.is_empty() {
+            StepResult::Dead
+        } else {
+            StepResult::Alive
+        }
+    }
+
+    /// In strict mode `feed` panics when called on a dead automaton, to catch loops that keep
+    /// feeding input after the result is already known. Off by default.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
     }
 
-    pub fn feed(&mut self, c: char) {
+    /// Like `feed`, but returns whether the set of active states changed.
+    pub fn step_tracked(&mut self, c: char) -> bool {
+        let old_states = self.cur_states.clone();
         self.step(c);
+        self.cur_states != old_states
+    }
+
+    /// Returns whether some extension of the input fed so far could be accepted, i.e. the
+    /// automaton is not dead.
+    pub fn can_continue(&self) -> bool {
+        !self.cur_states.is_empty()
+    }
+
+    pub fn status(&self) -> MatchStatus {
+        if self.check_accepting() {
+            MatchStatus::Accepting
+        } else if self.can_continue() {
+            MatchStatus::Partial
+        } else {
+            MatchStatus::Dead
+        }
     }
 
     pub fn check_accepting(&self) -> bool {
         for state in self.cur_states.iter() {
-            if self.accepting.contains(state) {
+            if self.accepting.contains_key(state) {
                 return true;
             }
         }
         false
     }
 
+    /// Returns the currently active accepting states, sorted.
+    pub fn active_accepting(&self) -> Vec<usize> {
+        let mut states: Vec<usize> =
+            self.cur_states.iter().cloned().filter(|state| self.accepting.contains_key(state)).collect();
+        states.sort();
+        states
+    }
+
+    /// Returns indices of the rules that are currently accepting, sorted.
+    pub fn accepting_tags(&self) -> Vec<usize> {
+        let mut tags = Vec::new();
+        for state in self.cur_states.iter() {
+            if let Some(tag) = self.accepting.get(state) {
+                tags.push(*tag);
+            }
+        }
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+
+    /// Returns the lowest index among the currently accepting rules, i.e. the rule that wins a
+    /// tie between matches of the same length.
+    pub fn first_accepting_tag(&self) -> Option<usize> {
+        let mut first = None;
+        for state in self.cur_states.iter() {
+            if let Some(tag) = self.accepting.get(state) {
+                first = match first {
+                    Some(first) if first <= *tag => Some(first),
+                    _ => Some(*tag),
+                };
+            }
+        }
+        first
+    }
+
+    /// Returns the priority of an accepting state within its rule, or `None` if the state is not
+    /// accepting. Smaller is higher priority: when a rule's regex is an `Or`, accepting states of
+    /// the left branch have smaller priorities than the ones of the right branch.
+    pub fn accepting_priority(&self, state: usize) -> Option<usize> {
+        if self.accepting.contains_key(&state) {
+            Some(self.priorities.get(&state).cloned().unwrap_or(0))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the currently active accepting state that wins ties: the one of the rule with the
+    /// lowest index, and within that rule the one with the highest priority (see
+    /// `accepting_priority`).
+    pub fn first_accepting_state(&self) -> Option<usize> {
+        let mut first: Option<(usize, usize, usize)> = None;
+        for state in self.cur_states.iter() {
+            if let Some(tag) = self.accepting.get(state) {
+                let key = (*tag, self.accepting_priority(*state).unwrap(), *state);
+                if first.map(|first| key < first).unwrap_or(true) {
+                    first = Some(key);
+                }
+            }
+        }
+        first.map(|(_, _, state)| state)
+    }
+
     fn step(&mut self, c: char) {
+        self.step_with_stats(c, &mut MatchStats::default());
+    }
+
+    fn step_with_stats(&mut self, c: char, stats: &mut MatchStats) {
+        self.step_states(c, stats, |_| true);
+    }
+
+    // Steps the current states for which `take(state)` returns true, and drops the rest
+    fn step_states<F: FnMut(usize) -> bool>(&mut self, c: char, stats: &mut MatchStats, mut take: F) {
         let mut new_states: HashSet<usize> = HashSet::with_capacity(self.cur_states.len());
         for cur_state in self.cur_states.iter() {
-            if let Some(ts) = self.transitions.get(cur_state) {
-                for &(ref cs, ref t) in ts {
-                    if cs.test(c) {
-                        new_states.insert(*t);
-                    }
-                }
+            if !take(*cur_state) {
+                continue;
             }
+            let targets = self.char_targets(*cur_state, c);
+            stats.transitions_taken += targets.len();
+            new_states.extend(targets);
         }
         std::mem::swap(&mut self.cur_states, &mut new_states);
 
-        self.take_epsilons();
+        stats.epsilon_closure_iterations += self.take_epsilons();
+        stats.steps += 1;
+        stats.max_active_states = std::cmp::max(stats.max_active_states, self.cur_states.len());
+    }
+
+    // Returns the number of states visited to reach the closure.
+    fn take_epsilons(&mut self) -> usize {
+        if !self.has_epsilons {
+            return 0;
+        }
+
+        let mut states = std::mem::take(&mut self.cur_states);
+        let work = states.iter().cloned().collect();
+        let visited = self.follow_epsilons(work, |&state| state, |_, target| {
+            if states.insert(target) { Some(target) } else { None }
+        });
+        self.cur_states = states;
+        visited
+    }
+
+    // Targets of the char transitions of `state` on `c`. Epsilon transitions are not in the range
+    // index, they're followed with `follow_epsilons`.
+    fn char_targets(&self, state: usize, c: char) -> &[usize] {
+        match self.range_index.get(&state) {
+            Some(index) => index.targets(c),
+            None => &[],
+        }
     }
 
-    fn take_epsilons(&mut self) {
-        let mut new_states = HashSet::with_capacity(self.cur_states.len());
-
-        loop {
-            for cur_state in self.cur_states.iter() {
-                if let Some(ts) = self.transitions.get(cur_state) {
-                    for &(ref cs, ref t) in ts.iter() {
-                        match cs {
-                            &CharSet::Epsilon => {
-                                if !self.cur_states.contains(t) {
-                                    new_states.insert(*t);
-                                }
-                            },
-                            _ => {}
-                        }
+    // Follows epsilon transitions from the items in `work`, for computing the closures of sets of
+    // states, or of states with extra data (e.g. counters). `state_of` returns the state of an
+    // item. `follow(item, target)` is called for each epsilon transition from the item's state,
+    // and returns the item for `target`, or `None` if it was already visited or the transition
+    // shouldn't be taken. Returned items are followed in turn. Returns the number of items visited.
+    fn follow_epsilons<T, S, F>(&self, mut work: Vec<T>, state_of: S, mut follow: F) -> usize
+        where S: Fn(&T) -> usize, F: FnMut(&T, usize) -> Option<T>
+    {
+        let mut visited = 0;
+        while let Some(item) = work.pop() {
+            visited += 1;
+            if let Some(ts) = self.transitions.get(&state_of(&item)) {
+                for &(ref cs, target) in ts {
+                    if *cs != CharSet::Epsilon {
+                        continue;
+                    }
+                    if let Some(next) = follow(&item, target) {
+                        work.push(next);
                     }
                 }
             }
+        }
+        visited
+    }
+}
+
+/// Counters collected by `NFA::run_with_stats`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MatchStats {
+    /// Number of chars consumed.
+    pub steps: usize,
+
+    /// Largest number of states active at once.
+    pub max_active_states: usize,
+
+    /// Number of (non-epsilon) transitions taken.
+    pub transitions_taken: usize,
+
+    /// Number of states visited while computing epsilon closures after steps.
+    pub epsilon_closure_iterations: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Regex {
+    Eps,
+    CharSet(CharSet),
+    Seq(Box<Regex>, Box<Regex>),
+    Or(Box<Regex>, Box<Regex>),
+    Star(Box<Regex>),
+    Plus(Box<Regex>),
+    Ques(Box<Regex>),
+    /// Matches the same as the inner regex, but `NFA::run_counts` counts how many times it
+    /// matched, under the given group id. E.g. `CountGroup(0, digit)` in a `Star` counts the
+    /// repetitions.
+    CountGroup(usize, Box<Regex>),
+}
+
+impl From<RangeInclusive<char>> for Regex {
+    fn from(range: RangeInclusive<char>) -> Regex {
+        Regex::CharSet(range.into())
+    }
+}
+
+/// `r1 + r2` is `Regex::seq(r1, r2)`.
+impl Add for Regex {
+    type Output = Regex;
+
+    fn add(self, other: Regex) -> Regex {
+        Regex::seq(self, other)
+    }
+}
+
+/// `r1 | r2` is `Regex::or(r1, r2)`.
+impl BitOr for Regex {
+    type Output = Regex;
+
+    fn bitor(self, other: Regex) -> Regex {
+        Regex::or(self, other)
+    }
+}
+
+impl Regex {
+    /// Builds an NFA for the regex. See `NFABuilder` for more control over the construction.
+    ///
+    /// ```
+    /// use notlex::{CharSet, Regex};
+    ///
+    /// let regex = Regex::star(Regex::CharSet(CharSet::SingleChar('a')));
+    /// let mut nfa = regex.to_nfa();
+    /// assert!(nfa.run("aaa".chars()));
+    /// ```
+    pub fn to_nfa(&self) -> NFA {
+        NFABuilder::build(self)
+    }
+
+    /// Matches the chars of `s` in sequence. The empty string gives `Eps`.
+    ///
+    /// Together with the constructors below this avoids boxing by hand:
+    ///
+    /// ```
+    /// use notlex::Regex;
+    ///
+    /// // (ab)*c?
+    /// let regex = Regex::seq(Regex::star(Regex::literal("ab")), Regex::ques(Regex::literal("c")));
+    /// let mut nfa = regex.to_nfa();
+    /// assert!(nfa.run("ababc".chars()));
+    /// nfa.reset();
+    /// assert!(!nfa.run("abac".chars()));
+    /// ```
+    pub fn literal(s: &str) -> Regex {
+        let mut chars: Vec<char> = s.chars().collect();
+        let mut ret = match chars.pop() {
+            None => { return Regex::Eps; }
+            Some(c) => Regex::CharSet(CharSet::SingleChar(c)),
+        };
+        while let Some(c) = chars.pop() {
+            ret = Regex::seq(Regex::CharSet(CharSet::SingleChar(c)), ret);
+        }
+        ret
+    }
+
+    /// Matches a char in one of the inclusive `ranges`, or when `negated`, a char not in any of
+    /// them.
+    pub fn char_class(ranges: &[(char, char)], negated: bool) -> Regex {
+        let union = CharSet::Union(ranges.iter().map(|&range| CharSet::from(range)).collect());
+        if negated {
+            Regex::CharSet(CharSet::Diff { include: Box::new(CharSet::AnyChar), exclude: Box::new(union) })
+        } else {
+            Regex::CharSet(union)
+        }
+    }
+
+    /// Matches one of the chars, e.g. an operator.
+    pub fn any_of(chars: &[char]) -> Regex {
+        Regex::CharSet(CharSet::one_of(chars))
+    }
+
+    /// Matches a char that's not one of the chars.
+    pub fn none_of(chars: &[char]) -> Regex {
+        Regex::CharSet(CharSet::Diff { include: Box::new(CharSet::AnyChar), exclude: Box::new(CharSet::one_of(chars)) })
+    }
+
+    pub fn seq(r1: Regex, r2: Regex) -> Regex {
+        Regex::Seq(Box::new(r1), Box::new(r2))
+    }
+
+    pub fn or(r1: Regex, r2: Regex) -> Regex {
+        Regex::Or(Box::new(r1), Box::new(r2))
+    }
+
+    pub fn star(r: Regex) -> Regex {
+        Regex::Star(Box::new(r))
+    }
+
+    pub fn plus(r: Regex) -> Regex {
+        Regex::Plus(Box::new(r))
+    }
+
+    pub fn ques(r: Regex) -> Regex {
+        Regex::Ques(Box::new(r))
+    }
+
+    pub fn count_group(group: usize, r: Regex) -> Regex {
+        Regex::CountGroup(group, Box::new(r))
+    }
+
+    /// `n` copies of `r` in a sequence. `n = 0` gives `Eps`. The sequence is a balanced tree, so
+    /// its depth is logarithmic in `n`.
+    pub fn repeat_exact(r: Regex, n: usize) -> Regex {
+        if n == 0 {
+            return Regex::Eps;
+        }
+        if n == 1 {
+            return r;
+        }
+        let half = n / 2;
+        Regex::seq(Regex::repeat_exact(r.clone(), half), Regex::repeat_exact(r, n - half))
+    }
+
+    /// Method version of `repeat_exact`: `times` copies of the regex in a sequence.
+    pub fn repeated(self, times: usize) -> Regex {
+        Regex::repeat_exact(self, times)
+    }
+
+    /// Matches `min` to `max` (inclusive) copies of the regex. The optional copies are built so
+    /// that each length has only one way to match (see `up_to`). `between(0, 0)` gives `Eps`.
+    /// Panics if `min > max`.
+    pub fn between(self, min: usize, max: usize) -> Regex {
+        assert!(min <= max, "Regex::between: min ({}) > max ({})", min, max);
+        let optional = Regex::up_to(self.clone(), max - min);
+        match (Regex::repeat_exact(self, min), optional) {
+            (Regex::Eps, r) | (r, Regex::Eps) => r,
+            (required, optional) => Regex::seq(required, optional),
+        }
+    }
+
+    // Matches 0 to `n` copies of `r`. Nesting the copies as `(r(r(r)?)?)?` makes the depth of
+    // the regex linear in `n`, which overflows the stack when compiling large bounds. Instead an
+    // odd `n = 2m + 1` is `(rr){0,m}r?`, and an even `n` is `(r r{0,n-1})?`, so the depth is
+    // logarithmic in `n`. The number of `r` copies is still linear in `n`.
+    fn up_to(r: Regex, n: usize) -> Regex {
+        if n == 0 {
+            return Regex::Eps;
+        }
+        if n % 2 == 1 {
+            match Regex::up_to(Regex::seq(r.clone(), r.clone()), n / 2) {
+                Regex::Eps => Regex::ques(r),
+                pairs => Regex::seq(pairs, Regex::ques(r)),
+            }
+        } else {
+            Regex::ques(Regex::seq(r.clone(), Regex::up_to(r, n - 1)))
+        }
+    }
+
+    /// Matches `min` or more copies of the regex. The last required copy is a `Plus`, so the
+    /// regex isn't repeated for the `Star`.
+    pub fn at_least(self, min: usize) -> Regex {
+        if min == 0 {
+            return Regex::star(self);
+        }
+        match Regex::repeat_exact(self.clone(), min - 1) {
+            Regex::Eps => Regex::plus(self),
+            required => Regex::seq(required, Regex::plus(self)),
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// The automaton needs more states than the limit given to the builder.
+    TooManyStates,
+}
+
+pub struct NFABuilder<D = ()> {
+    next_state: usize,
+    max_states: usize,
+    transitions: HashMap<usize, Vec<(CharSet, usize)>>,
+    // Accepting states of the rules added with `add_rule`
+    accepting: HashMap<usize, usize>,
+    state_data: HashMap<usize, D>,
+    priorities: HashMap<usize, usize>,
+    start: usize,
+    // Replaces `AnyChar`s in the regexes when set
+    any_char_set: Option<CharSet>,
+    loop_edges: HashMap<(usize, usize), LoopEdge>,
+    count_edges: HashMap<(usize, usize), usize>,
+}
+
+impl NFABuilder {
+    /// Returns a builder for adding rules one at a time with `add_rule`.
+    pub fn new() -> NFABuilder {
+        NFABuilder::with_limit(usize::MAX)
+    }
+
+    pub fn build(regex: &Regex) -> NFA {
+        NFABuilder::build_with_limit(regex, usize::MAX).unwrap()
+    }
+
+    /// Like `build`, but fails with `BuildError::TooManyStates` as soon as the automaton needs
+    /// more than `max_states` states.
+    pub fn build_with_limit(regex: &Regex, max_states: usize) -> Result<NFA, BuildError> {
+        let mut builder: NFABuilder = NFABuilder::with_limit(max_states);
+
+        let accepting_states = builder.add_regex(&vec![0], regex)?;
+        builder.add_priorities(&accepting_states);
+
+        let mut nfa = NFA::new(builder.transitions, HashMap::from_iter(accepting_states.into_iter().map(|s| (s, 0))));
+        nfa.priorities = builder.priorities;
+        nfa.loop_edges = builder.loop_edges;
+        nfa.count_edges = builder.count_edges;
+        Ok(nfa)
+    }
+
+    /// Builds an NFA that runs all of the rules simultaneously. Accepting states of a rule are
+    /// tagged with the rule's index in `rules`.
+    pub fn build_rules(rules: &[Regex]) -> NFA {
+        let mut builder = NFABuilder::new();
+        for (tag, rule) in rules.iter().enumerate() {
+            builder.add_rule(rule, tag);
+        }
+        builder.finish()
+    }
+}
+
+impl<D> NFABuilder<D> {
+    /// Like `new`, but for an NFA with state data of type `D`. This only picks the type of the
+    /// data, the data itself is attached to the accepting states of each rule with
+    /// `add_rule_with_data`.
+    pub fn new_with_data() -> NFABuilder<D> {
+        NFABuilder::with_limit(usize::MAX)
+    }
+
+    /// Adds a rule that runs simultaneously with the rules added before. Accepting states of the
+    /// rule are tagged with `tag`. Smaller tags have higher priority, see
+    /// `NFA::first_accepting_tag`.
+    ///
+    /// Returns the start state of the rule, which can be passed to `set_start` to run only this
+    /// rule.
+    pub fn add_rule(&mut self, regex: &Regex, tag: usize) -> usize {
+        self.add_rule_states(regex, tag).0
+    }
+
+    /// Like `add_rule`, but also attaches `data` to the accepting states of the rule.
+    pub fn add_rule_with_data(&mut self, regex: &Regex, tag: usize, data: D) -> usize where D: Clone {
+        let (start, accepting_states) = self.add_rule_states(regex, tag);
+        for state in accepting_states {
+            self.state_data.entry(state).or_insert_with(|| data.clone());
+        }
+        start
+    }
+
+    /// Sets the start state of the NFA returned by `finish`. By default it's state 0, which runs
+    /// all of the rules.
+    pub fn set_start(&mut self, start: usize) {
+        self.start = start;
+    }
+
+    /// Makes `AnyChar` (e.g. `.`, or the `AnyChar` in a negated class) match only the chars in
+    /// `cs` in the rules added after this call. For example with `('\0', '\u{7F}')` `.` only
+    /// matches ASCII.
+    pub fn set_any_char_set(&mut self, cs: CharSet) {
+        self.any_char_set = Some(cs);
+    }
+
+    /// Returns an NFA that runs the rules added with `add_rule`. Panics if the start state was set
+    /// to a state that doesn't exist.
+    pub fn finish(self) -> NFA<D> {
+        let start = self.start;
+        let mut nfa = NFA::with_state_data(self.transitions, self.accepting, self.state_data);
+        nfa.priorities = self.priorities;
+        nfa.loop_edges = self.loop_edges;
+        nfa.count_edges = self.count_edges;
+        if start != 0 {
+            nfa.set_start(start);
+        }
+        nfa
+    }
+
+    fn with_limit(max_states: usize) -> NFABuilder<D> {
+        NFABuilder {
+            next_state: 1,
+            max_states: max_states,
+            transitions: HashMap::new(),
+            accepting: HashMap::new(),
+            state_data: HashMap::new(),
+            priorities: HashMap::new(),
+            start: 0,
+            any_char_set: None,
+            loop_edges: HashMap::new(),
+            count_edges: HashMap::new(),
+        }
+    }
+
+    // Returns the start and accepting states of the rule
+    fn add_rule_states(&mut self, regex: &Regex, tag: usize) -> (usize, Vec<usize>) {
+        // Each rule gets its own start state so that loops in one rule can't reach transitions of
+        // another.
+        let start = self.new_state().unwrap();
+        self.add_transition(0, &CharSet::Epsilon, start);
+        let accepting_states = self.add_regex(&vec![start], regex).unwrap();
+        for state in accepting_states.iter() {
+            self.accepting.entry(*state).or_insert(tag);
+        }
+        self.add_priorities(&accepting_states);
+        (start, accepting_states)
+    }
+
+    // `add_regex` returns accepting states of the left branches of `Or`s before the ones of the
+    // right branches, so the order gives the priorities
+    fn add_priorities(&mut self, accepting_states: &[usize]) {
+        for (priority, state) in accepting_states.iter().enumerate() {
+            self.priorities.entry(*state).or_insert(priority);
+        }
+    }
+
+    fn add_regex(&mut self, current_states: &[usize], regex: &Regex) -> Result<Vec<usize>, BuildError> {
+        match regex {
+
+            &Regex::Eps => {
+                Ok(current_states.to_owned())
+            }
+
+            &Regex::CharSet(ref cs) => {
+                let cs = match self.any_char_set {
+                    None => cs.clone(),
+                    Some(ref any) => replace_any_char(cs, any),
+                };
+                let mut next_states = Vec::with_capacity(current_states.len());
+                for state in current_states.iter().cloned() {
+                    let next_state = self.new_state()?;
+                    self.add_transition(state, &cs, next_state);
+                    next_states.push(next_state);
+                }
+                Ok(next_states)
+            }
+
+            &Regex::Seq(ref r1, ref r2) => {
+                let next_states = self.add_regex(current_states, r1)?;
+                self.add_regex(&next_states, r2)
+            }
+
+            &Regex::Or(ref r1, ref r2) => {
+                let mut next_states_1 = self.add_regex(current_states, r1)?;
+                let mut next_states_2 = self.add_regex(current_states, r2)?;
+                let mut ret = Vec::with_capacity(next_states_1.len() + next_states_2.len());
+                ret.append(&mut next_states_1);
+                ret.append(&mut next_states_2);
+                Ok(ret)
+            }
+
+            &Regex::Star(ref r) => {
+                // A loop for each current state, so that the states after the loop keep the
+                // priorities of the current states (see `add_priorities`)
+                let mut next_states = Vec::with_capacity(current_states.len());
+                for state in current_states.iter().cloned() {
+                    // The loop is left through its own exit state, so that a loop around this one
+                    // doesn't add its back edge to the inner loop state
+                    let loop_state = self.add_loop(state, r, LoopEdge::Entry)?.0;
+                    let exit_state = self.new_state()?;
+                    self.add_transition(loop_state, &CharSet::Epsilon, exit_state);
+                    next_states.push(exit_state);
+                }
+                Ok(next_states)
+            }
+
+            &Regex::Plus(ref r) => {
+                let mut next_states = Vec::with_capacity(current_states.len());
+                for state in current_states.iter().cloned() {
+                    next_states.append(&mut self.add_loop(state, r, LoopEdge::PlusEntry)?.1);
+                }
+                Ok(next_states)
+            }
+
+            &Regex::Ques(ref r) => {
+                let mut next_states = Vec::with_capacity(current_states.len());
+                for state in current_states.iter().cloned() {
+                    let mut matched = self.add_regex(&[state], r)?;
+                    if matched.len() != 1 || matched[0] == state {
+                        // `r` has an `Or` (or is empty), its end states have different priorities
+                        next_states.push(state);
+                        next_states.extend(matched.into_iter().filter(|s| *s != state));
+                        continue;
+                    }
+                    // Join the paths that skip and match `r` with epsilon transitions to a new
+                    // state. Otherwise the regexes after this one would add their states for each
+                    // of them, and the number of states would double with each `Ques` in a
+                    // sequence. The current states are joined separately, so that the states after
+                    // this one keep their priorities (see `add_priorities`).
+                    let join_state = self.new_state()?;
+                    self.add_transition(state, &CharSet::Epsilon, join_state);
+                    self.add_transition(matched.pop().unwrap(), &CharSet::Epsilon, join_state);
+                    next_states.push(join_state);
+                }
+                Ok(next_states)
+            }
+
+            &Regex::CountGroup(group, ref r) => {
+                // A new state after each end state of `r`, so that there's an edge to count for
+                // each match
+                let end_states = self.add_regex(current_states, r)?;
+                let mut next_states = Vec::with_capacity(end_states.len());
+                for end_state in end_states {
+                    let next_state = self.new_state()?;
+                    self.add_transition(end_state, &CharSet::Epsilon, next_state);
+                    self.count_edges.insert((end_state, next_state), group);
+                    next_states.push(next_state);
+                }
+                Ok(next_states)
+            }
+        }
+    }
+
+    // Adds a loop for `r*` or `r+`: an epsilon transition from `current_state` to a new loop
+    // state, `r` from the loop state, and epsilon transitions back to the loop state. Returns the
+    // loop state and the states after `r`. `entry` is the kind of the edge into the loop, for
+    // `run_with_repeat_cap`.
+    //
+    // The new state makes sure the back edges don't reach other transitions of the current state
+    // (e.g. the `b` in `a*|b`), and `r` is only added once, so `r+` doesn't need to duplicate it
+    // as `r r*`.
+    fn add_loop(&mut self, current_state: usize, r: &Regex, entry: LoopEdge) -> Result<(usize, Vec<usize>), BuildError> {
+        let loop_state = self.new_state()?;
+        self.add_transition(current_state, &CharSet::Epsilon, loop_state);
+        self.loop_edges.insert((current_state, loop_state), entry);
+        let next_states = self.add_regex(&[loop_state], r)?;
+        for next_state in next_states.iter() {
+            self.add_transition(*next_state, &CharSet::Epsilon, loop_state);
+            self.loop_edges.insert((*next_state, loop_state), LoopEdge::Back);
+        }
+        Ok((loop_state, next_states))
+    }
+
+    fn new_state(&mut self) -> Result<usize, BuildError> {
+        if self.next_state >= self.max_states {
+            return Err(BuildError::TooManyStates);
+        }
+        let ret = self.next_state;
+        self.next_state += 1;
+        Ok(ret)
+    }
+
+    fn add_transition(&mut self, from: usize, cs: &CharSet, to: usize) {
+        match self.transitions.entry(from) {
+            Entry::Occupied(mut ent) => {
+                ent.get_mut().push((cs.clone(), to));
+            },
+            Entry::Vacant(ent) => {
+                ent.insert(vec![(cs.clone(), to)]);
+            },
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Only the `AnyChar`s that add chars to the set are replaced. Replacing the ones in `exclude`
+// would make the excluded set smaller, and the set bigger.
+fn replace_any_char(cs: &CharSet, any: &CharSet) -> CharSet {
+    match cs {
+        &CharSet::AnyChar => any.clone(),
+        &CharSet::Diff { ref include, ref exclude } => CharSet::Diff {
+            include: Box::new(replace_any_char(include, any)),
+            exclude: exclude.clone(),
+        },
+        &CharSet::Union(ref css) => CharSet::Union(css.iter().map(|cs| replace_any_char(cs, any)).collect()),
+        cs => cs.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use test_util::assert_accepts_same;
+
+    #[test]
+    fn regex_seq() {
+        let cs1 = CharSet::SingleChar('a');
+        let cs2 = CharSet::SingleChar('b');
+        let cs3 = CharSet::SingleChar('c');
+        let r1  = Regex::seq(Regex::CharSet(cs1),
+                             Regex::seq(Regex::CharSet(cs2), Regex::CharSet(cs3)));
+
+
+        let mut nfa = r1.to_nfa();
+        assert!(nfa.run("abc".chars()));
+
+        nfa.reset();
+        assert!(!nfa.run("".chars()));
+
+        nfa.reset();
+        assert!(!nfa.run("a".chars()));
+
+        nfa.reset();
+        assert!(!nfa.run("ab".chars()));
+
+        nfa.reset();
+        assert!(!nfa.run("abcd".chars()));
+    }
+
+    #[test]
+    fn regex_or() {
+        let cs1 = CharSet::SingleChar('a');
+        let cs2 = CharSet::SingleChar('b');
+        let cs3 = CharSet::SingleChar('c');
+        let r1  = Regex::or(Regex::CharSet(cs1),
+                            Regex::or(Regex::CharSet(cs2), Regex::CharSet(cs3)));
+
+        let mut nfa = r1.to_nfa();
+        assert!(nfa.run("a".chars()));
+
+        nfa.reset();
+        assert!(nfa.run("b".chars()));
+
+        nfa.reset();
+        assert!(nfa.run("c".chars()));
+
+        nfa.reset();
+        assert!(!nfa.run("ac".chars()));
+    }
+
+    #[test]
+    fn regex_eps() {
+        let r1  = Regex::Eps;
+
+        let mut nfa = r1.to_nfa();
+        assert!(nfa.run("".chars()));
+    }
+
+    #[test]
+    fn regex_star() {
+        let cs1 = CharSet::SingleChar('a');
+        let r1  = Regex::star(Regex::CharSet(cs1));
+
+        let mut nfa = r1.to_nfa();
+        assert!(nfa.run("".chars()));
+
+        nfa.reset();
+        assert!(nfa.run("a".chars()));
+
+        nfa.reset();
+        assert!(nfa.run("aa".chars()));
+
+        nfa.reset();
+        assert!(nfa.run("aaa".chars()));
+
+        // Epsilon transitions of the loop must not consume the 'b'
+        nfa.reset();
+        assert!(!nfa.run("ab".chars()));
+    }
+
+    #[test]
+    fn regex_plus() {
+        let cs1 = CharSet::SingleChar('a');
+        let r1  = Regex::plus(Regex::CharSet(cs1));
+
+        let mut nfa = r1.to_nfa();
+        assert!(!nfa.run("".chars()));
+
+        nfa.reset();
+        assert!(nfa.run("a".chars()));
+
+        nfa.reset();
+        assert!(nfa.run("aa".chars()));
+
+        nfa.reset();
+        assert!(nfa.run("aaa".chars()));
+    }
+
+    #[test]
+    fn regex_ques() {
+        let cs1 = CharSet::SingleChar('a');
+        let r1  = Regex::ques(Regex::CharSet(cs1));
+
+        let mut nfa = r1.to_nfa();
+        assert!(nfa.run("".chars()));
+
+        nfa.reset();
+        assert!(nfa.run("a".chars()));
+
+        nfa.reset();
+        assert!(!nfa.run("aa".chars()));
+    }
+
+    #[test]
+    fn regex_complex() {
+        let cs1 = CharSet::SingleChar('a');
+        let cs2 = CharSet::SingleChar('b');
+        let r1  = Regex::or(Regex::ques(Regex::CharSet(cs1)),
+                            Regex::ques(Regex::CharSet(cs2)));
+
+        let mut nfa = r1.to_nfa();
+        assert!(nfa.run("".chars()));
+
+        nfa.reset();
+        assert!(nfa.run("a".chars()));
+
+        nfa.reset();
+        assert!(nfa.run("b".chars()));
+
+        nfa.reset();
+        assert!(!nfa.run("c".chars()));
+
+        nfa.reset();
+        assert!(!nfa.run("ab".chars()));
+    }
+
+    #[test]
+    fn regex_macro() {
+        let cs1 = CharSet::SingleChar('a');
+        let cs2 = CharSet::SingleChar('b');
+        let r1  = Regex::or(Regex::ques(Regex::CharSet(cs1)),
+                            Regex::ques(Regex::CharSet(cs2)));
+        assert_eq!(regex!("a"? | "b"?), r1);
+
+        assert_eq!(regex!(("ab")* "c" | ),
+                   Regex::or(Regex::seq(Regex::star(Regex::literal("ab")), Regex::literal("c")),
+                             Regex::Eps));
+        assert_eq!(regex!(), Regex::Eps);
+    }
+
+    #[test]
+    fn regex_operators() {
+        let ab = Regex::literal("ab");
+        let c = Regex::CharSet('c'.into());
+        assert_eq!(ab.clone() + c.clone(), Regex::seq(ab.clone(), c.clone()));
+        assert_eq!(ab.clone() | c.clone(), Regex::or(ab.clone(), c.clone()));
+
+        // Usual precedence: `+` binds tighter than `|`, both are left-associative. The precedence is
+        // what's tested, so no parens.
+        #[allow(clippy::precedence)]
+        let r = ab.clone() + c.clone() | c.clone() + c.clone() | Regex::Eps;
+        assert_eq!(r, Regex::or(Regex::or(Regex::seq(ab.clone(), c.clone()), Regex::seq(c.clone(), c.clone())),
+                                Regex::Eps));
+
+        let mut nfa = ((ab + Regex::star(c.clone())) | c).to_nfa();
+        for &(input, accept) in &[("ab", true), ("abcc", true), ("c", true), ("cc", false), ("", false)] {
+            nfa.reset();
+            assert_eq!(nfa.run(input.chars()), accept, "{}", input);
+        }
+    }
+
+    #[test]
+    fn accepting_tags() {
+        // rule 0: ab
+        let r0 = Regex::literal("ab");
+        // rule 1: [a-z]+
+        let r1 = Regex::plus(Regex::CharSet(CharSet::Range { lo: 'a', hi: 'z' }));
+
+        let mut nfa = NFABuilder::build_rules(&[r0, r1]);
+        assert_eq!(nfa.accepting_tags(), Vec::<usize>::new());
+
+        nfa.feed('a');
+        assert_eq!(nfa.accepting_tags(), vec![1]);
+
+        nfa.feed('b');
+        assert_eq!(nfa.accepting_tags(), vec![0, 1]);
+
+        nfa.feed('c');
+        assert_eq!(nfa.accepting_tags(), vec![1]);
+
+        nfa.reset();
+        assert!(!nfa.run("a1".chars()));
+        assert_eq!(nfa.accepting_tags(), Vec::<usize>::new());
+    }
+
+    // a-m -> 1, k-z -> 2, then 1 -x-> 3 (accepting, tag 0) and 2 -y-> 4 (accepting, tag 1)
+    fn overlapping_ranges_nfa() -> NFA {
+        let mut transitions = HashMap::new();
+        transitions.insert(0, vec![(CharSet::Range { lo: 'a', hi: 'm' }, 1),
+                                   (CharSet::Range { lo: 'k', hi: 'z' }, 2)]);
+        transitions.insert(1, vec![(CharSet::SingleChar('x'), 3)]);
+        transitions.insert(2, vec![(CharSet::SingleChar('y'), 4)]);
+        let mut accepting = HashMap::new();
+        accepting.insert(3, 0);
+        accepting.insert(4, 1);
+        NFA::new(transitions, accepting)
+    }
+
+    #[test]
+    fn overlapping_range_transitions() {
+        let mut nfa = overlapping_ranges_nfa();
+
+        // In the overlap both branches stay alive
+        for c in ['k', 'l', 'm'].iter() {
+            nfa.reset();
+            nfa.feed(*c);
+            assert_eq!(nfa.cur_states, HashSet::from_iter(vec![1, 2]));
+        }
+
+        nfa.reset();
+        nfa.feed('b');
+        assert_eq!(nfa.cur_states, HashSet::from_iter(vec![1]));
+
+        nfa.reset();
+        nfa.feed('q');
+        assert_eq!(nfa.cur_states, HashSet::from_iter(vec![2]));
+
+        nfa.reset();
+        assert!(nfa.run("lx".chars()));
+        assert_eq!(nfa.accepting_tags(), vec![0]);
+
+        nfa.reset();
+        assert!(nfa.run("ly".chars()));
+        assert_eq!(nfa.accepting_tags(), vec![1]);
+
+        nfa.reset();
+        assert!(!nfa.run("by".chars()));
+
+        nfa.reset();
+        assert!(!nfa.run("qx".chars()));
+    }
+
+    #[test]
+    fn range_index() {
+        // 100 disjoint ranges, plus one overlapping all of them and one made of fragments
+        let mut ts = vec![];
+        for i in 0 .. 100 {
+            let lo = ::std::char::from_u32(0x100 + i * 10).unwrap();
+            let hi = ::std::char::from_u32(0x100 + i * 10 + 4).unwrap();
+            ts.push((CharSet::Range { lo: lo, hi: hi }, i as usize + 1));
+        }
+        ts.push((CharSet::Range { lo: '\u{150}', hi: '\u{160}' }, 200));
+        ts.push((CharSet::Diff { include: Box::new(CharSet::AnyChar),
+                                 exclude: Box::new(CharSet::Range { lo: 'a', hi: '\u{10FFFE}' }) }, 201));
+        ts.push((CharSet::Epsilon, 202));
+        let index = range_index::RangeIndex::new(&ts);
+
+        assert_eq!(index.targets('\u{100}'), &[1]);
+        assert_eq!(index.targets('\u{104}'), &[1]);
+        assert!(index.targets('\u{105}').is_empty());
+        assert_eq!(index.targets('\u{3E5}'), &[75]);
+        assert!(index.targets('\u{3E9}').is_empty());
+        assert_eq!(index.targets('\u{150}'), &[9, 200]);
+        assert_eq!(index.targets('\u{155}'), &[200]);
+        assert_eq!(index.targets('\u{15A}'), &[10, 200]);
+        assert_eq!(index.targets('\u{160}'), &[200]);
+        assert!(index.targets('\u{161}').is_empty());
+        assert_eq!(index.targets('A'), &[201]);
+        assert_eq!(index.targets('\u{10FFFF}'), &[201]);
+        assert!(index.targets('b').is_empty());
+
+        let index = range_index::RangeIndex::new(&[(CharSet::Range { lo: 'a', hi: 'f' }, 1),
+                                                   (CharSet::Range { lo: 'g', hi: 'z' }, 1),
+                                                   (CharSet::Range { lo: 'c', hi: 'p' }, 1)]);
+        assert_eq!(index.targets('a'), &[1]);
+        assert_eq!(index.targets('g'), &[1]);
+        assert_eq!(index.targets('z'), &[1]);
+    }
+
+    #[test]
+    fn step_tracked() {
+        let mut nfa = Regex::star(Regex::literal("a")).to_nfa();
+        assert!(nfa.step_tracked('a'));
+        for _ in 0 .. 100 {
+            assert!(!nfa.step_tracked('a'));
+        }
+        assert!(nfa.check_accepting());
+        assert!(nfa.step_tracked('b'));
+        assert!(!nfa.step_tracked('b'));
+        assert!(!nfa.check_accepting());
+    }
+
+    #[test]
+    fn regex_repeat_helpers() {
+        let digit = || Regex::CharSet(CharSet::Range { lo: '0', hi: '9' });
+        let accepts = |r: &Regex, s: &str| r.to_nfa().run(s.chars());
+        let accepted_lengths = |r: Regex| -> Vec<usize> {
+            (0 .. 8).filter(|n| accepts(&r, &"7".repeat(*n))).collect()
+        };
+
+        assert_eq!(accepted_lengths(digit().repeated(3)), vec![3]);
+        assert_eq!(accepted_lengths(digit().between(2, 4)), vec![2, 3, 4]);
+        assert_eq!(accepted_lengths(digit().between(0, 2)), vec![0, 1, 2]);
+        assert_eq!(accepted_lengths(digit().between(3, 3)), vec![3]);
+        assert_eq!(accepted_lengths(digit().at_least(0)), vec![0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(accepted_lengths(digit().at_least(1)), vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(accepted_lengths(digit().at_least(3)), vec![3, 4, 5, 6, 7]);
+        assert!(!accepts(&digit().between(2, 4), "1a"));
+
+        assert_eq!(digit().between(0, 0), Regex::Eps);
+        assert_eq!(digit().repeated(0), Regex::Eps);
+        assert_eq!(digit().between(0, 1), Regex::ques(digit()));
+        assert_eq!(digit().at_least(1), Regex::plus(digit()));
+
+        for max in 0 .. 8 {
+            assert_eq!(accepted_lengths(digit().between(0, max)), (0 .. max + 1).collect::<Vec<_>>());
+        }
+
+        // Large bounds don't build deep regexes
+        let r = digit().between(0, 10000);
+        assert!(r.complexity_report().depth < 100);
+        let mut nfa = r.to_nfa();
+        assert!(nfa.run("7".repeat(10000).chars()));
+        nfa.reset();
+        assert!(!nfa.run("7".repeat(10001).chars()));
+    }
+
+    #[test]
+    fn regex_repeat_exact() {
+        let hex_digit = Regex::char_class(&[('0', '9'), ('a', 'f'), ('A', 'F')], false);
+        let mut nfa = Regex::repeat_exact(hex_digit.clone(), 4).to_nfa();
+        for s in &["0000", "beef", "12aF"] {
+            nfa.reset();
+            assert!(nfa.run(s.chars()), "{}", s);
+        }
+        for s in &["", "123", "12345", "12g4"] {
+            nfa.reset();
+            assert!(!nfa.run(s.chars()), "{}", s);
+        }
+
+        assert_eq!(Regex::repeat_exact(hex_digit.clone(), 0), Regex::Eps);
+        assert_eq!(Regex::repeat_exact(hex_digit.clone(), 1), hex_digit);
+
+        let report = Regex::repeat_exact(hex_digit, 1000).complexity_report();
+        assert_eq!(report.depth, 11);
+    }
+
+    #[test]
+    fn regex_any_of() {
+        let ops = ['+', '-', '*', '/'];
+        let mut nfa = Regex::any_of(&ops).to_nfa();
+        for op in &ops {
+            nfa.reset();
+            assert!(nfa.run(op.to_string().chars()), "{}", op);
+        }
+        for s in &["a", "", "++"] {
+            nfa.reset();
+            assert!(!nfa.run(s.chars()), "{}", s);
+        }
+
+        let mut nfa = Regex::none_of(&ops).to_nfa();
+        for op in &ops {
+            nfa.reset();
+            assert!(!nfa.run(op.to_string().chars()), "{}", op);
+        }
+        nfa.reset();
+        assert!(nfa.run("a".chars()));
+
+        let mut nfa = Regex::any_of(&[]).to_nfa();
+        assert!(!nfa.run("a".chars()));
+    }
+
+    #[test]
+    fn regex_char_class() {
+        let ranges = [('a', 'f'), ('0', '9')];
+
+        let mut nfa = Regex::char_class(&ranges, false).to_nfa();
+        for (c, expected) in vec![('a', true), ('f', true), ('5', true), ('g', false), ('A', false)] {
+            nfa.reset();
+            nfa.feed(c);
+            assert_eq!(nfa.check_accepting(), expected, "{:?}", c);
+        }
+
+        let mut nfa = Regex::char_class(&ranges, true).to_nfa();
+        for (c, expected) in vec![('a', false), ('f', false), ('5', false), ('g', true), ('A', true)] {
+            nfa.reset();
+            nfa.feed(c);
+            assert_eq!(nfa.check_accepting(), expected, "{:?}", c);
+        }
+
+        let mut nfa = Regex::char_class(&[], false).to_nfa();
+        assert!(!nfa.run("a".chars()));
+
+        let mut nfa = Regex::char_class(&[], true).to_nfa();
+        assert!(nfa.run("a".chars()));
+    }
+
+    #[test]
+    fn has_epsilons() {
+        let mut nfa = Regex::literal("hello").to_nfa();
+        assert!(!nfa.has_epsilons);
+        assert!(nfa.run("hello".chars()));
+        nfa.reset();
+        assert!(!nfa.run("hell".chars()));
+
+        assert!(Regex::star(Regex::literal("a")).to_nfa().has_epsilons);
+        assert!(NFABuilder::build_rules(&[Regex::literal("hello")]).has_epsilons);
+    }
+
+    #[test]
+    fn run_with_stats() {
+        let mut nfa = Regex::star(Regex::literal("a")).to_nfa();
+        let (accepting, stats) = nfa.run_with_stats("aaaa".chars());
+        assert!(accepting);
+        assert_eq!(stats.steps, 4);
+        // The loop state, its exit state, and the state after `a`
+        assert_eq!(stats.max_active_states, 3);
+
+        // (a|a)* keeps both branches alive
+        let a = Regex::literal("a");
+        let mut nfa = Regex::star(Regex::or(a.clone(), a)).to_nfa();
+        let (accepting, ambiguous_stats) = nfa.run_with_stats("aaaa".chars());
+        assert!(accepting);
+        assert_eq!(ambiguous_stats.steps, 4);
+        assert!(ambiguous_stats.max_active_states > stats.max_active_states);
+        assert!(ambiguous_stats.transitions_taken > stats.transitions_taken);
+        assert!(ambiguous_stats.epsilon_closure_iterations > 0);
+    }
+
+    #[test]
+    fn feed_dead() {
+        let mut nfa = Regex::literal("ab").to_nfa();
+        assert_eq!(nfa.feed('a'), StepResult::Alive);
+        assert_eq!(nfa.feed('x'), StepResult::Dead);
+        assert_eq!(nfa.feed('b'), StepResult::Dead);
+        assert!(!nfa.check_accepting());
+
+        nfa.reset();
+        nfa.set_strict(true);
+        assert_eq!(nfa.feed('a'), StepResult::Alive);
+        assert_eq!(nfa.feed('b'), StepResult::Alive);
+        assert!(nfa.check_accepting());
+        assert_eq!(nfa.feed('b'), StepResult::Dead);
+    }
+
+    #[test]
+    fn match_status() {
+        let digit = || Regex::CharSet(('0' ..= '9').into());
+        let dash = Regex::literal("-");
+        let date = Regex::seq(Regex::repeat_exact(digit(), 4),
+                              Regex::seq(dash.clone(), Regex::seq(Regex::repeat_exact(digit(), 2),
+                                                                  Regex::seq(dash, Regex::repeat_exact(digit(), 2)))));
+        let mut nfa = date.to_nfa();
+        assert_eq!(nfa.status(), MatchStatus::Partial);
+
+        for c in "2024-10-1".chars() {
+            nfa.feed(c);
+            assert_eq!(nfa.status(), MatchStatus::Partial);
+            assert!(nfa.can_continue());
+        }
+        nfa.feed('4');
+        assert_eq!(nfa.status(), MatchStatus::Accepting);
+        nfa.feed('4');
+        assert_eq!(nfa.status(), MatchStatus::Dead);
+        assert!(!nfa.can_continue());
+
+        nfa.reset();
+        nfa.run("2024/".chars());
+        assert_eq!(nfa.status(), MatchStatus::Dead);
+    }
+
+    #[test]
+    #[should_panic]
+    fn feed_dead_strict() {
+        let mut nfa = Regex::literal("ab").to_nfa();
+        nfa.set_strict(true);
+        nfa.feed('x');
+        nfa.feed('b');
+    }
+
+    #[test]
+    fn run_char_slice() {
+        let mut nfa = Regex::literal("abc").to_nfa();
+        assert!(nfa.run_char_slice(&['a', 'b', 'c']));
+
+        nfa.reset();
+        assert!(!nfa.run_char_slice(&[]));
+
+        nfa.reset();
+        assert!(!nfa.run_char_slice(&['a', 'b']));
+
+        nfa.reset();
+        assert!(!nfa.run_char_slice(&['a', 'b', 'c', 'd']));
+
+        let mut nfa = Regex::star(Regex::literal("a")).to_nfa();
+        assert!(nfa.run_char_slice(&[]));
+
+        nfa.reset();
+        assert!(nfa.run_char_slice(&['a', 'a', 'a']));
+    }
+
+    #[test]
+    fn find_char_slice() {
+        let chars: Vec<char> = "abcabd".chars().collect();
+
+        // (abc)*
+        let mut nfa = Regex::star(Regex::literal("abc")).to_nfa();
+        assert_eq!(nfa.find_char_slice(&chars), Some(3));
+
+        nfa.reset();
+        assert_eq!(nfa.find_char_slice(&chars[1 ..]), Some(0));
+
+        let mut nfa = Regex::literal("abd").to_nfa();
+        assert_eq!(nfa.find_char_slice(&chars), None);
+
+        nfa.reset();
+        assert_eq!(nfa.find_char_slice(&chars[3 ..]), Some(3));
+
+        // Non-ASCII chars are counted as one
+        let chars: Vec<char> = "ééé".chars().collect();
+        let mut nfa = Regex::plus(Regex::literal("é")).to_nfa();
+        assert_eq!(nfa.find_char_slice(&chars), Some(3));
+    }
+
+    #[test]
+    fn find_match() {
+        let mut nfa = Regex::seq(Regex::literal("ab"), Regex::ques(Regex::literal("c"))).to_nfa();
+        assert_eq!(nfa.find_match("abcdef"), Some("abc"));
+        nfa.reset();
+        assert_eq!(nfa.find_match("abd"), Some("ab"));
+        nfa.reset();
+        assert_eq!(nfa.find_match("a"), None);
+
+        // Byte offsets of multi-byte chars
+        let mut nfa = Regex::plus(Regex::CharSet(CharSet::Range { lo: '\u{E0}', hi: '\u{FF}' })).to_nfa();
+        assert_eq!(nfa.find_match("\u{E9}\u{E8}x\u{E9}"), Some("\u{E9}\u{E8}"));
+
+        let mut nfa = Regex::star(Regex::literal("a")).to_nfa();
+        assert_eq!(nfa.find_match("b"), Some(""));
+    }
+
+    #[test]
+    fn explain() {
+        let mut nfa = Regex::seq(Regex::literal("ab"), Regex::ques(Regex::literal("c"))).to_nfa();
+        assert_eq!(nfa.explain("ab"), Ok(()));
+        assert_eq!(nfa.explain("abc"), Ok(()));
+        assert_eq!(nfa.explain("\u{E9}b"), Err(MatchFailure::UnexpectedChar { pos: 0, c: '\u{E9}' }));
+        assert_eq!(nfa.explain("ax"), Err(MatchFailure::UnexpectedChar { pos: 1, c: 'x' }));
+        assert_eq!(nfa.explain("a"), Err(MatchFailure::UnexpectedEnd { expected: vec![CharSet::SingleChar('b')] }));
+        assert_eq!(nfa.explain(""), Err(MatchFailure::UnexpectedEnd { expected: vec![CharSet::SingleChar('a')] }));
+        assert_eq!(nfa.explain("abd"), Err(MatchFailure::TrailingInput { pos: 2 }));
+        assert_eq!(nfa.explain("abcc"), Err(MatchFailure::TrailingInput { pos: 3 }));
+
+        // Several sets can come next. Build the NFA a few times, as each gets a differently
+        // seeded state set.
+        let regex = Regex::or(Regex::literal("xa"),
+                              Regex::or(Regex::literal("xb"),
+                                        Regex::or(Regex::literal("xc"), Regex::literal("xd"))));
+        for _ in 0 .. 20 {
+            let mut nfa = regex.to_nfa();
+            assert_eq!(nfa.explain("x"), Err(MatchFailure::UnexpectedEnd {
+                expected: vec![CharSet::SingleChar('a'), CharSet::SingleChar('b'),
+                               CharSet::SingleChar('c'), CharSet::SingleChar('d')]
+            }));
+        }
+    }
+
+    #[test]
+    fn regex_visitor() {
+        struct CharSetCollector(Vec<CharSet>);
+
+        impl RegexVisitor for CharSetCollector {
+            fn visit_charset(&mut self, cs: &CharSet) {
+                self.0.push(cs.clone());
+            }
+        }
+
+        // (ab|[0-9])*c?
+        let r = Regex::seq(Regex::star(Regex::or(Regex::literal("ab"),
+                                                 Regex::CharSet(('0', '9').into()))),
+                           Regex::ques(Regex::literal("c")));
+        let mut collector = CharSetCollector(vec![]);
+        r.accept(&mut collector);
+        assert_eq!(collector.0, vec!['a'.into(), 'b'.into(), ('0', '9').into(), 'c'.into()]);
+    }
+
+    #[test]
+    fn regex_folder() {
+        // Replaces all charsets with `x`
+        struct Replace;
+
+        impl RegexFolder for Replace {
+            fn fold_charset(&mut self, _cs: &CharSet) -> Regex {
+                Regex::literal("x")
+            }
+        }
+
+        let r = Regex::or(Regex::literal("ab"), Regex::star(Regex::literal("c")));
+        assert_eq!(r.fold(&mut Replace), Regex::or(Regex::literal("xx"), Regex::star(Regex::literal("x"))));
+    }
+
+    #[test]
+    fn regex_simplify() {
+        let a = Regex::literal("a");
+        assert_eq!(Regex::seq(Regex::Eps, Regex::seq(a.clone(), Regex::Eps)).simplify(), a);
+        assert_eq!(Regex::star(Regex::plus(a.clone())).simplify(), Regex::star(a.clone()));
+        assert_eq!(Regex::ques(Regex::ques(a.clone())).simplify(), Regex::ques(a.clone()));
+        assert_eq!(Regex::plus(Regex::ques(a.clone())).simplify(), Regex::star(a.clone()));
+        assert_eq!(Regex::star(Regex::Eps).simplify(), Regex::Eps);
+        assert_eq!(Regex::CharSet(('a', 'a').into()).simplify(), a);
+        assert_eq!(Regex::or(Regex::star(Regex::star(a.clone())), Regex::seq(Regex::Eps, a.clone())).simplify(),
+                   Regex::or(Regex::star(a.clone()), a));
+    }
+
+    #[test]
+    fn regex_same_after_simplify() {
+        let a = Regex::literal("a");
+        let b = Regex::literal("b");
+        assert!(Regex::star(Regex::star(a.clone())).same_after_simplify(&Regex::star(a.clone())));
+        assert!(Regex::seq(Regex::Eps, a.clone()).same_after_simplify(&a));
+        assert!(!Regex::star(a.clone()).same_after_simplify(&Regex::plus(a.clone())));
+
+        // Same language, but not found by simplification
+        assert!(!Regex::or(a.clone(), b.clone()).same_after_simplify(&Regex::or(b, a)));
+    }
+
+    #[test]
+    fn regex_normalize_seq() {
+        let a = Regex::literal("a");
+        let b = Regex::literal("b");
+        let c = Regex::literal("c");
+        assert_eq!(Regex::seq(Regex::seq(a.clone(), b.clone()), c.clone()).normalize_seq(), Regex::literal("abc"));
+
+        let r = Regex::seq(Regex::seq(Regex::Eps, Regex::seq(a.clone(), Regex::Eps)),
+                           Regex::star(Regex::seq(Regex::seq(b.clone(), c.clone()), a.clone())));
+        assert_eq!(r.normalize_seq(), Regex::seq(a.clone(), Regex::star(Regex::literal("bca"))));
+
+        assert_eq!(Regex::seq(Regex::Eps, Regex::Eps).normalize_seq(), Regex::Eps);
+        assert_eq!(Regex::or(Regex::seq(Regex::Eps, a.clone()), b.clone()).normalize_seq(), Regex::or(a, b));
+    }
+
+    #[test]
+    fn regex_simplify_never() {
+        let never = Regex::CharSet(CharSet::Union(vec![]));
+        assert_eq!(Regex::seq(never.clone(), Regex::literal("abc")).simplify(), never);
+        assert_eq!(Regex::seq(Regex::literal("abc"), never.clone()).simplify(), never);
+
+        // Any empty charset is canonicalized
+        let empty = CharSet::Diff { include: Box::new(('a', 'z').into()), exclude: Box::new(CharSet::AnyChar) };
+        let r = Regex::seq(Regex::literal("x"), Regex::seq(Regex::CharSet(empty), Regex::star(Regex::literal("y"))));
+        assert_eq!(r.simplify(), never);
+
+        // Alternatives that can't match are dropped, unless none of them can match
+        let r = Regex::or(Regex::seq(never.clone(), Regex::literal("a")), Regex::literal("b"));
+        assert_eq!(r.simplify(), Regex::literal("b"));
+        let r = Regex::or(Regex::literal("a"), Regex::or(never.clone(), Regex::literal("b")));
+        assert_eq!(r.simplify(), Regex::or(Regex::literal("a"), Regex::literal("b")));
+        assert_eq!(Regex::or(never.clone(), never.clone()).simplify(), never);
+    }
+
+    #[test]
+    fn regex_simplify_or() {
+        let a = Regex::literal("a");
+        let b = Regex::literal("b");
+        let c = Regex::literal("c");
+        assert_eq!(Regex::or(a.clone(), Regex::or(a.clone(), b.clone())).simplify(), Regex::or(a.clone(), b.clone()));
+        assert_eq!(Regex::or(a.clone(), a.clone()).simplify(), a);
+
+        // Duplicates are found across the whole alternation, and the first occurrence is kept
+        let r = Regex::or(Regex::or(b.clone(), a.clone()), Regex::or(c.clone(), Regex::or(a.clone(), b.clone())));
+        assert_eq!(r.simplify(), Regex::or(b.clone(), Regex::or(a.clone(), c.clone())));
+
+        // Branches are compared after simplification
+        let r = Regex::or(Regex::star(Regex::star(a.clone())), Regex::or(b.clone(), Regex::star(a.clone())));
+        assert_eq!(r.simplify(), Regex::or(Regex::star(a.clone()), b.clone()));
+    }
+
+    #[test]
+    fn complexity_report() {
+        let a = Regex::literal("a");
+        let report = Regex::star(Regex::star(Regex::star(a.clone()))).complexity_report();
+        assert!(report.is_risky);
+        assert_eq!(report.quantifier_nesting, 3);
+        assert!(report.reason.is_some());
+
+        assert!(Regex::plus(Regex::seq(Regex::star(a.clone()), Regex::literal("b"))).complexity_report().is_risky);
+
+        let report = Regex::literal("abc").complexity_report();
+        assert_eq!(report, ComplexityReport { depth: 3, quantifier_nesting: 0, is_risky: false, reason: None });
+
+        // Bounded quantifiers and sequences of quantifiers are fine
+        let r = Regex::seq(Regex::star(Regex::ques(a.clone())), Regex::star(a.clone()));
+        let report = r.complexity_report();
+        assert!(!report.is_risky);
+        assert_eq!(report.quantifier_nesting, 1);
+
+        let mut r = a.clone();
+        for _ in 0 .. complexity::MAX_SAFE_DEPTH {
+            r = Regex::ques(r);
+        }
+        assert!(r.complexity_report().is_risky);
+    }
+
+    #[test]
+    fn minimal_dfa() {
+        // The minimal DFA for (a|b)*abb, with state 3 accepting
+        let mut transitions = HashMap::new();
+        for &(state, on_a, on_b) in &[(0, 1, 0), (1, 1, 2), (2, 1, 3), (3, 1, 0)] {
+            transitions.insert(state, vec![(CharSet::SingleChar('a'), on_a), (CharSet::SingleChar('b'), on_b)]);
+        }
+        let mut accepting = HashMap::new();
+        accepting.insert(3, 0);
+        let dfa = NFA::new(transitions, accepting);
+
+        let a_or_b = Regex::or(Regex::literal("a"), Regex::literal("b"));
+        let r = Regex::seq(Regex::star(a_or_b), Regex::literal("abb"));
+        assert_accepts_same(&r.to_nfa(), &dfa, &['a', 'b'], 10);
+        assert_accepts_same(&r.simplify().to_nfa(), &dfa, &['a', 'b'], 10);
+        assert_accepts_same(&r.to_nfa().to_regex().to_nfa(), &dfa, &['a', 'b'], 8);
+    }
+
+    #[test]
+    fn nfa_to_regex() {
+        let abc = ['a', 'b', 'c'];
+
+        let r = Regex::seq(Regex::star(Regex::literal("a")), Regex::literal("b"));
+        assert_accepts_same(&r.to_nfa().to_regex().to_nfa(), &r.to_nfa(), &abc, 6);
+
+        let r = Regex::seq(Regex::star(Regex::or(Regex::literal("a"), Regex::literal("bc"))),
+                           Regex::ques(Regex::plus(Regex::CharSet(('b', 'c').into()))));
+        assert_accepts_same(&r.to_nfa().to_regex().to_nfa(), &r.to_nfa(), &abc, 6);
+
+        let r = Regex::or(Regex::literal("ab"), Regex::Eps);
+        assert_accepts_same(&NFABuilder::build_rules(&[r.clone(), Regex::literal("c")]).to_regex().to_nfa(),
+                            &Regex::or(r, Regex::literal("c")).to_nfa(), &abc, 4);
+
+        let mut transitions = HashMap::new();
+        transitions.insert(0, vec![(CharSet::SingleChar('a'), 1)]);
+        let mut accepting = HashMap::new();
+        accepting.insert(2, 0);
+        assert_eq!(NFA::new(transitions, accepting).to_regex(), Regex::CharSet(CharSet::Union(vec![])));
+    }
+
+    #[test]
+    fn nfa_alphabet() {
+        let r = Regex::or(Regex::CharSet(('a', 'c').into()), Regex::CharSet(('x', 'z').into()));
+        let alphabet = r.to_nfa().alphabet();
+        assert_eq!(alphabet, CharSet::Union(vec![('a', 'c').into(), ('x', 'z').into()]));
+        assert_eq!(alphabet.to_ranges(), vec![('a', 'c'), ('x', 'z')]);
+
+        // Overlapping and adjacent sets are merged, epsilons are ignored
+        let r = Regex::seq(Regex::star(Regex::CharSet(('a', 'c').into())),
+                           Regex::or(Regex::CharSet(('b', 'd').into()), Regex::literal("e")));
+        assert_eq!(r.to_nfa().alphabet(), ('a', 'e').into());
+
+        assert_eq!(Regex::Eps.to_nfa().alphabet(), CharSet::Union(vec![]));
+    }
+
+    #[test]
+    fn merge_parallel_transitions() {
+        let mut transitions = HashMap::new();
+        transitions.insert(0, vec![(CharSet::SingleChar('a'), 1),
+                                   (CharSet::SingleChar('b'), 1),
+                                   (CharSet::Epsilon, 2),
+                                   (CharSet::Range { lo: '0', hi: '9' }, 1),
+                                   (CharSet::SingleChar('c'), 2)]);
+        transitions.insert(2, vec![(CharSet::SingleChar('d'), 1),
+                                   (CharSet::SingleChar('e'), 1),
+                                   (CharSet::Epsilon, 1)]);
+        let mut accepting = HashMap::new();
+        accepting.insert(1, 0);
+        let mut nfa = NFA::new(transitions, accepting);
+
+        let num_transitions = |nfa: &NFA| nfa.transitions.values().map(|ts| ts.len()).sum::<usize>();
+        assert_eq!(num_transitions(&nfa), 8);
+
+        let mut merged = nfa.clone();
+        merged.merge_parallel_transitions();
+        assert_eq!(num_transitions(&merged), 5);
+        assert_eq!(merged.transitions[&0],
+                   vec![(CharSet::Union(vec!['a'.into(), 'b'.into(), ('0', '9').into()]), 1),
+                        (CharSet::SingleChar('c'), 2),
+                        (CharSet::Epsilon, 2)]);
+
+        for input in ["", "a", "5", "c", "cd", "d", "e", "ab", "x"].iter() {
+            nfa.reset();
+            merged.reset();
+            assert_eq!(nfa.run(input.chars()), merged.run(input.chars()), "{:?}", input);
+        }
+
+        // a|b|c|[0-9]: the alternatives end in different states, so there's nothing to merge
+        let regex = Regex::or(Regex::or(Regex::literal("a"), Regex::literal("b")),
+                              Regex::or(Regex::literal("c"), Regex::CharSet(('0', '9').into())));
+        let mut nfa = regex.to_nfa();
+        let mut merged = nfa.clone();
+        merged.merge_parallel_transitions();
+        assert_eq!(num_transitions(&nfa), 4);
+        assert_eq!(merged.transitions, nfa.transitions);
+        for input in ["", "a", "b", "c", "5", "ab", "x"].iter() {
+            nfa.reset();
+            merged.reset();
+            assert_eq!(nfa.run(input.chars()), merged.run(input.chars()), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn literal_set() {
+        let set = LiteralSet::new(vec![("he".to_owned(), 0),
+                                       ("she".to_owned(), 1),
+                                       ("his".to_owned(), 2),
+                                       ("hers".to_owned(), 3),
+                                       ("".to_owned(), 4)]);
+        assert_eq!(set.find("ushers"), vec![(1, 4, 1), (2, 4, 0), (2, 6, 3)]);
+        assert_eq!(set.find("hishe"), vec![(0, 3, 2), (2, 5, 1), (3, 5, 0)]);
+        assert_eq!(set.find("xyz"), vec![]);
+        assert_eq!(set.find(""), vec![]);
+
+        // Offsets are in bytes
+        let set = LiteralSet::new(vec![("é".to_owned(), 'e'), ("aé".to_owned(), 'a')]);
+        assert_eq!(set.find("éaé"), vec![(0, 2, 'e'), (2, 5, 'a'), (3, 5, 'e')]);
+    }
+
+    #[cfg(feature = "grapheme")]
+    #[test]
+    fn grapheme_nfa() {
+        use grapheme::GraphemeNFA;
+
+        // A French flag is two regional indicator chars but one grapheme cluster
+        let flag = "\u{1F1EB}\u{1F1F7}";
+        let any = Regex::CharSet(CharSet::AnyChar);
+
+        let mut nfa = any.to_nfa();
+        assert!(!nfa.run(flag.chars()));
+
+        let mut nfa = GraphemeNFA::new(any.to_nfa());
+        assert!(nfa.run(flag));
+
+        nfa.reset();
+        assert!(!nfa.run(&flag.repeat(2)));
+
+        // Clusters are matched by their first char
+        let mut nfa = GraphemeNFA::new(Regex::literal("\u{1F1EB}a").to_nfa());
+        assert!(nfa.run("\u{1F1EB}\u{1F1F7}a"));
+
+        let mut nfa = GraphemeNFA::new(Regex::literal("e").to_nfa());
+        assert!(nfa.run("e\u{301}"));
+    }
+
+    #[cfg(feature = "nfc")]
+    #[test]
+    fn nfc_nfa() {
+        use nfc::NfcNFA;
+
+        let precomposed = "\u{E9}";
+        let decomposed = "e\u{301}";
+        let e_acute = Regex::CharSet(CharSet::SingleChar('\u{E9}'));
+
+        let mut nfa = e_acute.to_nfa();
+        assert!(!nfa.run(decomposed.chars()));
+
+        let mut nfa = NfcNFA::new(e_acute.to_nfa());
+        assert!(nfa.run(precomposed));
+        nfa.reset();
+        assert!(nfa.run(decomposed));
+
+        // No precomposed form for `q` with an acute accent
+        let mut nfa = NfcNFA::new(Regex::literal("q\u{301}").to_nfa());
+        assert!(nfa.run("q\u{301}"));
+
+        // A strict NFA isn't fed after it dies
+        let mut strict = e_acute.to_nfa();
+        strict.set_strict(true);
+        let mut nfa = NfcNFA::new(strict);
+        assert!(!nfa.run("xe\u{301}"));
+    }
+
+    #[test]
+    fn first_accepting_tag() {
+        let keyword = Regex::literal("if");
+        let ident = Regex::plus(Regex::CharSet(('a', 'z').into()));
+
+        let mut nfa = NFABuilder::build_rules(&[keyword.clone(), ident.clone()]);
+        assert_eq!(nfa.first_accepting_tag(), None);
+        assert!(nfa.run("if".chars()));
+        assert_eq!(nfa.first_accepting_tag(), Some(0));
+
+        nfa.reset();
+        assert!(nfa.run("iff".chars()));
+        assert_eq!(nfa.first_accepting_tag(), Some(1));
+
+        nfa.reset();
+        assert!(!nfa.run("i1".chars()));
+        assert_eq!(nfa.first_accepting_tag(), None);
+
+        // Rule order decides
+        let mut nfa = NFABuilder::build_rules(&[ident, keyword]);
+        assert!(nfa.run("if".chars()));
+        assert_eq!(nfa.first_accepting_tag(), Some(0));
+    }
+
+    #[test]
+    fn build_with_limit() {
+        // Charsets add a state for each current state, so every `a|a` in the sequence doubles the
+        // number of states.
+        let a = Regex::CharSet(CharSet::SingleChar('a'));
+        let mut r = a.clone();
+        for _ in 0 .. 40 {
+            r = Regex::seq(r, Regex::or(a.clone(), a.clone()));
+        }
+        assert_eq!(NFABuilder::build_with_limit(&r, 1000).err(), Some(BuildError::TooManyStates));
+
+        let r = Regex::plus(Regex::CharSet(CharSet::SingleChar('a')));
+        assert!(NFABuilder::build_with_limit(&r, 2).is_err());
+        let mut nfa = NFABuilder::build_with_limit(&r, 3).unwrap();
+        assert!(nfa.run("aaa".chars()));
+    }
+
+    #[test]
+    fn builder_add_rule() {
+        let mut builder = NFABuilder::new();
+        builder.add_rule(&Regex::literal("if"), 10);
+        builder.add_rule(&Regex::plus(Regex::CharSet(('a', 'z').into())), 20);
+        builder.add_rule(&Regex::plus(Regex::CharSet(('0', '9').into())), 5);
+        let mut nfa = builder.finish();
+
+        nfa.run("if".chars());
+        assert_eq!(nfa.accepting_tags(), vec![10, 20]);
+        assert_eq!(nfa.first_accepting_tag(), Some(10));
+
+        nfa.reset();
+        nfa.run("iff".chars());
+        assert_eq!(nfa.accepting_tags(), vec![20]);
+
+        nfa.reset();
+        nfa.run("42".chars());
+        assert_eq!(nfa.accepting_tags(), vec![5]);
+
+        nfa.reset();
+        assert!(!nfa.run("a1".chars()));
+
+        assert!(!NFABuilder::new().finish().run("".chars()));
+    }
+
+    #[test]
+    fn search_policy() {
+        let mut nfa = Regex::or(Regex::literal("a"), Regex::literal("ab")).to_nfa();
+        assert_eq!(nfa.search("xab", MatchPolicy::LeftmostLongest), Some(1 .. 3));
+        assert_eq!(nfa.search("xab", MatchPolicy::LeftmostShortest), Some(1 .. 2));
+        assert_eq!(nfa.search("xyz", MatchPolicy::LeftmostLongest), None);
+
+        let matches: Vec<_> = nfa.find_iter("abaab", MatchPolicy::LeftmostLongest).collect();
+        assert_eq!(matches, vec![0 .. 2, 2 .. 3, 3 .. 5]);
+        let matches: Vec<_> = nfa.find_iter("abaab", MatchPolicy::LeftmostShortest).collect();
+        assert_eq!(matches, vec![0 .. 1, 2 .. 3, 3 .. 4]);
+
+        // Empty matches
+        let mut nfa = Regex::star(Regex::literal("a")).to_nfa();
+        let matches: Vec<_> = nfa.find_iter("baa", MatchPolicy::LeftmostLongest).collect();
+        assert_eq!(matches, vec![0 .. 0, 1 .. 3, 3 .. 3]);
+        let matches: Vec<_> = nfa.find_iter("ba", MatchPolicy::LeftmostShortest).collect();
+        assert_eq!(matches, vec![0 .. 0, 1 .. 1, 2 .. 2]);
+
+        // The leftmost match wins over a longer one starting later
+        let nfa = Regex::or(Regex::literal("bc"), Regex::literal("abcd")).to_nfa();
+        assert_eq!(nfa.search("xabcd", MatchPolicy::LeftmostShortest), Some(1 .. 5));
+        assert_eq!(nfa.search("xbcd", MatchPolicy::LeftmostLongest), Some(1 .. 3));
+
+        // No match: a single pass, rather than one per start position
+        let nfa = Regex::seq(Regex::star(Regex::literal("a")), Regex::literal("b")).to_nfa();
+        let input: String = "a".repeat(50000);
+        assert_eq!(nfa.search(&input, MatchPolicy::LeftmostLongest), None);
+        assert_eq!(nfa.search(&(input.clone() + "b"), MatchPolicy::LeftmostShortest), Some(0 .. 50001));
+    }
+
+    #[test]
+    fn active_accepting() {
+        assert!(overlapping_ranges_nfa().active_accepting().is_empty());
+
+        // Three rules with different accepting states that all accept "ab"
+        let mut nfa = NFABuilder::build_rules(&[Regex::literal("ab"),
+                                                Regex::plus(Regex::CharSet(('a', 'z').into())),
+                                                Regex::or(Regex::literal("x"), Regex::literal("ab"))]);
+        nfa.run("ab".chars());
+        let states = nfa.active_accepting();
+        assert_eq!(states.len(), 3);
+        let mut sorted = states.clone();
+        sorted.sort();
+        assert_eq!(states, sorted);
+        let mut tags: Vec<usize> = states.iter().map(|s| nfa.accepting[s]).collect();
+        tags.sort();
+        assert_eq!(tags, vec![0, 1, 2]);
+
+        nfa.reset();
+        nfa.run("abc".chars());
+        assert_eq!(nfa.active_accepting().len(), 1);
+    }
+
+    #[test]
+    fn or_priority() {
+        // Both branches accept "ab", the left one should win
+        let left = Regex::literal("ab");
+        let right = Regex::seq(Regex::CharSet(('a', 'b').into()), Regex::CharSet(('a', 'b').into()));
+        let mut nfa = Regex::or(left, right).to_nfa();
+        nfa.run("ab".chars());
+        let states = nfa.active_accepting();
+        assert_eq!(states.len(), 2);
+        let first = nfa.first_accepting_state().unwrap();
+        assert_eq!(nfa.accepting_priority(first), Some(0));
+
+        // Only the right branch accepts "ba"
+        nfa.reset();
+        nfa.run("ba".chars());
+        let first = nfa.first_accepting_state().unwrap();
+        assert_eq!(nfa.accepting_priority(first), Some(1));
+
+        // Rule order comes before branch order
+        let mut nfa = NFABuilder::build_rules(&[Regex::or(Regex::literal("b"), Regex::literal("a")),
+                                                Regex::literal("a")]);
+        nfa.run("a".chars());
+        let first = nfa.first_accepting_state().unwrap();
+        assert_eq!(nfa.accepting[&first], 0);
+        assert_eq!(nfa.accepting_priority(first), Some(1));
+        assert_eq!(nfa.accepting_priority(0), None);
+
+        // The priorities survive a loop or a `Ques` after the `Or`
+        for &(repeat, input) in &[(Regex::star as fn(Regex) -> Regex, "cc"), (Regex::plus, "cc"), (Regex::ques, "c")] {
+            let right = Regex::seq(Regex::CharSet(('a', 'b').into()), Regex::CharSet(('a', 'b').into()));
+            let mut nfa = Regex::seq(Regex::or(Regex::literal("ab"), right), repeat(Regex::literal("c"))).to_nfa();
+            nfa.run(format!("ab{}", input).chars());
+            assert_eq!(nfa.active_accepting().len(), 2);
+            let first = nfa.first_accepting_state().unwrap();
+            assert_eq!(nfa.accepting_priority(first), Some(0));
+
+            nfa.reset();
+            nfa.run(format!("ba{}", input).chars());
+            let first = nfa.first_accepting_state().unwrap();
+            assert_eq!(nfa.accepting_priority(first), Some(1));
+        }
+    }
+
+    #[test]
+    fn any_char_set() {
+        let dot = Regex::CharSet(CharSet::AnyChar);
+        let not_a = Regex::char_class(&[('a', 'a')], true);
+
+        let mut builder = NFABuilder::new();
+        builder.set_any_char_set(CharSet::Range { lo: '\0', hi: '\u{7F}' });
+        builder.add_rule(&dot, 0);
+        builder.add_rule(&not_a, 1);
+        let mut nfa = builder.finish();
+
+        assert!(nfa.run("x".chars()));
+        assert_eq!(nfa.accepting_tags(), vec![0, 1]);
+        nfa.reset();
+        assert!(!nfa.run("\u{e9}".chars()));
+        nfa.reset();
+        nfa.run("a".chars());
+        assert_eq!(nfa.accepting_tags(), vec![0]);
+
+        // Without the constraint
+        let mut nfa = NFABuilder::build_rules(&[dot, not_a]);
+        nfa.run("\u{e9}".chars());
+        assert_eq!(nfa.accepting_tags(), vec![0, 1]);
+
+        // An `AnyChar` in an excluded set is not replaced: `[a-\u{e9}]` minus everything but
+        // `[a-z]` is `[a-z]`
+        let nested = CharSet::Diff {
+            include: Box::new(CharSet::Range { lo: 'a', hi: '\u{e9}' }),
+            exclude: Box::new(CharSet::Diff {
+                include: Box::new(CharSet::AnyChar),
+                exclude: Box::new(CharSet::Range { lo: 'a', hi: 'z' }),
+            }),
+        };
+        let mut builder = NFABuilder::new();
+        builder.set_any_char_set(CharSet::Range { lo: '\0', hi: '\u{7F}' });
+        builder.add_rule(&Regex::CharSet(nested), 0);
+        let mut nfa = builder.finish();
+        for &(input, accepted) in &[("q", true), ("\u{e9}", false), ("~", false)] {
+            nfa.reset();
+            assert_eq!(nfa.run(input.chars()), accepted, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn start_state() {
+        let mut builder = NFABuilder::new();
+        builder.add_rule(&Regex::literal("ab"), 0);
+        let cd = builder.add_rule(&Regex::literal("cd"), 1);
+        builder.set_start(cd);
+        let mut nfa = builder.finish();
+        assert_eq!(nfa.start(), cd);
+
+        assert!(nfa.run("cd".chars()));
+        nfa.reset();
+        assert!(!nfa.run("ab".chars()));
+
+        nfa.set_start(0);
+        assert!(nfa.run("ab".chars()));
+
+        // A start state in the middle of a regex
+        let mut nfa = Regex::literal("abc").to_nfa();
+        let b_state = nfa.transitions[&0][0].1;
+        nfa.set_start(b_state);
+        assert!(nfa.run("bc".chars()));
+        nfa.reset();
+        assert!(!nfa.run("abc".chars()));
+    }
+
+    #[test]
+    fn reset_initial_closure() {
+        // Start state has epsilon transitions, so the initial states are a closure
+        let mut nfa = Regex::star(Regex::literal("ab")).to_nfa();
+        let initial = nfa.cur_states.clone();
+        assert!(initial.len() > 1);
+        assert!(nfa.run("abab".chars()));
+        nfa.reset();
+        assert_eq!(nfa.cur_states, initial);
+        assert!(!nfa.run("aba".chars()));
+        nfa.reset();
+        assert!(nfa.run("".chars()));
+    }
+
+    #[test]
+    fn add_remove_accepting() {
+        let mut nfa = Regex::literal("abc").to_nfa();
+        let a_state = nfa.transitions[&0][0].1;
+        let b_state = nfa.transitions[&a_state][0].1;
+        assert!(!nfa.run("ab".chars()));
+
+        nfa.add_accepting(b_state, 0);
+        nfa.reset();
+        assert!(nfa.run("ab".chars()));
+        nfa.reset();
+        assert!(nfa.run("abc".chars()));
+        nfa.reset();
+        assert!(!nfa.run("a".chars()));
+
+        let c_state = nfa.transitions[&b_state][0].1;
+        nfa.remove_accepting(c_state);
+        nfa.reset();
+        assert!(!nfa.run("abc".chars()));
+        nfa.reset();
+        assert!(nfa.run("ab".chars()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn add_accepting_invalid() {
+        let mut nfa = Regex::literal("ab").to_nfa();
+        nfa.add_accepting(100, 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn start_state_invalid() {
+        let mut nfa = Regex::literal("ab").to_nfa();
+        nfa.set_start(100);
+    }
+
+    #[test]
+    fn state_data() {
+        #[derive(Debug, Clone, PartialEq)]
+        enum Kind { Keyword, Ident }
+
+        let mut builder = NFABuilder::new_with_data();
+        builder.add_rule_with_data(&Regex::or(Regex::literal("if"), Regex::literal("else")), 0, Kind::Keyword);
+        builder.add_rule_with_data(&Regex::plus(Regex::CharSet(('a', 'z').into())), 1, Kind::Ident);
+        builder.add_rule(&Regex::plus(Regex::CharSet(('0', '9').into())), 2);
+        let mut nfa = builder.finish();
+
+        nfa.run("else".chars());
+        let kinds: Vec<&Kind> = nfa.active_accepting().into_iter().filter_map(|s| nfa.state_data(s)).collect();
+        assert_eq!(kinds, vec![&Kind::Keyword, &Kind::Ident]);
+
+        nfa.reset();
+        nfa.run("elsewhere".chars());
+        let kinds: Vec<&Kind> = nfa.active_accepting().into_iter().filter_map(|s| nfa.state_data(s)).collect();
+        assert_eq!(kinds, vec![&Kind::Ident]);
+
+        nfa.reset();
+        nfa.run("12".chars());
+        let states = nfa.active_accepting();
+        assert_eq!(states.len(), 1);
+        assert_eq!(nfa.state_data(states[0]), None);
+        assert_eq!(nfa.state_data(0), None);
+
+        // The APIs that don't use the data work with it
+        assert_eq!(nfa.search("if 42", MatchPolicy::LeftmostLongest), Some(0 .. 2));
+        assert_eq!(nfa.find_iter("ab 42", MatchPolicy::LeftmostLongest).count(), 2);
+        let decoded = NFA::decode(&nfa.encode().unwrap()).unwrap();
+        assert_eq!(decoded.transitions, nfa.transitions);
+        let mut lazy = nfa.determinize_lazy();
+        assert!(lazy.run("else".chars()));
+    }
+
+    #[test]
+    fn run_batch() {
+        let nfa = Regex::seq(Regex::plus(Regex::CharSet(('a' ..= 'z').into())),
+                             Regex::star(Regex::CharSet(('0' ..= '9').into()))).to_nfa();
+        let inputs: Vec<String> = (0 .. 1000).map(|i| {
+            match i % 4 {
+                0 => format!("abc{}", i),
+                1 => format!("{}abc", i),
+                2 => "x".repeat(i % 7),
+                _ => format!("a{}b", i),
+            }
+        }).collect();
+        let inputs: Vec<&str> = inputs.iter().map(|s| s.as_str()).collect();
+
+        let mut sequential_nfa = nfa.clone();
+        let sequential: Vec<bool> = inputs.iter().map(|input| {
+            sequential_nfa.reset();
+            sequential_nfa.run(input.chars())
+        }).collect();
+        assert_eq!(nfa.run_batch(&inputs), sequential);
+        assert!(sequential.iter().any(|b| *b) && sequential.iter().any(|b| !*b));
+    }
+
+    #[test]
+    fn run_both() {
+        let ident = Regex::plus(Regex::CharSet(CharSet::Range { lo: 'a', hi: 'z' }));
+        let keyword = Regex::or(Regex::literal("if"), Regex::literal("else"));
+        let mut ident = ident.to_nfa();
+        let mut keyword = keyword.to_nfa();
+
+        assert_eq!(ident.run_both(&mut keyword, "foo".chars()), (true, false));
+        assert_eq!(ident.run_both(&mut keyword, "else".chars()), (true, true));
+        assert_eq!(ident.run_both(&mut keyword, "if1".chars()), (false, false));
+        assert_eq!(keyword.run_both(&mut ident, "if".chars()), (true, true));
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn run_stream() {
+        use futures::executor::block_on;
+        use futures::stream;
+
+        let mut nfa = Regex::seq(Regex::literal("ab"), Regex::star(Regex::literal("c"))).to_nfa();
+        assert!(block_on(nfa.run_stream(stream::iter("abccc".chars()))));
+        nfa.reset();
+        assert!(!block_on(nfa.run_stream(stream::iter("abd".chars()))));
+
+        // Dead automata don't wait for the end of the stream
+        nfa.reset();
+        assert!(!block_on(nfa.run_stream(stream::iter("x".chars().chain(::std::iter::repeat('c'))))));
+    }
+
+    #[test]
+    fn feed_until_dead() {
+        let mut nfa = Regex::seq(Regex::literal("ab"), Regex::star(Regex::literal("c"))).to_nfa();
+
+        let input: Vec<char> = "abccxc".chars().collect();
+        let mut pos = 0;
+        let result = nfa.feed_until_dead(|| {
+            let c = input.get(pos).cloned();
+            pos += 1;
+            c
+        });
+        assert_eq!(result, (5, Some(4)));
+        assert_eq!(pos, 5);
+
+        nfa.reset();
+        let mut chars = "a".chars();
+        assert_eq!(nfa.feed_until_dead(|| chars.next()), (1, None));
+
+        nfa.reset();
+        let mut chars = "abc".chars();
+        assert_eq!(nfa.feed_until_dead(|| chars.next()), (3, Some(3)));
+
+        // An accepting start state is an empty match
+        let mut nfa = Regex::star(Regex::literal("a")).to_nfa();
+        let mut chars = "b".chars();
+        assert_eq!(nfa.feed_until_dead(|| chars.next()), (1, Some(0)));
+    }
+
+    #[test]
+    fn plus_states() {
+        // `r+` adds `r` once, plus a loop state: 8 states with the start state, instead of 13 for
+        // `r r*`
+        let r = Regex::plus(Regex::literal("abcdef"));
+        assert!(NFABuilder::build_with_limit(&r, 8).is_ok());
+        assert_eq!(NFABuilder::build_with_limit(&r, 7).err(), Some(BuildError::TooManyStates));
+
+        let mut nfa = r.to_nfa();
+        for s in &["abcdef", "abcdefabcdef", "abcdefabcdefabcdef"] {
+            nfa.reset();
+            assert!(nfa.run(s.chars()), "{}", s);
+        }
+        for s in &["", "abcde", "abcdefa", "abcdefabcdeff"] {
+            nfa.reset();
+            assert!(!nfa.run(s.chars()), "{}", s);
+        }
+    }
+
+    #[test]
+    fn ques_states() {
+        // Each `(ab)?` adds 2 states for `ab` and a join state, so the states after it don't need
+        // to be duplicated for the paths that skip and match `ab`: 1 + 4 * 3 + 6 = 19 states
+        let mut r = Regex::literal("abcdef");
+        for _ in 0 .. 4 {
+            r = Regex::seq(Regex::ques(Regex::literal("ab")), r);
+        }
+        assert!(NFABuilder::build_with_limit(&r, 19).is_ok());
+        assert_eq!(NFABuilder::build_with_limit(&r, 18).err(), Some(BuildError::TooManyStates));
+
+        let mut nfa = r.to_nfa();
+        for s in &["abcdef", "ababcdef", "abababababcdef"] {
+            nfa.reset();
+            assert!(nfa.run(s.chars()), "{}", s);
+        }
+        for s in &["", "abab", "ababababababcdef", "aabcdef"] {
+            nfa.reset();
+            assert!(!nfa.run(s.chars()), "{}", s);
+        }
+
+        // On its own a `Ques` costs one extra state for the join
+        let r = Regex::ques(Regex::literal("abcdef"));
+        assert!(NFABuilder::build_with_limit(&r, 8).is_ok());
+        assert_eq!(NFABuilder::build_with_limit(&r, 7).err(), Some(BuildError::TooManyStates));
+
+        // After an `Or` there's a join state for each branch: 1 + 2 + 2 * (1 + 1) + 2 * 3 = 13
+        let r = Regex::seq(Regex::or(Regex::literal("a"), Regex::literal("b")),
+                           Regex::seq(Regex::ques(Regex::literal("c")), Regex::literal("def")));
+        assert!(NFABuilder::build_with_limit(&r, 13).is_ok());
+        assert_eq!(NFABuilder::build_with_limit(&r, 12).err(), Some(BuildError::TooManyStates));
+    }
+
+    #[test]
+    fn loops_dont_leak() {
+        // Back edges of a loop used to go to the states before the loop, which also have the
+        // transitions of the other branches
+        let a_star_or_b = Regex::or(Regex::star(Regex::literal("a")), Regex::literal("b"));
+        let mut nfa = a_star_or_b.to_nfa();
+        assert!(!nfa.run("ab".chars()));
+        nfa.reset();
+        assert!(nfa.run("aa".chars()));
+        nfa.reset();
+        assert!(nfa.run("b".chars()));
+
+        let mut nfa = Regex::or(Regex::plus(Regex::literal("a")), Regex::literal("b")).to_nfa();
+        assert!(!nfa.run("ab".chars()));
+        nfa.reset();
+        assert!(!nfa.run("ba".chars()));
+
+        // Nested loops: the outer loop can't be left in the middle of the inner one
+        let mut nfa = Regex::star(Regex::seq(Regex::star(Regex::literal("a")), Regex::literal("b"))).to_nfa();
+        for &(input, accepted) in &[("", true), ("b", true), ("aab", true), ("bab", true), ("ba", false),
+                                    ("a", false), ("abaa", false)] {
+            nfa.reset();
+            assert_eq!(nfa.run(input.chars()), accepted, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn run_with_repeat_cap() {
+        let nfa = Regex::star(Regex::literal("a")).to_nfa();
+        assert!(nfa.run_with_repeat_cap("".chars(), 3));
+        assert!(nfa.run_with_repeat_cap("aaa".chars(), 3));
+        assert!(!nfa.run_with_repeat_cap("aaaa".chars(), 3));
+
+        let nfa = Regex::plus(Regex::literal("ab")).to_nfa();
+        assert!(nfa.run_with_repeat_cap("abab".chars(), 2));
+        assert!(!nfa.run_with_repeat_cap("ababab".chars(), 2));
+
+        // Inner loop is counted from zero in each iteration of the outer loop
+        let nfa = Regex::star(Regex::seq(Regex::star(Regex::literal("a")), Regex::literal("b"))).to_nfa();
+        assert!(nfa.run_with_repeat_cap("aabaab".chars(), 2));
+        assert!(!nfa.run_with_repeat_cap("aaab".chars(), 2));
+        assert!(!nfa.run_with_repeat_cap("ababab".chars(), 2));
+    }
+
+    #[test]
+    fn run_counts() {
+        let digit = Regex::CharSet(CharSet::Range { lo: '0', hi: '9' });
+        let digits = Regex::count_group(0, Regex::plus(Regex::count_group(1, digit)));
+        let phone = Regex::seq(digits.clone(), Regex::star(Regex::seq(Regex::literal("-"), digits)));
+        let nfa = phone.to_nfa();
+
+        let counts = nfa.run_counts("555-123-4567".chars()).unwrap();
+        assert_eq!(counts.get(&0), Some(&3));
+        assert_eq!(counts.get(&1), Some(&10));
+
+        let counts = nfa.run_counts("5".chars()).unwrap();
+        assert_eq!(counts.get(&0), Some(&1));
+        assert_eq!(counts.get(&1), Some(&1));
+
+        assert_eq!(nfa.run_counts("555-".chars()), None);
+        assert_eq!(nfa.run_counts("".chars()), None);
+    }
+
+    #[test]
+    fn step_class() {
+        // Digits and other chars. Other chars are split into the intervals before and after the
+        // digits.
+        let digits = Regex::plus(Regex::CharSet(CharSet::Range { lo: '0', hi: '9' }));
+        let mut nfa = Regex::seq(digits, Regex::CharSet(CharSet::Diff {
+            include: Box::new(CharSet::AnyChar),
+            exclude: Box::new(CharSet::Range { lo: '0', hi: '9' }),
+        })).to_nfa();
+        let classes = nfa.build_class_table();
+        assert_eq!(classes.num_classes(), 3);
+        assert_eq!(classes.class_of('0'), classes.class_of('7'));
+        assert_eq!(classes.class_of('a'), classes.class_of(std::char::MAX));
+        assert_ne!(classes.class_of('a'), classes.class_of('\0'));
+        assert_ne!(classes.class_of('a'), classes.class_of('5'));
+
+        for &(input, accept) in &[("12x", true), ("1", false), ("x", false), ("1xx", false)] {
+            nfa.reset();
+            for c in input.chars() {
+                nfa.step_class(classes.class_of(c));
+            }
+            assert_eq!(nfa.check_accepting(), accept, "{}", input);
+        }
+    }
+
+    #[test]
+    fn run_classified() {
+        let digit = || Regex::CharSet(CharSet::Range { lo: '0', hi: '9' });
+        let mut number = Regex::plus(digit()).to_nfa();
+        let mut ident = Regex::seq(Regex::CharSet(CharSet::Range { lo: 'a', hi: 'z' }), Regex::star(digit())).to_nfa();
+
+        let classes = CharClasses::from_nfas(&[&number, &ident]);
+        assert_eq!(classes.num_classes(), 5);
+        number.build_class_table_for(&classes);
+        ident.build_class_table_for(&classes);
+
+        for &(input, is_number, is_ident) in &[("123", true, false), ("x12", false, true), ("x", false, true),
+                                               ("1x", false, false), ("", false, false)] {
+            let input_classes = ClassifiedInput::new(&classes, input);
+            number.reset();
+            ident.reset();
+            assert_eq!(number.run_classified(&input_classes), is_number, "{}", input);
+            assert_eq!(ident.run_classified(&input_classes), is_ident, "{}", input);
+        }
+    }
+
+    #[test]
+    fn run_with_observer() {
+        let mut nfa = Regex::literal("abc").to_nfa();
+        let mut observed = vec![];
+        let accepted = nfa.run_with_observer("abx\u{e9}".chars(), |i, c, states| observed.push((i, c, states.len())));
+        assert!(!accepted);
+        assert_eq!(observed, vec![(0, 'a', 1), (1, 'b', 1), (2, 'x', 0), (3, '\u{e9}', 0)]);
+
+        nfa.reset();
+        let mut n_calls = 0;
+        assert!(nfa.run_with_observer("abc".chars(), |_, _, _| n_calls += 1));
+        assert_eq!(n_calls, 3);
+    }
+
+    #[test]
+    fn difference() {
+        let ident = Regex::plus(Regex::CharSet(CharSet::Range { lo: 'a', hi: 'z' }));
+        let keywords = Regex::or(Regex::literal("if"), Regex::literal("while"));
+        let mut nfa = ident.difference(&keywords);
+        for &(input, accept) in &[("whil", true), ("while", false), ("whiles", true), ("if", false),
+                                  ("i", true), ("iff", true), ("", false), ("if!", false)] {
+            nfa.reset();
+            assert_eq!(nfa.run(input.chars()), accept, "{}", input);
+        }
+
+        let mut nfa = ident.difference(&Regex::literal("if"));
+        assert!(nfa.run("while".chars()));
+        nfa.reset();
+        assert!(!nfa.run("if".chars()));
+    }
+
+    #[test]
+    fn determinize() {
+        let abc = ['a', 'b', 'c'];
+        let r = Regex::seq(Regex::star(Regex::or(Regex::literal("a"), Regex::literal("ab"))), Regex::literal("c"));
+        let nfa = r.to_nfa();
+        let dfa = nfa.determinize();
+        assert_accepts_same(&nfa, &dfa, &abc, 6);
+        for ts in dfa.transitions.values() {
+            assert!(ts.iter().all(|&(ref cs, _)| *cs != CharSet::Epsilon));
+        }
+    }
+
+    #[test]
+    fn to_rust_fn() {
+        let nfa = Regex::seq(Regex::literal("a"), Regex::ques(Regex::literal("b"))).to_nfa();
+        assert_eq!(nfa.to_rust_fn("ab"), "\
+fn ab(input: &str) -> bool {
+    let mut state: usize = 0;
+    for c in input.chars() {
+        state = match (state, c) {
+            (0, 'a') => 1,
+            (1, 'b') => 2,
+            _ => return false,
+        };
+    }
+    match state {
+        1 | 2 => true,
+        _ => false,
+    }
+}
+");
+
+        let nfa = Regex::plus(Regex::CharSet(CharSet::Diff {
+            include: Box::new(CharSet::AnyChar),
+            exclude: Box::new(CharSet::SingleChar('\'')),
+        })).to_nfa();
+        assert_eq!(nfa.to_rust_fn("no_quotes"), "\
+fn no_quotes(input: &str) -> bool {
+    let mut state: usize = 0;
+    for c in input.chars() {
+        state = match (state, c) {
+            (0, '\\0' ..= '&') | (0, '(' ..= '\\u{10ffff}') => 1,
+            (1, '\\0' ..= '&') | (1, '(' ..= '\\u{10ffff}') => 1,
+            _ => return false,
+        };
+    }
+    match state {
+        1 => true,
+        _ => false,
+    }
+}
+");
+    }
+
+    // `to_rust_fn` output for `rust_fn_regexes`, compiled into the tests
+    mod rust_fns {
+        #![allow(clippy::all)]
+        include!("rust_fns.rs");
+
+        pub const ALL: &[(&str, fn(&str) -> bool)] =
+            &[("ab", ab), ("no_quotes", no_quotes), ("ends_with_abb", ends_with_abb), ("address", address)];
+    }
+
+    fn rust_fn_regexes() -> Vec<(&'static str, Regex)> {
+        let word = Regex::plus(Regex::char_class(&[('a', 'z'), ('0', '9')], false));
+        vec![
+            ("ab", Regex::seq(Regex::literal("a"), Regex::ques(Regex::literal("b")))),
+            ("no_quotes", Regex::plus(Regex::char_class(&[('\'', '\'')], true))),
+            ("ends_with_abb",
+             Regex::seq(Regex::star(Regex::or(Regex::literal("a"), Regex::literal("b"))), Regex::literal("abb"))),
+            ("address",
+             Regex::seq(word.clone(), Regex::seq(Regex::literal("@"), Regex::seq(word.clone(),
+                        Regex::plus(Regex::seq(Regex::literal("."), word)))))),
+        ]
+    }
+
+    #[test]
+    fn to_rust_fn_compiles() {
+        let regexes = rust_fn_regexes();
+
+        // src/rust_fns.rs needs to be regenerated when the output changes
+        let mut source = String::new();
+        for &(name, ref regex) in &regexes {
+            source.push_str(&regex.to_nfa().to_rust_fn(name));
+        }
+        assert_eq!(include_str!("rust_fns.rs"), source);
+
+        let alphabet = ['a', 'b', '1', '@', '.', '\'', '\u{E9}'];
+        let mut inputs = vec![String::new()];
+        for len in 1 .. 5 {
+            let mut next = vec![];
+            for s in inputs.iter().filter(|s| s.chars().count() == len - 1) {
+                for c in &alphabet {
+                    let mut s = s.clone();
+                    s.push(*c);
+                    next.push(s);
+                }
+            }
+            inputs.extend(next);
+        }
+        inputs.push("john.smith@example.com".to_owned());
+        inputs.push("babaabb".to_owned());
+
+        for (&(name, ref regex), &(fn_name, f)) in regexes.iter().zip(rust_fns::ALL) {
+            assert_eq!(name, fn_name);
+            let mut nfa = regex.to_nfa();
+            for input in &inputs {
+                nfa.reset();
+                assert_eq!(f(input), nfa.run(input.chars()), "{} {:?}", name, input);
+            }
+        }
+    }
+
+    #[test]
+    fn run_with_lookbehind() {
+        // `[a-z ]*\bx[a-z]*`: state 2 has the word boundary assertion before the `x`
+        let mut transitions = HashMap::new();
+        transitions.insert(0, vec![(CharSet::Range { lo: 'a', hi: 'z' }, 0), (CharSet::SingleChar(' '), 0),
+                                   (CharSet::Epsilon, 2)]);
+        transitions.insert(2, vec![(CharSet::SingleChar('x'), 1)]);
+        transitions.insert(1, vec![(CharSet::Range { lo: 'a', hi: 'z' }, 1)]);
+        let mut accepting = HashMap::new();
+        accepting.insert(1, 0);
+        let mut nfa = NFA::new(transitions, accepting);
+
+        let mut seen = vec![];
+        assert!(!nfa.run_with_lookbehind("ab c".chars(), |state, prev, c| {
+            if state == 0 {
+                seen.push((prev, c));
+            }
+            true
+        }));
+        assert_eq!(seen, vec![(None, 'a'), (Some('a'), 'b'), (Some('b'), ' '), (Some(' '), 'c')]);
+
+        let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+        let mut at_boundary = |state: usize, prev: Option<char>, _: char| {
+            state != 2 || prev.filter(|p| is_word_char(*p)).is_none()
+        };
+        for &(input, accepted) in &[("xyz", true), ("ab xyz", true), ("ab xyx", true), ("abxyz", false),
+                                    ("axx", false), ("ab x", true), ("ab", false)] {
+            nfa.reset();
+            assert_eq!(nfa.run_with_lookbehind(input.chars(), &mut at_boundary), accepted, "{:?}", input);
+        }
+
+        // `step_with_lookbehind` sees the previous char given to it
+        nfa.reset();
+        nfa.step_with_lookbehind(Some('a'), 'x', &mut at_boundary);
+        assert!(!nfa.check_accepting());
+        nfa.reset();
+        nfa.step_with_lookbehind(Some(' '), 'x', &mut at_boundary);
+        assert!(nfa.check_accepting());
+    }
 
-            if new_states.is_empty() {
-                break;
-            } else {
-                self.cur_states.extend(&new_states);
-                new_states.clear();
+    #[test]
+    fn add_sink_and_complete() {
+        let abc = ['a', 'b', 'c'];
+        let alphabet = CharSet::Range { lo: 'a', hi: 'c' };
+
+        // A dead state (2) reached on `b` from the start
+        let mut transitions = HashMap::new();
+        transitions.insert(0, vec![(CharSet::SingleChar('a'), 1), (CharSet::SingleChar('b'), 2)]);
+        transitions.insert(1, vec![(CharSet::SingleChar('a'), 1)]);
+        transitions.insert(2, vec![(CharSet::SingleChar('c'), 2)]);
+        let mut accepting = HashMap::new();
+        accepting.insert(1, 0);
+        let nfa = NFA::new(transitions, accepting);
+
+        let mut complete = nfa.clone();
+        let sink = complete.add_sink_and_complete(&alphabet);
+        // The dead state's number is free for the sink
+        assert_eq!(sink, 2);
+        assert_accepts_same(&nfa, &complete, &abc, 5);
+
+        // Every state has exactly one transition on every char of the alphabet
+        let mut states: Vec<usize> = complete.transitions.keys().cloned().collect();
+        states.sort();
+        assert_eq!(states, vec![0, 1, 2]);
+        for state in states {
+            for &c in &abc {
+                assert_eq!(complete.range_index[&state].targets(c).len(), 1, "{} {}", state, c);
             }
         }
+        assert_eq!(complete.range_index[&0].targets('b'), &[sink]);
+
+        let r = Regex::seq(Regex::star(Regex::literal("ab")), Regex::literal("c"));
+        let mut dfa = r.to_nfa().determinize();
+        dfa.add_sink_and_complete(&alphabet);
+        assert_accepts_same(&r.to_nfa(), &dfa, &abc, 6);
+
+        // A class table built after completing sees the sink
+        let mut complete = complete;
+        let classes = complete.build_class_table();
+        complete.reset();
+        complete.step_class(classes.class_of('b'));
+        assert_eq!(complete.cur_states, Some(sink).into_iter().collect());
+        complete.step_class(classes.class_of('a'));
+        assert_eq!(complete.cur_states, Some(sink).into_iter().collect());
     }
-}
 
-#[derive(Clone)]
-pub enum Regex {
-    Eps,
-    CharSet(CharSet),
-    Seq(Box<Regex>, Box<Regex>),
-    Or(Box<Regex>, Box<Regex>),
-    Star(Box<Regex>),
-    Plus(Box<Regex>),
-    Ques(Box<Regex>),
-}
+    #[test]
+    #[should_panic(expected = "class table is not built")]
+    fn add_sink_and_complete_drops_class_table() {
+        let mut nfa = Regex::literal("ab").to_nfa();
+        nfa.build_class_table();
+        nfa.add_sink_and_complete(&CharSet::Range { lo: 'a', hi: 'c' });
+        nfa.step_class(0);
+    }
 
+    #[test]
+    fn canonical_strings() {
+        let a = || Regex::literal("a");
+        let b = || Regex::literal("b");
+
+        assert_eq!(Regex::or(a(), Regex::star(b())).to_nfa().canonical_string(),
+                   "0 -[a]-> 1\n0 -ε-> 2\n1 accepting 0\n2 -[b]-> 3\n2 -ε-> 4\n3 -ε-> 2\n4 accepting 0\n");
+        assert_eq!(Regex::seq(a(), b()).to_nfa().canonical_string(),
+                   "0 -[a]-> 1\n1 -[b]-> 2\n2 accepting 0\n");
+        assert_eq!(Regex::plus(a()).to_nfa().canonical_string(),
+                   "0 -ε-> 1\n1 -[a]-> 2\n2 -ε-> 1\n2 accepting 0\n");
+        assert_eq!(Regex::ques(a()).to_nfa().canonical_string(),
+                   "0 -[a]-> 1\n0 -ε-> 2\n1 -ε-> 2\n2 accepting 0\n");
+
+        // Renumbering states doesn't change the string
+        let mut transitions = HashMap::new();
+        transitions.insert(7, vec![(CharSet::Epsilon, 3), (CharSet::SingleChar('a'), 5)]);
+        transitions.insert(3, vec![(CharSet::Epsilon, 11), (CharSet::Union(vec![CharSet::SingleChar('b')]), 9)]);
+        transitions.insert(9, vec![(CharSet::Epsilon, 3)]);
+        let mut accepting = HashMap::new();
+        accepting.insert(5, 0);
+        accepting.insert(11, 0);
+        let mut nfa = NFA::new(transitions, accepting);
+        nfa.set_start(7);
+        assert_eq!(nfa.canonical_string(), Regex::or(a(), Regex::star(b())).to_nfa().canonical_string());
+    }
 
-pub struct NFABuilder {
-    next_state: usize,
-    transitions: HashMap<usize, Vec<(CharSet, usize)>>,
-}
+    #[test]
+    fn alphabet_classes() {
+        let lower = Regex::CharSet(CharSet::Range { lo: 'a', hi: 'z' });
+        let nfa = Regex::seq(lower.clone(), Regex::star(lower)).to_nfa();
+        assert_eq!(nfa.alphabet_classes(),
+                   (vec![('\0', '`', 0), ('a', 'z', 1), ('{', std::char::MAX, 0)], 2));
+
+        // Overlapping sets: [a-f], [d-z] and 'q' split [a-z] into four classes
+        let nfa = Regex::or(Regex::CharSet(CharSet::Range { lo: 'a', hi: 'f' }),
+                            Regex::seq(Regex::CharSet(CharSet::Range { lo: 'd', hi: 'z' }),
+                                       Regex::literal("q"))).to_nfa();
+        let (ranges, num_classes) = nfa.alphabet_classes();
+        assert_eq!(ranges, vec![('\0', '`', 0), ('a', 'c', 1), ('d', 'f', 2), ('g', 'p', 3), ('q', 'q', 4),
+                                ('r', 'z', 3), ('{', std::char::MAX, 0)]);
+        assert_eq!(num_classes, 5);
+    }
 
-impl NFABuilder {
-    pub fn build(regex: &Regex) -> NFA {
-        let mut builder = NFABuilder {
-            next_state: 1,
-            transitions: HashMap::new(),
-        };
+    #[test]
+    fn sliding_matcher() {
+        let mut matcher = SlidingMatcher::new(Regex::literal("ERROR").to_nfa(), 100);
+        let matches: Vec<(usize, ::std::ops::Range<usize>)> = "ok ERRERROR ok\nERROR".chars().enumerate()
+            .filter_map(|(i, c)| matcher.push(c).map(|m| (i, m)))
+            .collect();
+        assert_eq!(matches, vec![(10, 6 .. 11), (19, 15 .. 20)]);
+
+        // Matches longer than the window are not reported
+        let a_to_b = Regex::seq(Regex::literal("a"), Regex::seq(Regex::star(Regex::literal("x")), Regex::literal("b")));
+        let mut matcher = SlidingMatcher::new(a_to_b.to_nfa(), 4);
+        let matches: Vec<::std::ops::Range<usize>> = "axxxbaxxbab".chars().filter_map(|c| matcher.push(c)).collect();
+        assert_eq!(matches, vec![5 .. 9, 9 .. 11]);
+    }
 
-        let accepting_states = builder.add_regex(&vec![0], regex);
+    #[test]
+    fn run_progress() {
+        let r = Regex::literal("abcd");
 
-        NFA::new(builder.transitions, HashSet::from_iter(accepting_states.into_iter()))
-    }
+        let mut nfa = r.to_nfa();
+        assert_eq!(nfa.run_progress("abXcd".chars()), (false, 2));
 
-    fn add_regex(&mut self, current_states: &[usize], regex: &Regex) -> Vec<usize> {
-        match regex {
+        nfa.reset();
+        assert_eq!(nfa.run_progress("ab".chars()), (false, 2));
 
-            &Regex::Eps => {
-                current_states.to_owned()
-            }
+        nfa.reset();
+        assert_eq!(nfa.run_progress("abcd".chars()), (true, 4));
 
-            &Regex::CharSet(ref cs) => {
-                let mut next_states = Vec::with_capacity(current_states.len());
-                for state in current_states.iter().cloned() {
-                    let next_state = self.new_state();
-                    self.add_transition(state, cs, next_state);
-                    next_states.push(next_state);
-                }
-                next_states
-            }
+        nfa.reset();
+        assert_eq!(nfa.run_progress("abcde".chars()), (false, 4));
+    }
 
-            &Regex::Seq(ref r1, ref r2) => {
-                let next_states = self.add_regex(current_states, r1);
-                self.add_regex(&next_states, r2)
+    #[test]
+    fn line_lexer() {
+        #[derive(Debug, Clone, PartialEq)]
+        enum Tok { Word, Num }
+
+        let word = Regex::plus(Regex::CharSet(CharSet::Range { lo: 'a', hi: 'z' }));
+        let num = Regex::plus(Regex::CharSet(CharSet::Range { lo: '0', hi: '9' }));
+        let lexer = LineLexer::new(Lexer::new(vec![(word, Tok::Word), (num, Tok::Num)]));
+
+        assert_eq!(lexer.lex_line("ab12c"),
+                   Ok(vec![(Tok::Word, 0 .. 2), (Tok::Num, 2 .. 4), (Tok::Word, 4 .. 5)]));
+
+        let input = "ab12\r\n34cd\n5!6\n\nx";
+        let lines: Vec<_> = lexer.lines(input.as_bytes()).collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0].as_ref().unwrap(), &vec![(Tok::Word, 0 .. 2), (Tok::Num, 2 .. 4)]);
+        assert_eq!(lines[1].as_ref().unwrap(), &vec![(Tok::Num, 0 .. 2), (Tok::Word, 2 .. 4)]);
+        match lines[2] {
+            Err(lexer::LineLexError::Lex { line, ref error }) => {
+                assert_eq!(line, 2);
+                assert_eq!(error.pos, 1);
             }
+            ref other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(lines[3].as_ref().unwrap(), &vec![]);
+        assert_eq!(lines[4].as_ref().unwrap(), &vec![(Tok::Word, 0 .. 1)]);
+    }
 
-            &Regex::Or(ref r1, ref r2) => {
-                let mut next_states_1 = self.add_regex(current_states, r1);
-                let mut next_states_2 = self.add_regex(current_states, r2);
-                let mut ret = Vec::with_capacity(next_states_1.len() + next_states_2.len());
-                ret.append(&mut next_states_1);
-                ret.append(&mut next_states_2);
-                ret
-            }
+    #[test]
+    fn lexer_skip_rules() {
+        #[derive(Debug, Clone, PartialEq)]
+        enum Tok { Ident, Kw }
+
+        let ident = Regex::plus(Regex::CharSet(CharSet::Range { lo: 'a', hi: 'z' }));
+        let space = Regex::plus(Regex::CharSet(CharSet::SingleChar(' ')));
+        let lexer = Lexer::new_with_skips(vec![(ident.clone(), Tok::Ident)], vec![space.clone()]);
+        let tokens: Result<Vec<_>, _> = lexer.tokenize("a  b").collect();
+        assert_eq!(tokens, Ok(vec![(Tok::Ident, 0 .. 1), (Tok::Ident, 3 .. 4)]));
+
+        let tokens: Result<Vec<_>, _> = lexer.tokenize("  ").collect();
+        assert_eq!(tokens, Ok(vec![]));
+
+        // Longest match wins over the rule kind: the comment `--if` is skipped as a whole
+        let comment = Regex::seq(Regex::literal("--"), Regex::star(Regex::CharSet(CharSet::Range { lo: 'a', hi: 'z' })));
+        let lexer = Lexer::new_with_skips(vec![(Regex::literal("-"), Tok::Kw), (ident, Tok::Ident)],
+                                          vec![space, comment]);
+        let tokens: Result<Vec<_>, _> = lexer.tokenize("x --if -y").collect();
+        assert_eq!(tokens, Ok(vec![(Tok::Ident, 0 .. 1), (Tok::Kw, 7 .. 8), (Tok::Ident, 8 .. 9)]));
+    }
 
-            &Regex::Star(ref r) => {
-                let next_states = self.add_regex(current_states, r);
-                // add epsilon transitions from next states to current states
-                for next_state in next_states {
-                    for current_state in current_states {
-                        self.add_transition(next_state, &CharSet::Epsilon, *current_state)
-                    }
-                }
-                current_states.to_owned()
-            }
+    #[test]
+    fn lexer_accepting_by_length() {
+        #[derive(Debug, Clone, PartialEq)]
+        enum Tok { If, Ifdef, Ident }
+
+        let ident = Regex::plus(Regex::CharSet(CharSet::Range { lo: 'a', hi: 'z' }));
+        let space = Regex::plus(Regex::CharSet(CharSet::SingleChar(' ')));
+        let lexer = Lexer::new_with_skips(vec![(Regex::literal("if"), Tok::If),
+                                               (Regex::literal("ifdef"), Tok::Ifdef),
+                                               (ident, Tok::Ident)],
+                                          vec![space]);
+        assert_eq!(lexer.accepting_by_length("ifdefs x"),
+                   vec![(1, vec![2]), (2, vec![0, 2]), (3, vec![2]), (4, vec![2]), (5, vec![1, 2]), (6, vec![2])]);
+        assert_eq!(lexer.accepting_by_length("  x"), vec![(1, vec![3]), (2, vec![3])]);
+        assert_eq!(lexer.accepting_by_length("?"), vec![]);
+    }
 
-            &Regex::Plus(ref r) => {
-                let next_states = self.add_regex(current_states, r);
-                let r_cloned: Box<Regex> = r.clone();
-                self.add_regex(&next_states, &Regex::Star(r_cloned))
-            }
+    #[test]
+    fn lexer_recover() {
+        #[derive(Debug, Clone, PartialEq)]
+        enum Tok { Word, Num }
+
+        let word = Regex::plus(Regex::CharSet(CharSet::Range { lo: 'a', hi: 'z' }));
+        let num = Regex::plus(Regex::CharSet(CharSet::Range { lo: '0', hi: '9' }));
+        let mut lexer = Lexer::new(vec![(word, Tok::Word), (num, Tok::Num)]);
+
+        let tokens: Vec<_> = lexer.tokenize("ab!12?c").collect();
+        assert_eq!(tokens, vec![Ok((Tok::Word, 0 .. 2)), Err(lexer::LexError { pos: 2 })]);
+
+        lexer.set_recover(true);
+        let tokens: Vec<_> = lexer.tokenize("ab!12\u{e9}c").collect();
+        assert_eq!(tokens, vec![Ok((Tok::Word, 0 .. 2)),
+                                Err(lexer::LexError { pos: 2 }),
+                                Ok((Tok::Num, 3 .. 5)),
+                                Err(lexer::LexError { pos: 5 }),
+                                Ok((Tok::Word, 7 .. 8))]);
+    }
 
-            &Regex::Ques(ref r) => {
-                let mut next_states_1 = current_states.to_owned();
-                let mut next_states_2 = self.add_regex(current_states, r);
-                next_states_1.append(&mut next_states_2);
-                next_states_1
-            }
+    // Chars to compare `CharSet::test` results on
+    const TEST_CHARS: &'static [char] =
+        &['\0', 'a', 'b', 'p', 'q', 'r', 'z', 'A', '0', '9', '\u{D7FF}', '\u{E000}', std::char::MAX];
+
+    fn assert_same_test(cs1: &CharSet, cs2: &CharSet) {
+        for c in TEST_CHARS {
+            assert_eq!(cs1.test(*c), cs2.test(*c), "{:?} vs. {:?} on {:?}", cs1, cs2, c);
         }
     }
 
-    fn new_state(&mut self) -> usize {
-        let ret = self.next_state;
-        self.next_state += 1;
-        ret
+    #[test]
+    fn charset_simplify_empty_exclude() {
+        let cs = CharSet::Diff { include: Box::new(CharSet::Range { lo: 'a', hi: 'z' }),
+                                 exclude: Box::new(CharSet::Union(vec![])) };
+        assert_eq!(cs.simplify(), CharSet::Range { lo: 'a', hi: 'z' });
+        assert_same_test(&cs, &cs.simplify());
+
+        let cs = CharSet::Diff { include: Box::new(CharSet::SingleChar('a')),
+                                 exclude: Box::new(CharSet::Range { lo: 'z', hi: 'a' }) };
+        assert_eq!(cs.simplify(), CharSet::SingleChar('a'));
+        assert_same_test(&cs, &cs.simplify());
     }
 
-    fn add_transition(&mut self, from: usize, cs: &CharSet, to: usize) {
-        match self.transitions.entry(from) {
-            Entry::Occupied(mut ent) => {
-                ent.get_mut().push((cs.clone(), to));
-            },
-            Entry::Vacant(ent) => {
-                ent.insert(vec![(cs.clone(), to)]);
-            },
-        }
+    #[test]
+    fn charset_simplify_full_exclude() {
+        let cs = CharSet::Diff { include: Box::new(CharSet::Range { lo: 'a', hi: 'z' }),
+                                 exclude: Box::new(CharSet::AnyChar) };
+        assert_eq!(cs.simplify(), CharSet::Union(vec![]));
+        assert_same_test(&cs, &cs.simplify());
+
+        let cs = CharSet::Diff {
+            include: Box::new(CharSet::SingleChar('a')),
+            exclude: Box::new(CharSet::Union(vec![CharSet::Range { lo: '\0', hi: 'm' },
+                                                  CharSet::Range { lo: 'n', hi: std::char::MAX }])),
+        };
+        assert_eq!(cs.simplify(), CharSet::Union(vec![]));
+        assert_same_test(&cs, &cs.simplify());
     }
-}
 
-////////////////////////////////////////////////////////////////////////////////////////////////////
+    #[test]
+    fn charset_simplify_complement() {
+        let cs = CharSet::Diff { include: Box::new(CharSet::AnyChar),
+                                 exclude: Box::new(CharSet::Range { lo: 'b', hi: 'y' }) };
+        assert_eq!(cs.simplify(),
+                   CharSet::Union(vec![CharSet::Range { lo: '\0', hi: 'a' },
+                                       CharSet::Range { lo: 'z', hi: std::char::MAX }]));
+        assert_same_test(&cs, &cs.simplify());
+    }
 
-#[cfg(test)]
-mod tests {
+    #[test]
+    fn charset_simplify_union() {
+        let cs = CharSet::Union(vec![CharSet::Union(vec![CharSet::SingleChar('a')]),
+                                     CharSet::Range { lo: 'z', hi: 'a' }]);
+        assert_eq!(cs.simplify(), CharSet::SingleChar('a'));
+        assert_same_test(&cs, &cs.simplify());
+
+        let cs = CharSet::Union(vec![CharSet::SingleChar('a'),
+                                     CharSet::Union(vec![CharSet::SingleChar('b'), CharSet::AnyChar])]);
+        assert_eq!(cs.simplify(), CharSet::AnyChar);
+        assert_same_test(&cs, &cs.simplify());
+
+        // Members that are only full together, with the surrogate gap between them
+        let cs = CharSet::Union(vec![CharSet::Range { lo: '\0', hi: '\u{D7FF}' },
+                                     CharSet::Union(vec![CharSet::Range { lo: '\u{E000}', hi: '\u{10FFFF}' }])]);
+        assert_eq!(cs.simplify(), CharSet::AnyChar);
+        assert_same_test(&cs, &cs.simplify());
+    }
 
-    use super::*;
+    #[test]
+    fn charset_contains_set() {
+        let a_z = CharSet::Range { lo: 'a', hi: 'z' };
+        let c_f = CharSet::Range { lo: 'c', hi: 'f' };
+        assert!(a_z.contains_set(&c_f));
+        assert!(!c_f.contains_set(&a_z));
+
+        let a_c = CharSet::Range { lo: 'a', hi: 'c' };
+        let b_d = CharSet::Range { lo: 'b', hi: 'd' };
+        assert!(!a_c.contains_set(&b_d));
+        assert!(!b_d.contains_set(&a_c));
+
+        let a_c_e = CharSet::Union(vec![a_c.clone(), CharSet::SingleChar('e')]);
+        assert!(a_c_e.contains_set(&CharSet::Union(vec![CharSet::SingleChar('b'),
+                                                        CharSet::SingleChar('e')])));
+        assert!(!a_c_e.contains_set(&CharSet::Range { lo: 'c', hi: 'e' }));
+
+        let empty = CharSet::Union(vec![]);
+        assert!(CharSet::AnyChar.contains_set(&a_z));
+        assert!(!a_z.contains_set(&CharSet::AnyChar));
+        assert!(a_z.contains_set(&empty));
+        assert!(empty.contains_set(&empty));
+        assert!(!empty.contains_set(&a_z));
+    }
 
     #[test]
-    fn regex_seq() {
-        let cs1 = CharSet::SingleChar('a');
-        let cs2 = CharSet::SingleChar('b');
-        let cs3 = CharSet::SingleChar('c');
-        let r1  = Regex::Seq(
-                    Box::new(Regex::CharSet(cs1)),
-                    Box::new(Regex::Seq(Box::new(Regex::CharSet(cs2)),
-                                        Box::new(Regex::CharSet(cs3)))));
+    fn charset_class_string_round_trip() {
+        for s in &["[]", "[^]", "[a]", "[ab]", "[0-9_a-z]", "[^a-z]", "[\\-\\\\\\^]", "[\\]]", "[^\\^a-c]"] {
+            let cs = CharSet::from_class_string(s).unwrap();
+            assert_eq!(&cs.to_class_string(), s);
+            assert_same_test(&cs, &CharSet::from_class_string(&cs.to_class_string()).unwrap());
+        }
 
+        let cs = CharSet::Diff { include: Box::new(CharSet::Range { lo: 'a', hi: 'z' }),
+                                 exclude: Box::new(CharSet::SingleChar('q')) };
+        assert_eq!(cs.to_class_string(), "[a-pr-z]");
 
-        let mut nfa = NFABuilder::build(&r1);
-        assert!(nfa.run("abc".chars()));
+        let cs = CharSet::Diff { include: Box::new(CharSet::AnyChar),
+                                 exclude: Box::new(CharSet::SingleChar('q')) };
+        assert_eq!(cs.to_class_string(), "[^q]");
+        assert_same_test(&cs, &CharSet::from_class_string("[^q]").unwrap());
+    }
 
-        nfa.reset();
-        assert!(!nfa.run("".chars()));
+    #[test]
+    fn charset_display_escapes() {
+        use charset::escape_char_for_display;
+
+        let cs = CharSet::Union(vec![CharSet::SingleChar('\n'), CharSet::SingleChar('"'), CharSet::SingleChar('a')]);
+        assert_eq!(cs.to_class_string(), "[\\n\\\"a]");
+        assert_eq!(cs.to_string(), "[\\n\\\"a]");
+        assert_same_test(&cs, &CharSet::from_class_string(&cs.to_class_string()).unwrap());
+
+        let cs = CharSet::Range { lo: '\0', hi: '\u{1F}' };
+        assert_eq!(cs.to_class_string(), "[\\0-\\x1F]");
+        assert_same_test(&cs, &CharSet::from_class_string(&cs.to_class_string()).unwrap());
+
+        assert_eq!(escape_char_for_display('\t'), "\\t");
+        assert_eq!(escape_char_for_display('\u{7F}'), "\\x7F");
+        assert_eq!(escape_char_for_display('\u{85}'), "\\u{85}");
+        assert_eq!(escape_char_for_display('\u{A0}'), "\\u{A0}");
+        assert_eq!(escape_char_for_display(' '), " ");
+        assert_eq!(escape_char_for_display('\u{E9}'), "\u{E9}");
+        assert_eq!(CharSet::Epsilon.to_string(), "\u{3B5}");
+    }
 
-        nfa.reset();
-        assert!(!nfa.run("a".chars()));
+    #[test]
+    fn charset_from_class_string() {
+        use charset::ParseError;
+
+        assert_eq!(CharSet::from_class_string("[-a^-]"),
+                   Ok(CharSet::Union(vec![CharSet::SingleChar('-'),
+                                          CharSet::SingleChar('a'),
+                                          CharSet::SingleChar('^'),
+                                          CharSet::SingleChar('-')])));
+        assert_eq!(CharSet::from_class_string("[^0-9]"),
+                   Ok(CharSet::Diff { include: Box::new(CharSet::AnyChar),
+                                      exclude: Box::new(CharSet::Union(vec![CharSet::Range { lo: '0', hi: '9' }])) }));
+
+        assert_eq!(CharSet::from_class_string(""), Err(ParseError::UnexpectedEnd));
+        assert_eq!(CharSet::from_class_string("[a-"), Err(ParseError::UnexpectedEnd));
+        assert_eq!(CharSet::from_class_string("[a\\"), Err(ParseError::UnexpectedEnd));
+        assert_eq!(CharSet::from_class_string("a]"), Err(ParseError::UnexpectedChar { offset: 0, c: 'a' }));
+        assert_eq!(CharSet::from_class_string("[a]b"), Err(ParseError::UnexpectedChar { offset: 3, c: 'b' }));
+        assert_eq!(CharSet::from_class_string("[az-a]"), Err(ParseError::InvalidRange { offset: 2 }));
+    }
 
-        nfa.reset();
-        assert!(!nfa.run("ab".chars()));
+    #[test]
+    fn charset_from() {
+        let cs: CharSet = 'a'.into();
+        assert_eq!(cs, CharSet::SingleChar('a'));
+        assert!(cs.test('a'));
+        assert!(!cs.test('b'));
+
+        let cs: CharSet = ('a', 'z').into();
+        assert_eq!(cs, CharSet::Range { lo: 'a', hi: 'z' });
+        assert!(cs.test('a'));
+        assert!(cs.test('m'));
+        assert!(!cs.test('A'));
+
+        let mut nfa = Regex::seq(Regex::CharSet('a'.into()), Regex::CharSet(('0', '9').into())).to_nfa();
+        assert!(nfa.run("a5".chars()));
+    }
 
-        nfa.reset();
-        assert!(!nfa.run("abcd".chars()));
+    #[test]
+    fn charset_to_ranges() {
+        let cs = CharSet::Diff { include: Box::new(('a', 'z').into()), exclude: Box::new('q'.into()) };
+        assert_eq!(cs.to_ranges(), vec![('a', 'p'), ('r', 'z')]);
+
+        // Excluding the ends, nested `Diff`s
+        let cs = CharSet::Diff {
+            include: Box::new(CharSet::Union(vec![('a', 'f').into(), ('x', 'z').into()])),
+            exclude: Box::new(CharSet::Diff {
+                include: Box::new(CharSet::Union(vec!['a'.into(), ('e', 'y').into()])),
+                exclude: Box::new('x'.into()),
+            }),
+        };
+        assert_eq!(cs.to_ranges(), vec![('b', 'd'), ('x', 'x'), ('z', 'z')]);
+
+        let cs = CharSet::Diff { include: Box::new(CharSet::AnyChar), exclude: Box::new(('\0', 'y').into()) };
+        assert_eq!(cs.to_ranges(), vec![('z', ::std::char::MAX)]);
     }
 
     #[test]
-    fn regex_or() {
-        let cs1 = CharSet::SingleChar('a');
-        let cs2 = CharSet::SingleChar('b');
-        let cs3 = CharSet::SingleChar('c');
-        let r1  = Regex::Or(
-                    Box::new(Regex::CharSet(cs1)),
-                    Box::new(Regex::Or(Box::new(Regex::CharSet(cs2)),
-                                       Box::new(Regex::CharSet(cs3)))));
+    fn charset_surrogate_boundary() {
+        let around_gap = CharSet::Range { lo: '\u{D7FE}', hi: '\u{E001}' };
+
+        // Excluding the chars right before and after the gap
+        let cs = CharSet::Diff { include: Box::new(around_gap.clone()), exclude: Box::new('\u{D7FF}'.into()) };
+        assert_eq!(cs.to_ranges(), vec![('\u{D7FE}', '\u{D7FE}'), ('\u{E000}', '\u{E001}')]);
+        let cs = CharSet::Diff { include: Box::new(around_gap.clone()), exclude: Box::new('\u{E000}'.into()) };
+        assert_eq!(cs.to_ranges(), vec![('\u{D7FE}', '\u{D7FF}'), ('\u{E001}', '\u{E001}')]);
+
+        // Ranges on the two sides of the gap are adjacent
+        let cs = CharSet::Union(vec![('a', '\u{D7FF}').into(), ('\u{E000}', '\u{E005}').into()]);
+        assert_eq!(cs.to_ranges(), vec![('a', '\u{E005}')]);
+
+        // Complement and intersection
+        let cs = CharSet::Diff { include: Box::new(CharSet::AnyChar), exclude: Box::new(around_gap.clone()) };
+        assert_eq!(cs.to_ranges(), vec![('\0', '\u{D7FD}'), ('\u{E002}', ::std::char::MAX)]);
+        let cs = CharSet::Diff { include: Box::new(around_gap.clone()), exclude: Box::new(cs) };
+        assert_eq!(cs.to_ranges(), vec![('\u{D7FE}', '\u{E001}')]);
+
+        assert_eq!(around_gap.iter_chars(), Some(vec!['\u{D7FE}', '\u{D7FF}', '\u{E000}', '\u{E001}']));
+        assert_eq!(CharSet::Range { lo: '\u{10FFFE}', hi: ::std::char::MAX }.iter_chars().map(|chars| chars.len()),
+                   Some(2));
+    }
 
-        let mut nfa = NFABuilder::build(&r1);
-        assert!(nfa.run("a".chars()));
+    #[test]
+    fn charset_union_all() {
+        let cs = CharSet::union_all(vec![CharSet::Union(vec!['a'.into(), ('x', 'z').into()]),
+                                         'b'.into(),
+                                         CharSet::Union(vec![CharSet::Union(vec!['c'.into()])])]);
+        assert_eq!(cs, CharSet::Union(vec!['a'.into(), ('x', 'z').into(), 'b'.into(),
+                                          CharSet::Union(vec!['c'.into()])]));
+
+        // Empty members are dropped
+        let cs = CharSet::union_all(vec![CharSet::Union(vec![]), 'a'.into(), CharSet::Union(vec![('z', 'a').into()])]);
+        assert_eq!(cs, CharSet::SingleChar('a'));
+        let cs = CharSet::union_all(vec![CharSet::Union(vec![('a', 'z').into()])]);
+        assert_eq!(cs, ('a', 'z').into());
+
+        assert_eq!(CharSet::union_all(vec![CharSet::Union(vec![]), ('z', 'a').into()]), CharSet::Union(vec![]));
+        assert_eq!(CharSet::union_all(vec![]), CharSet::Union(vec![]));
+
+        let cs = CharSet::union_all(vec![('\0', '\u{D7FF}').into(), ('\u{E000}', '\u{10FFFF}').into()]);
+        assert_eq!(cs, CharSet::AnyChar);
+        let cs = CharSet::union_all(vec![('\0', 'm').into(), ('n', '\u{D7FF}').into(), ('\u{E000}', '\u{FFFF}').into()]);
+        // Surrogates are not chars, so the ranges around them are adjacent
+        assert_eq!(cs.to_ranges(), vec![('\0', '\u{FFFF}')]);
+    }
 
+    #[test]
+    fn charset_predicate() {
+        let control = CharSet::predicate(char::is_control);
+        assert!(control.test('\n'));
+        assert!(control.test('\u{7F}'));
+        assert!(!control.test('a'));
+        assert_eq!(control.to_ranges(), vec![('\0', '\u{1F}'), ('\u{7F}', '\u{9F}')]);
+
+        // Predicates are only equal to their clones
+        assert_eq!(control.clone(), control);
+        assert_ne!(CharSet::predicate(char::is_control), control);
+
+        let mut nfa = Regex::plus(Regex::CharSet(control)).to_nfa();
+        assert!(nfa.run("\t\r\n".chars()));
         nfa.reset();
-        assert!(nfa.run("b".chars()));
+        assert!(!nfa.run("\ta".chars()));
+    }
 
-        nfa.reset();
-        assert!(nfa.run("c".chars()));
+    #[test]
+    fn charset_intervals() {
+        let exclude = CharSet::Union((0 .. 200u32).map(|i| {
+            let lo = std::char::from_u32(0x100 + i * 4).unwrap();
+            CharSet::Range { lo: lo, hi: std::char::from_u32(lo as u32 + 1).unwrap() }
+        }).collect());
+        let sets = vec![
+            CharSet::Diff { include: Box::new(CharSet::AnyChar), exclude: Box::new(exclude.clone()) },
+            CharSet::Diff { include: Box::new(CharSet::SingleChar('\u{104}')), exclude: Box::new(exclude.clone()) },
+            CharSet::Diff { include: Box::new(CharSet::Range { lo: 'a', hi: '\u{200}' }), exclude: Box::new(exclude) },
+            CharSet::Union(vec![]),
+            CharSet::AnyChar,
+        ];
+        for cs in sets {
+            let intervals = cs.to_intervals();
+            for c in (0 .. 0x400).filter_map(std::char::from_u32).chain(TEST_CHARS.iter().cloned()) {
+                assert_eq!(intervals.test(c), cs.test(c), "{:?} {:?}", cs, c);
+            }
+        }
+    }
 
-        nfa.reset();
-        assert!(!nfa.run("ac".chars()));
+    #[test]
+    fn charset_explain() {
+        let cs = CharSet::Union(vec![CharSet::SingleChar('a'), CharSet::Range { lo: '0', hi: '9' }]);
+        assert_eq!(cs.explain('x'), "'x' in Union: not in SingleChar('a'); not in Range('0'-'9'); => false");
+        assert_eq!(cs.explain('a'), "'a' in Union: in SingleChar('a'); not in Range('0'-'9'); => true");
+
+        // Identifier chars, except 'a' to 'f' other than 'c'
+        let cs = CharSet::Diff {
+            include: Box::new(CharSet::Union(vec![CharSet::Range { lo: 'a', hi: 'z' }, CharSet::SingleChar('_')])),
+            exclude: Box::new(CharSet::Diff {
+                include: Box::new(CharSet::Range { lo: 'a', hi: 'f' }),
+                exclude: Box::new(CharSet::SingleChar('c')),
+            }),
+        };
+        assert_eq!(cs.explain('c'),
+                   "'c' in Diff: in include Union(in Range('a'-'z'); not in SingleChar('_')); \
+                    not in exclude Diff(in include Range('a'-'f'); in exclude SingleChar('c')); => true");
+        assert_eq!(cs.explain('d'),
+                   "'d' in Diff: in include Union(in Range('a'-'z'); not in SingleChar('_')); \
+                    in exclude Diff(in include Range('a'-'f'); not in exclude SingleChar('c')); => false");
+
+        assert_eq!(CharSet::Range { lo: '0', hi: '9' }.explain('5'), "'5' in Range('0'-'9') => true");
+        assert_eq!(CharSet::Union(vec![]).explain('5'), "'5' in Union: => false");
     }
 
     #[test]
-    fn regex_eps() {
-        let r1  = Regex::Eps;
+    fn charset_range_u32() {
+        use charset::CharSetError;
 
-        let mut nfa = NFABuilder::build(&r1);
-        assert!(nfa.run("".chars()));
+        assert_eq!(CharSet::range_u32(0x61, 0x7A), Ok(CharSet::Range { lo: 'a', hi: 'z' }));
+        assert_eq!(CharSet::range_u32(0, 0x10FFFF), Ok(CharSet::Range { lo: '\0', hi: std::char::MAX }));
+        assert_eq!(CharSet::range_u32(0xE000, 0xE0FF).unwrap().to_ranges(), vec![('\u{E000}', '\u{E0FF}')]);
+
+        assert_eq!(CharSet::range_u32(0x41, 0xD800), Err(CharSetError::Surrogate(0xD800)));
+        assert_eq!(CharSet::range_u32(0xDFFF, 0xE000), Err(CharSetError::Surrogate(0xDFFF)));
+        assert_eq!(CharSet::range_u32(0x41, 0x110000), Err(CharSetError::OutOfRange(0x110000)));
+        assert_eq!(CharSet::range_u32(u32::MAX, 0x41), Err(CharSetError::OutOfRange(u32::MAX)));
     }
 
     #[test]
-    fn regex_star() {
-        let cs1 = CharSet::SingleChar('a');
-        let r1  = Regex::Star(Box::new(Regex::CharSet(cs1)));
+    fn charset_intervals_nested_diff() {
+        // [a-z] minus ([h-t] minus [m-o]) = [a-g] [m-o] [u-z]
+        let cs = CharSet::Diff {
+            include: Box::new(CharSet::Range { lo: 'a', hi: 'z' }),
+            exclude: Box::new(CharSet::Diff {
+                include: Box::new(CharSet::Range { lo: 'h', hi: 't' }),
+                exclude: Box::new(CharSet::Range { lo: 'm', hi: 'o' }),
+            }),
+        };
+        assert_eq!(cs.to_ranges(), vec![('a', 'g'), ('m', 'o'), ('u', 'z')]);
 
-        let mut nfa = NFABuilder::build(&r1);
-        assert!(nfa.run("".chars()));
+        // Same in a complement, with another level of nesting
+        let cs = CharSet::Diff { include: Box::new(CharSet::AnyChar), exclude: Box::new(cs) };
+        let intervals = cs.to_intervals();
+        for c in (0 ..= std::char::MAX as u32).filter_map(std::char::from_u32) {
+            assert_eq!(intervals.test(c), cs.test(c), "{:?}", c);
+        }
+    }
 
-        nfa.reset();
-        assert!(nfa.run("a".chars()));
+    #[test]
+    fn cached_charset() {
+        use charset::CachedCharSet;
 
-        nfa.reset();
-        assert!(nfa.run("aa".chars()));
+        let cs = CharSet::from_class_string("[\\t\\n\\r ]").unwrap();
+        let cached = CachedCharSet::new(cs.clone());
+        for c in "  \t\tx  xx\n ".chars() {
+            assert_eq!(cached.test(c), cs.test(c), "{:?}", c);
+        }
+        assert_eq!(cached.into_inner(), cs);
+    }
 
-        nfa.reset();
-        assert!(nfa.run("aaa".chars()));
+    #[cfg(feature = "profile")]
+    #[test]
+    fn charset_test_counts() {
+        // Other tests can run at the same time, so only lower bounds can be checked
+        let before = CharSet::test_counts();
+        let a = CharSet::SingleChar('a');
+        assert!(a.test('a'));
+        assert!(!a.test('b'));
+        let union = CharSet::Union(vec![CharSet::SingleChar('x'), CharSet::Range { lo: 'a', hi: 'f' }]);
+        assert!(union.test('c'));
+        let after = CharSet::test_counts();
+
+        assert!(after.single_char - before.single_char >= 3);
+        assert!(after.range - before.range >= 1);
+        assert!(after.union - before.union >= 1);
     }
 
+    #[cfg(feature = "profile")]
     #[test]
-    fn regex_plus() {
-        let cs1 = CharSet::SingleChar('a');
-        let r1  = Regex::Plus(Box::new(Regex::CharSet(cs1)));
+    fn transition_lookup_counts() {
+        let word = Regex::plus(Regex::CharSet(CharSet::Range { lo: 'a', hi: 'z' }));
+        let num = Regex::plus(Regex::CharSet(CharSet::Range { lo: '0', hi: '9' }));
+        let lexer = Lexer::new(vec![(word, 0), (num, 1)]);
+
+        let before = CharSet::test_counts();
+        let tokens: Vec<_> = lexer.tokenize("abc123").collect();
+        assert_eq!(tokens, vec![Ok((0, 0 .. 3)), Ok((1, 3 .. 6))]);
+        let after = CharSet::test_counts();
+
+        assert!(after.transition_lookups > before.transition_lookups);
+        assert!(after.transition_hits > before.transition_hits);
+    }
 
-        let mut nfa = NFABuilder::build(&r1);
-        assert!(!nfa.run("".chars()));
+    #[test]
+    fn charset_canonical() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        fn hash(cs: &CharSet) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            cs.hash(&mut hasher);
+            hasher.finish()
+        }
 
-        nfa.reset();
-        assert!(nfa.run("a".chars()));
+        let a_c = CharSet::Range { lo: 'a', hi: 'c' };
+        let union = CharSet::Union(vec![CharSet::Range { lo: 'a', hi: 'a' }, CharSet::Range { lo: 'b', hi: 'c' }]);
+        let diff = CharSet::Diff { include: Box::new(CharSet::Range { lo: 'a', hi: 'z' }),
+                                   exclude: Box::new(CharSet::Range { lo: 'd', hi: 'z' }) };
+        assert_ne!(a_c, union);
+        for cs in &[&union, &diff, &CharSet::predicate(|c| ('a' ..= 'c').contains(&c))] {
+            assert_eq!(cs.canonical(), a_c);
+            assert_eq!(hash(cs), hash(&a_c));
+        }
 
-        nfa.reset();
-        assert!(nfa.run("aa".chars()));
+        let full = CharSet::Union(vec![CharSet::Range { lo: '\0', hi: 'm' }, CharSet::Range { lo: 'n', hi: std::char::MAX }]);
+        assert_eq!(full.canonical(), CharSet::AnyChar);
+        assert_eq!(hash(&full), hash(&CharSet::AnyChar));
+        assert_eq!(CharSet::Union(vec![]).canonical(), CharSet::Union(vec![]));
+        assert_eq!(CharSet::Epsilon.canonical(), CharSet::Epsilon);
 
-        nfa.reset();
-        assert!(nfa.run("aaa".chars()));
+        let mut interned = HashSet::new();
+        interned.insert(a_c.canonical());
+        assert!(interned.contains(&union.canonical()));
     }
 
     #[test]
-    fn regex_ques() {
-        let cs1 = CharSet::SingleChar('a');
-        let r1  = Regex::Ques(Box::new(Regex::CharSet(cs1)));
+    fn charset_iter_chars() {
+        let cs = CharSet::Union(vec![CharSet::SingleChar('b'), CharSet::SingleChar('a')]);
+        assert_eq!(cs.iter_chars(), Some(vec!['a', 'b']));
 
-        let mut nfa = NFABuilder::build(&r1);
-        assert!(nfa.run("".chars()));
+        let cs = CharSet::Diff { include: Box::new(('a', 'e').into()), exclude: Box::new('c'.into()) };
+        assert_eq!(cs.iter_chars(), Some(vec!['a', 'b', 'd', 'e']));
 
-        nfa.reset();
-        assert!(nfa.run("a".chars()));
+        assert_eq!(CharSet::Union(vec![]).iter_chars(), Some(vec![]));
+        assert_eq!(CharSet::Range { lo: '\0', hi: '\u{FF}' }.iter_chars().map(|chars| chars.len()), Some(256));
+        assert_eq!(CharSet::Range { lo: '\0', hi: '\u{100}' }.iter_chars(), None);
+        assert_eq!(CharSet::AnyChar.iter_chars(), None);
+    }
 
+    #[test]
+    fn charset_from_range_inclusive() {
+        let cs: CharSet = ('a' ..= 'z').into();
+        assert_eq!(cs, CharSet::Range { lo: 'a', hi: 'z' });
+
+        let r: Regex = ('0' ..= '9').into();
+        assert_eq!(r, Regex::CharSet(('0', '9').into()));
+        let mut nfa = Regex::plus(r).to_nfa();
+        assert!(nfa.run("2024".chars()));
+
+        let empty: CharSet = ('9' ..= '0').into();
+        assert!(empty.is_empty());
+        let mut nfa = Regex::from('9' ..= '0').to_nfa();
+        assert!(!nfa.run("5".chars()));
         nfa.reset();
-        assert!(!nfa.run("aa".chars()));
+        assert!(!nfa.run("".chars()));
     }
 
     #[test]
-    fn regex_complex() {
-        let cs1 = CharSet::SingleChar('a');
-        let cs2 = CharSet::SingleChar('b');
-        let r1  = Regex::Or(
-                    Box::new(Regex::Ques(Box::new(Regex::CharSet(cs1)))),
-                    Box::new(Regex::Ques(Box::new(Regex::CharSet(cs2)))));
-
-        let mut nfa = NFABuilder::build(&r1);
-        assert!(nfa.run("".chars()));
+    fn charset_case_fold() {
+        let cs: CharSet = ('a', 'z').into();
+        assert_eq!(cs.case_fold().to_ranges(), vec![('A', 'Z'), ('a', 'z')]);
+
+        // Partial ranges fold to the matching partial ranges
+        let cs: CharSet = ('X', 'c').into();
+        assert_eq!(cs.case_fold().to_ranges(), vec![('A', 'C'), ('X', 'c'), ('x', 'z')]);
+
+        let cs = CharSet::Union(vec!['0'.into(), 'q'.into(), 'É'.into(), 'ß'.into(), ('σ', 'σ').into()]);
+        let folded = cs.case_fold();
+        for c in "0qQÉéßσΣ".chars() {
+            assert!(folded.test(c), "{}", c);
+        }
+        for c in "1pPS".chars() {
+            assert!(!folded.test(c), "{}", c);
+        }
 
-        nfa.reset();
-        assert!(nfa.run("a".chars()));
+        assert_eq!(CharSet::AnyChar.case_fold(), CharSet::AnyChar);
+        assert!(CharSet::Union(vec![]).case_fold().is_empty());
+
+        // Large sets are folded by intervals, not by walking every char
+        let cs = CharSet::Diff { include: Box::new(CharSet::AnyChar), exclude: Box::new('a'.into()) };
+        assert!(cs.case_fold().is_full());
+        let cs: CharSet = ('\u{80}', char::MAX).into();
+        let folded = cs.case_fold();
+        assert!(folded.test('k') && folded.test('\u{212A}'));
+        assert!(!folded.test('a') && !folded.test('K'));
+
+        // Every char with a case variant is folded
+        for c in (0 ..= char::MAX as u32).filter_map(std::char::from_u32) {
+            for variant in [c.to_lowercase().collect::<Vec<_>>(), c.to_uppercase().collect()].iter() {
+                if variant.len() == 1 && variant[0] != c {
+                    assert!(CharSet::SingleChar(c).case_fold().test(variant[0]), "{:?}", c);
+                }
+            }
+        }
+    }
 
-        nfa.reset();
-        assert!(nfa.run("b".chars()));
+    #[test]
+    fn charset_class_string_escapes() {
+        use charset::ParseError;
+
+        assert_eq!(CharSet::from_class_string("[\\x41]"),
+                   Ok(CharSet::Union(vec![CharSet::SingleChar('A')])));
+        assert_eq!(CharSet::from_class_string("[\\u{1F600}]"),
+                   Ok(CharSet::Union(vec![CharSet::SingleChar('\u{1F600}')])));
+        assert_eq!(CharSet::from_class_string("[\\t\\n\\r\\0]"),
+                   Ok(CharSet::Union(vec![CharSet::SingleChar('\t'),
+                                          CharSet::SingleChar('\n'),
+                                          CharSet::SingleChar('\r'),
+                                          CharSet::SingleChar('\0')])));
+        assert_eq!(CharSet::from_class_string("[\\x00-\\x7f]"),
+                   Ok(CharSet::Union(vec![CharSet::Range { lo: '\0', hi: '\x7f' }])));
+
+        assert_eq!(CharSet::from_class_string("[\\u{110000}]"), Err(ParseError::InvalidEscape { offset: 1 }));
+        assert_eq!(CharSet::from_class_string("[\\u{D800}]"), Err(ParseError::InvalidEscape { offset: 1 }));
+        assert_eq!(CharSet::from_class_string("[a\\u{}]"), Err(ParseError::InvalidEscape { offset: 2 }));
+        assert_eq!(CharSet::from_class_string("[a\\u41]"), Err(ParseError::InvalidEscape { offset: 2 }));
+        assert_eq!(CharSet::from_class_string("[a-\\xg0]"), Err(ParseError::InvalidEscape { offset: 3 }));
+        assert_eq!(CharSet::from_class_string("[\\q]"), Err(ParseError::InvalidEscape { offset: 1 }));
+        assert_eq!(CharSet::from_class_string("[\\x4"), Err(ParseError::UnexpectedEnd));
+    }
 
-        nfa.reset();
-        assert!(!nfa.run("c".chars()));
+    #[test]
+    fn charset_class_string_backreference() {
+        use charset::ParseError;
 
-        nfa.reset();
-        assert!(!nfa.run("ab".chars()));
+        assert_eq!(CharSet::from_class_string("[\\1]"), Err(ParseError::UnsupportedBackreference { offset: 1 }));
+        assert_eq!(CharSet::from_class_string("[ab\\9]"), Err(ParseError::UnsupportedBackreference { offset: 3 }));
+        assert_eq!(CharSet::from_class_string("[\\0]"), Ok(CharSet::Union(vec![CharSet::SingleChar('\0')])));
     }
 
     #[test]
@@ -379,4 +3590,344 @@ mod tests {
                                           CharSet::SingleChar('\''),
                                           CharSet::SingleChar('#')])));
     }
+
+    #[test]
+    fn charset_parsing_negated() {
+        let input = "[^0-9]";
+        let cs = charset_parser::parse_CharSet0(input).unwrap();
+        assert_eq!(cs,
+                   CharSet::Diff { include: Box::new(CharSet::AnyChar),
+                                   exclude: Box::new(CharSet::Union(vec![CharSet::Range { lo: '0', hi: '9' }])) });
+        assert!(cs.test('a'));
+        assert!(!cs.test('5'));
+
+        assert!(charset_parser::parse_CharSet0("[^]").is_err());
+
+        assert_eq!(charset_parser::parse_CharSet0("[a^]"),
+                   Ok(CharSet::Union(vec![CharSet::SingleChar('a'),
+                                          CharSet::SingleChar('^')])));
+        assert_eq!(charset_parser::parse_CharSet0("^"),
+                   Ok(CharSet::SingleChar('^')));
+    }
+
+    #[test]
+    fn charset_parsing_shorthand_classes() {
+        use charset::ParseError;
+
+        let non_digit = CharSet::from_class_string("[\\D]").unwrap();
+        assert!(non_digit.test('a'));
+        assert!(!non_digit.test('5'));
+
+        let non_space = charset_parser::parse_CharSet0("\\S").unwrap();
+        assert!(non_space.test('x'));
+        assert!(!non_space.test(' '));
+        assert!(!non_space.test('\n'));
+
+        let word = CharSet::from_class_string("[\\w-]").unwrap();
+        assert!(word.test('Q') && word.test('_') && word.test('7') && word.test('-'));
+        assert!(!word.test(' ') && !word.test('é'));
+
+        assert_eq!(charset_parser::parse_CharSet0("[\\d#]").unwrap().to_ranges(),
+                   vec![('#', '#'), ('0', '9')]);
+        assert_eq!(CharSet::from_class_string("[a-\\d]"), Err(ParseError::InvalidEscape { offset: 3 }));
+
+        // The negated forms are `Diff`s from `AnyChar`, so `set_any_char_set` restricts them
+        let mut builder = NFABuilder::new();
+        builder.set_any_char_set(CharSet::Range { lo: '\0', hi: '\u{7F}' });
+        for (tag, class) in ["[\\D]", "[\\W]", "[\\S]"].iter().enumerate() {
+            builder.add_rule(&Regex::CharSet(CharSet::from_class_string(class).unwrap()), tag);
+        }
+        let mut nfa = builder.finish();
+        for &(input, ref tags) in &[("x", vec![0, 2]), ("5", vec![2]), (" ", vec![0, 1]), ("-", vec![0, 1, 2]),
+                                    ("\u{E9}", vec![])] {
+            nfa.reset();
+            nfa.run(input.chars());
+            assert_eq!(nfa.accepting_tags(), *tags, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn charset_parsing_escapes() {
+        use charset::GrammarError;
+
+        let escapes = [("\\t", '\t'), ("\\n", '\n'), ("\\r", '\r'), ("\\0", '\0'), ("\\x41", 'A'),
+                       ("\\u{1F600}", '\u{1F600}'), ("\\u{e9}", '\u{E9}')];
+        for &(escape, c) in escapes.iter() {
+            let class = format!("[{}]", escape);
+            let expected = CharSet::Union(vec![CharSet::SingleChar(c)]);
+            assert_eq!(charset_parser::parse_CharSet0(&class), Ok(expected.clone()), "{}", escape);
+            assert_eq!(CharSet::from_class_string(&class), Ok(expected), "{}", escape);
+            assert_eq!(charset_parser::parse_CharSet0(escape), Ok(CharSet::SingleChar(c)), "{}", escape);
+        }
+
+        let range = "[\\x41-\\u{5A}]";
+        let expected = CharSet::Union(vec![CharSet::Range { lo: 'A', hi: 'Z' }]);
+        assert_eq!(charset_parser::parse_CharSet0(range), Ok(expected.clone()));
+        assert_eq!(CharSet::from_class_string(range), Ok(expected));
+
+        for invalid in ["\\u{110000}", "\\u{D800}"].iter() {
+            assert!(charset_parser::parse_CharSet0(invalid).is_err(), "{}", invalid);
+            assert!(CharSet::from_class_string(&format!("[{}]", invalid)).is_err(), "{}", invalid);
+        }
+
+        // The grammar reports where the invalid escape is
+        let error = match charset_parser::parse_CharSet0("[ab\\u{D800}]") {
+            Err(lalrpop_util::ParseError::User { error }) => error,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        assert_eq!(error, GrammarError::InvalidEscape { offset: 3, ch: 'u' });
+        assert_eq!(error.to_string(), "invalid escape \\u at offset 3");
+    }
+
+    #[test]
+    fn lazy_dfa() {
+        // (a|b)*abb
+        let r = Regex::seq(Regex::star(Regex::or(Regex::literal("a"), Regex::literal("b"))),
+                           Regex::literal("abb"));
+
+        let mut nfa = r.to_nfa();
+        let mut dfa = r.to_nfa().determinize_lazy();
+        let inputs = ["", "abb", "aabb", "babb", "ab", "abba", "abbabb", "c", "abbc"];
+        for input in inputs.iter() {
+            nfa.reset();
+            dfa.reset();
+            assert_eq!(dfa.run(input.chars()), nfa.run(input.chars()), "{:?}", input);
+        }
+
+        // All states are cached now
+        let num_states = dfa.num_states();
+        for input in inputs.iter() {
+            dfa.reset();
+            dfa.run(input.chars());
+        }
+        assert_eq!(dfa.num_states(), num_states);
+
+        // Chars in the same class share the cached transitions
+        dfa.reset();
+        dfa.run("xyz\u{1F600}".chars());
+        assert_eq!(dfa.num_states(), num_states);
+
+        // The cache is flushed when it's full, without changing the results
+        let mut limited = r.to_nfa().determinize_lazy();
+        limited.set_cache_limit(2);
+        for input in inputs.iter() {
+            nfa.reset();
+            limited.reset();
+            assert_eq!(limited.run(input.chars()), nfa.run(input.chars()), "{:?}", input);
+            assert!(limited.num_states() <= 2);
+        }
+        assert!(limited.num_flushes() > 0);
+    }
+
+    #[test]
+    fn encode_decode() {
+        let ident = Regex::seq(
+            Regex::CharSet(CharSet::Union(vec![CharSet::Range { lo: 'a', hi: 'z' }, CharSet::SingleChar('_')])),
+            Regex::star(Regex::CharSet(CharSet::Diff {
+                include: Box::new(CharSet::AnyChar),
+                exclude: Box::new(CharSet::Union(vec![CharSet::SingleChar(' '), CharSet::SingleChar('\u{1F600}')])),
+            })));
+        let num = Regex::plus(Regex::CharSet(CharSet::Range { lo: '0', hi: '9' }));
+        let nfa = NFABuilder::build_rules(&[ident, num, Regex::literal("if")]);
+
+        let bytes = nfa.encode().unwrap();
+        let mut decoded = NFA::decode(&bytes).unwrap();
+        assert_eq!(decoded.transitions, nfa.transitions);
+        assert_eq!(decoded.accepting, nfa.accepting);
+        for state in nfa.accepting.keys() {
+            assert_eq!(decoded.accepting_priority(*state), nfa.accepting_priority(*state));
+        }
+        assert_eq!(decoded.encode().unwrap(), bytes);
+
+        let mut abc = Regex::literal("abc").to_nfa();
+        let b_state = abc.transitions[&0][0].1;
+        abc.set_start(b_state);
+        let mut decoded_abc = NFA::decode(&abc.encode().unwrap()).unwrap();
+        assert_eq!(decoded_abc.start(), b_state);
+        assert!(decoded_abc.run("bc".chars()));
+
+        // Loop edges are kept, so repeat caps still work
+        let capped = NFA::decode(&Regex::star(Regex::literal("a")).to_nfa().encode().unwrap()).unwrap();
+        assert!(capped.run_with_repeat_cap("aaa".chars(), 3));
+        assert!(!capped.run_with_repeat_cap("aaaa".chars(), 3));
+
+        // So are count edges
+        let digits = Regex::plus(Regex::count_group(0, Regex::CharSet(CharSet::Range { lo: '0', hi: '9' })));
+        let counted = NFA::decode(&digits.to_nfa().encode().unwrap()).unwrap();
+        assert_eq!(counted.run_counts("123".chars()).unwrap()[&0], 3);
+
+        assert!(decoded.run("_x1".chars()));
+        decoded.reset();
+        assert!(decoded.accepting_tags().is_empty());
+        decoded.run("if".chars());
+        assert_eq!(decoded.accepting_tags(), vec![0, 2]);
+        decoded.reset();
+        assert!(!decoded.run("a \u{1F600}".chars()));
+    }
+
+    #[test]
+    fn decode_errors() {
+        let bytes = Regex::Or(Box::new(Regex::literal("ab")), Box::new(Regex::CharSet(CharSet::AnyChar))).to_nfa().encode().unwrap();
+
+        for len in 0 .. bytes.len() {
+            assert_eq!(NFA::decode(&bytes[.. len]).err(), Some(DecodeError::UnexpectedEnd));
+        }
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(NFA::decode(&trailing).err(), Some(DecodeError::TrailingBytes));
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(NFA::decode(&bad_magic).err(), Some(DecodeError::BadMagic));
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = 99;
+        assert_eq!(NFA::decode(&bad_version).err(), Some(DecodeError::UnsupportedVersion(99)));
+
+        // Magic, version, start, no accepting states, one state with a transition on a surrogate
+        let mut bad_char = b"NLXA\x05\0\0\0\0".to_vec();
+        bad_char.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0xD8, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(NFA::decode(&bad_char).err(), Some(DecodeError::InvalidChar(0xD800)));
+
+        let mut bad_tag = bad_char.clone();
+        bad_tag[25] = 9;
+        assert_eq!(NFA::decode(&bad_tag).err(), Some(DecodeError::InvalidCharSetTag(9)));
+
+        // Deeply nested `Diff`s
+        let mut deep = b"NLXA\x05\0\0\0\0".to_vec();
+        deep.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+        deep.extend(::std::iter::repeat(3).take(100000));
+        assert_eq!(NFA::decode(&deep).err(), Some(DecodeError::TooDeep));
+
+        let mut bad_start = bytes.clone();
+        bad_start[5] = 100;
+        assert_eq!(NFA::decode(&bad_start).err(), Some(DecodeError::InvalidStart(100)));
+
+        // Version 1 of `a`, which had no start state
+        let mut v1 = b"NLXA\x01".to_vec();
+        v1.extend_from_slice(&[1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 97, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(NFA::decode(&v1).err(), Some(DecodeError::UnsupportedVersion(1)));
+
+        // Version 2 of `a`, which had no priorities
+        let mut v2 = b"NLXA\x02\0\0\0\0".to_vec();
+        v2.extend_from_slice(&v1[5 ..]);
+        assert_eq!(NFA::decode(&v2).err(), Some(DecodeError::UnsupportedVersion(2)));
+
+        // Version 3 of `a`, which had no loop edges
+        let mut v3 = b"NLXA\x03\0\0\0\0".to_vec();
+        v3.extend_from_slice(&[1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        v3.extend_from_slice(&v1[17 ..]);
+        assert_eq!(NFA::decode(&v3).err(), Some(DecodeError::UnsupportedVersion(3)));
+
+        // Version 4 of `a`, which had no count edges
+        let mut v4 = v3.clone();
+        v4[4] = 4;
+        v4.extend_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(NFA::decode(&v4).err(), Some(DecodeError::UnsupportedVersion(4)));
+
+        // The last byte of the loop edges is the kind of the last edge, followed by no count edges
+        let star = Regex::star(Regex::literal("a")).to_nfa().encode().unwrap();
+        let mut bad_loop_edge = star.clone();
+        let kind_pos = bad_loop_edge.len() - 5;
+        bad_loop_edge[kind_pos] = 7;
+        assert_eq!(NFA::decode(&bad_loop_edge).err(), Some(DecodeError::InvalidLoopEdgeKind(7)));
+    }
+
+    #[test]
+    fn lookahead() {
+        let input: Vec<char> = "foobar foobaz foo".chars().collect();
+        let foo = Regex::literal("foo");
+        let bar = Regex::literal("bar");
+
+        let mut pos = Lookahead::positive(&foo, &bar);
+        assert_eq!(pos.find_at(&input, 0), Some(3));
+        assert_eq!(pos.find_at(&input, 7), None);
+        assert_eq!(pos.find_at(&input, 14), None);
+        assert_eq!(pos.find_at(&input, 1), None);
+
+        let mut neg = Lookahead::negative(&foo, &bar);
+        assert_eq!(neg.find_at(&input, 0), None);
+        assert_eq!(neg.find_at(&input, 7), Some(10));
+        assert_eq!(neg.find_at(&input, 14), Some(17));
+
+        // The longest match for which the assertion holds wins
+        let a_star = Regex::star(Regex::CharSet(CharSet::SingleChar('a')));
+        let mut pos = Lookahead::positive(&a_star, &Regex::literal("ab"));
+        let input: Vec<char> = "aaab".chars().collect();
+        assert_eq!(pos.find_at(&input, 0), Some(2));
+        assert_eq!(pos.find_at(&input, 3), None);
+        assert_eq!(pos.find_at(&input, 4), None);
+        assert_eq!(pos.find_at(&input, 5), None);
+
+        // With a limit the assertion only sees the next chars
+        let input: Vec<char> = "foo...bar".chars().collect();
+        let dots_bar = Regex::seq(Regex::star(Regex::literal(".")), bar);
+        let mut pos = Lookahead::positive(&foo, &dots_bar);
+        let mut neg = Lookahead::negative(&foo, &dots_bar);
+        assert_eq!(pos.find_at(&input, 0), Some(3));
+        assert_eq!(neg.find_at(&input, 0), None);
+        pos.set_limit(5);
+        neg.set_limit(5);
+        assert_eq!(pos.find_at(&input, 0), None);
+        assert_eq!(neg.find_at(&input, 0), Some(3));
+        pos.set_limit(6);
+        assert_eq!(pos.find_at(&input, 0), Some(3));
+    }
+
+    #[cfg(feature = "compress")]
+    #[test]
+    fn compressed_round_trip() {
+        // A large automaton with a lot of repetition in its encoding
+        let words: Vec<Regex> = (0 .. 200).map(|i| Regex::literal(&format!("identifier_{}", i))).collect();
+        let nfa = NFABuilder::build_rules(&words);
+
+        let mut compressed = vec![];
+        nfa.write_compressed(&mut compressed).unwrap();
+        assert!(compressed.len() < nfa.encode().unwrap().len());
+
+        let mut decoded = NFA::read_compressed(&compressed[..]).unwrap();
+        assert_eq!(decoded.encode().unwrap(), nfa.encode().unwrap());
+        assert!(decoded.run("identifier_123".chars()));
+        decoded.reset();
+        assert!(!decoded.run("identifier_".chars()));
+
+        match NFA::read_compressed(&b"not gzip"[..]) {
+            Err(ReadCompressedError::Io(_)) => {}
+            other => panic!("{:?}", other.map(|nfa| nfa.encode().unwrap())),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn regex_from_json() {
+        let spec = r#"{"seq": [
+            {"lit": "ab"},
+            {"star": {"charset": {"union": [{"range": ["a", "z"]}, {"char": "_"}]}}},
+            {"ques": {"or": [{"lit": "!"}, {"charset": {"diff": {"include": "any", "exclude": {"range": ["a", "z"]}}}}]}}
+        ]}"#;
+        let r = Regex::from_json(spec).unwrap();
+
+        for s in &["ab", "abc_d", "ab!", "abxy9"] {
+            assert!(r.to_nfa().run(s.chars()), "{}", s);
+        }
+        for s in &["", "a", "b", "abx!!", "abxyz99"] {
+            assert!(!r.to_nfa().run(s.chars()), "{}", s);
+        }
+
+        assert_eq!(Regex::from_json(r#"{"seq": []}"#).unwrap(), Regex::Eps);
+        assert_eq!(Regex::from_json(r#""eps""#).unwrap(), Regex::Eps);
+
+        match Regex::from_json("{\"seq\": [") {
+            Err(JsonError::Syntax(_)) => {}
+            other => panic!("{:?}", other),
+        }
+        for spec in &[r#"{"or": []}"#, r#"{"star": "a"}"#, r#"{"lit": "a", "star": "eps"}"#,
+                      r#"{"charset": {"char": "ab"}}"#, r#"{"charset": {"range": ["z", "a"]}}"#] {
+            match Regex::from_json(spec) {
+                Err(JsonError::Schema(_)) => {}
+                other => panic!("{}: {:?}", spec, other),
+            }
+        }
+    }
 }
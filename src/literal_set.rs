@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+/// Matches a set of string literals in one pass over the input, using the Aho-Corasick
+/// algorithm. Much faster than a general NFA for sets of fixed keywords.
+pub struct LiteralSet<T> {
+    // Trie transitions (the "goto" function). State 0 is the root.
+    goto: Vec<HashMap<char, usize>>,
+    // State to move to when there's no transition for a char. Points to the state for the longest
+    // proper suffix of the current state's string that's in the trie.
+    fail: Vec<usize>,
+    // Literals that end at each state, as indices into `literals`, longest first.
+    output: Vec<Vec<usize>>,
+    // Literals with their lengths in bytes
+    literals: Vec<(usize, T)>,
+}
+
+impl<T: Clone> LiteralSet<T> {
+    /// Empty literals are ignored.
+    pub fn new(literals: Vec<(String, T)>) -> LiteralSet<T> {
+        let mut set = LiteralSet {
+            goto: vec![HashMap::new()],
+            fail: vec![0],
+            output: vec![vec![]],
+            literals: Vec::with_capacity(literals.len()),
+        };
+
+        for (literal, value) in literals {
+            if literal.is_empty() {
+                continue;
+            }
+            let mut state = 0;
+            for c in literal.chars() {
+                state = match set.goto[state].get(&c) {
+                    Some(next) => *next,
+                    None => {
+                        let next = set.goto.len();
+                        set.goto.push(HashMap::new());
+                        set.fail.push(0);
+                        set.output.push(vec![]);
+                        set.goto[state].insert(c, next);
+                        next
+                    }
+                };
+            }
+            set.output[state].push(set.literals.len());
+            set.literals.push((literal.len(), value));
+        }
+
+        // Compute failure links in BFS order, so that links of shorter strings are ready when
+        // they're needed
+        let mut queue: VecDeque<usize> = set.goto[0].values().cloned().collect();
+        while let Some(state) = queue.pop_front() {
+            let children: Vec<(char, usize)> = set.goto[state].iter().map(|(c, s)| (*c, *s)).collect();
+            for (c, child) in children {
+                let mut fail = set.fail[state];
+                while fail != 0 && !set.goto[fail].contains_key(&c) {
+                    fail = set.fail[fail];
+                }
+                let child_fail = match set.goto[fail].get(&c) {
+                    Some(s) => *s,
+                    None => 0,
+                };
+                set.fail[child] = child_fail;
+                let inherited = set.output[child_fail].clone();
+                set.output[child].extend(inherited);
+                queue.push_back(child);
+            }
+        }
+
+        set
+    }
+
+    /// Returns all occurrences of the literals in `haystack`, including overlapping ones, as
+    /// `(start, end, value)` with byte offsets. Occurrences are ordered by end offset, and longer
+    /// ones come first when they end at the same offset.
+    pub fn find(&self, haystack: &str) -> Vec<(usize, usize, T)> {
+        let mut ret = vec![];
+        let mut state = 0;
+        for (i, c) in haystack.char_indices() {
+            loop {
+                if let Some(next) = self.goto[state].get(&c) {
+                    state = *next;
+                    break;
+                }
+                if state == 0 {
+                    break;
+                }
+                state = self.fail[state];
+            }
+
+            let end = i + c.len_utf8();
+            for literal_idx in &self.output[state] {
+                let (len, ref value) = self.literals[*literal_idx];
+                ret.push((end - len, end, value.clone()));
+            }
+        }
+        ret
+    }
+}
@@ -0,0 +1,80 @@
+use {NFA, Regex};
+
+/// A regex followed by a zero-width lookahead assertion, like `foo(?=bar)` (positive) or
+/// `foo(?!bar)` (negative).
+///
+/// Lookahead isn't regular in general, so it can't be compiled into the NFA. Instead the
+/// assertion gets its own NFA, which is run against the rest of the input at every position where
+/// the main regex accepts. A match is only reported if the assertion holds there. The assertion
+/// stops scanning as soon as its NFA dies, but an assertion like `.*bar` can scan the rest of the
+/// input each time, which makes a match O(n * n) instead of O(n). `set_limit` bounds the number of
+/// chars the assertion sees, which makes it O(n * limit).
+///
+/// The assertion is a plain `Regex`, so it can't refer back to the text matched by the main regex.
+pub struct Lookahead {
+    nfa: NFA,
+    assertion: NFA,
+    negative: bool,
+    limit: usize,
+}
+
+impl Lookahead {
+    /// `regex(?=assertion)`: the assertion needs to match a prefix of the rest of the input.
+    pub fn positive(regex: &Regex, assertion: &Regex) -> Lookahead {
+        Lookahead {
+            nfa: regex.to_nfa(),
+            assertion: assertion.to_nfa(),
+            negative: false,
+            limit: usize::MAX,
+        }
+    }
+
+    /// `regex(?!assertion)`: the assertion must not match any prefix of the rest of the input.
+    pub fn negative(regex: &Regex, assertion: &Regex) -> Lookahead {
+        Lookahead {
+            nfa: regex.to_nfa(),
+            assertion: assertion.to_nfa(),
+            negative: true,
+            limit: usize::MAX,
+        }
+    }
+
+    /// Makes the assertion only see the next `limit` chars after a match. A positive assertion
+    /// that needs more chars to match fails, and a negative one holds. Unlimited by default.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    /// Matches the regex starting at `start`, and returns the end of the longest match (in chars)
+    /// for which the assertion holds. The assertion is checked against `input[end ..]`, so it can
+    /// see the input after `start` but not before it. Returns `None` if `start` is past the end of
+    /// the input.
+    pub fn find_at(&mut self, input: &[char], start: usize) -> Option<usize> {
+        if start > input.len() {
+            return None;
+        }
+        self.nfa.reset();
+        let mut longest = None;
+        let mut pos = start;
+        loop {
+            if self.nfa.check_accepting() && self.assertion_holds(&input[pos ..]) {
+                longest = Some(pos);
+            }
+            if pos == input.len() {
+                break;
+            }
+            self.nfa.step(input[pos]);
+            if self.nfa.cur_states.is_empty() {
+                break;
+            }
+            pos += 1;
+        }
+        longest
+    }
+
+    fn assertion_holds(&mut self, rest: &[char]) -> bool {
+        let rest = &rest[.. ::std::cmp::min(rest.len(), self.limit)];
+        self.assertion.reset();
+        self.assertion.find_char_slice(rest).is_some() != self.negative
+    }
+}
@@ -0,0 +1,75 @@
+/// Builds a `Regex` from a regex-like syntax at compile time. String literals are matched
+/// literally (as with `Regex::literal`), `|` is alternation, postfix `*`, `+` and `?` are
+/// `Regex::star`, `Regex::plus` and `Regex::ques`, and parens group. Juxtaposition is sequencing,
+/// and binds tighter than `|`. An empty sequence is `Regex::Eps`.
+///
+/// ```
+/// #[macro_use]
+/// extern crate notlex;
+///
+/// use notlex::Regex;
+///
+/// fn main() {
+///     let regex = regex!("a" | "b"*);
+///     assert_eq!(regex, Regex::or(Regex::literal("a"), Regex::star(Regex::literal("b"))));
+///
+///     let mut nfa = regex!(("ab")+ "c"?).to_nfa();
+///     assert!(nfa.run("ababc".chars()));
+/// }
+/// ```
+#[macro_export]
+macro_rules! regex {
+    // Split the input on `|`. First list is the alternatives so far (each in parens), second one
+    // is the tokens of the current alternative.
+    (@alt [$($alts:tt)*] [$($seq:tt)*] | $($rest:tt)*) => {
+        $crate::regex!(@alt [$($alts)* ($($seq)*)] [] $($rest)*)
+    };
+    (@alt [$($alts:tt)*] [$($seq:tt)*] $t:tt $($rest:tt)*) => {
+        $crate::regex!(@alt [$($alts)*] [$($seq)* $t] $($rest)*)
+    };
+    (@alt [$($alts:tt)*] [$($seq:tt)*]) => {
+        $crate::regex!(@or $($alts)* ($($seq)*))
+    };
+
+    (@or ($($seq:tt)*)) => {
+        $crate::regex!(@seq $($seq)*)
+    };
+    (@or ($($seq:tt)*) $($rest:tt)+) => {
+        $crate::Regex::or($crate::regex!(@seq $($seq)*), $crate::regex!(@or $($rest)+))
+    };
+
+    (@seq) => {
+        $crate::Regex::Eps
+    };
+    (@seq $atom:tt * $($rest:tt)*) => {
+        $crate::regex!(@seq_cons ($crate::Regex::star($crate::regex!(@atom $atom))) $($rest)*)
+    };
+    (@seq $atom:tt + $($rest:tt)*) => {
+        $crate::regex!(@seq_cons ($crate::Regex::plus($crate::regex!(@atom $atom))) $($rest)*)
+    };
+    (@seq $atom:tt ? $($rest:tt)*) => {
+        $crate::regex!(@seq_cons ($crate::Regex::ques($crate::regex!(@atom $atom))) $($rest)*)
+    };
+    (@seq $atom:tt $($rest:tt)*) => {
+        $crate::regex!(@seq_cons ($crate::regex!(@atom $atom)) $($rest)*)
+    };
+
+    // Sequences are right-associative, like in `Regex::literal`
+    (@seq_cons ($head:expr)) => {
+        $head
+    };
+    (@seq_cons ($head:expr) $($rest:tt)+) => {
+        $crate::Regex::seq($head, $crate::regex!(@seq $($rest)+))
+    };
+
+    (@atom ($($group:tt)*)) => {
+        $crate::regex!($($group)*)
+    };
+    (@atom $lit:tt) => {
+        $crate::Regex::literal($lit)
+    };
+
+    ($($t:tt)*) => {
+        $crate::regex!(@alt [] [] $($t)*)
+    };
+}
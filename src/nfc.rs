@@ -0,0 +1,41 @@
+use unicode_normalization::UnicodeNormalization;
+
+use {NFA, StepResult};
+
+/// Runs an NFA over the NFC normalization of the input, so that precomposed and decomposed forms
+/// of accented chars match the same way. For example `CharSet::SingleChar('\u{E9}')` (`é`)
+/// matches both `"\u{E9}"` and `"e\u{301}"` (an `e` with a combining acute accent).
+///
+/// Normalization only composes the sequences that have a precomposed form. Other combining
+/// sequences are still multiple chars after normalization, and need to be matched as such (e.g.
+/// with a `CharSet` for the combining marks after the base char). Char offsets of the
+/// normalized input don't correspond to offsets in the original input.
+pub struct NfcNFA {
+    nfa: NFA,
+}
+
+impl NfcNFA {
+    pub fn new(nfa: NFA) -> NfcNFA {
+        NfcNFA { nfa: nfa }
+    }
+
+    /// Feeds the normalized input to the NFA and returns whether it's accepted. Stops normalizing
+    /// and feeding as soon as the NFA dies, so this also works with a strict NFA (see
+    /// `NFA::set_strict`).
+    pub fn run(&mut self, input: &str) -> bool {
+        for c in input.nfc() {
+            if self.nfa.feed(c) == StepResult::Dead {
+                return false;
+            }
+        }
+        self.nfa.check_accepting()
+    }
+
+    pub fn reset(&mut self) {
+        self.nfa.reset();
+    }
+
+    pub fn into_inner(self) -> NFA {
+        self.nfa
+    }
+}
@@ -0,0 +1,80 @@
+// Counters of `CharSet::test` calls and transition lookups, for finding the sets that are worth
+// optimizing. Only compiled with the `profile` feature, so `test` and `NFA` steps don't pay for
+// them otherwise.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use CharSet;
+
+/// Number of `CharSet::test` calls on each kind of set, since the start of the program or the
+/// last `CharSet::reset_test_counts`. Tests of the members of `Diff`s and `Union`s are counted
+/// too. Counters are global, so they include the calls of all threads.
+///
+/// NFA steps don't call `test`: they look up the targets of a char in each active state's
+/// precomputed ranges. Those lookups are counted in `transition_lookups`, and the ones that found
+/// a transition in `transition_hits`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TestCounts {
+    pub single_char: usize,
+    pub range: usize,
+    pub any_char: usize,
+    pub diff: usize,
+    pub union: usize,
+    pub epsilon: usize,
+    pub predicate: usize,
+    pub transition_lookups: usize,
+    pub transition_hits: usize,
+}
+
+static SINGLE_CHAR: AtomicUsize = AtomicUsize::new(0);
+static RANGE: AtomicUsize = AtomicUsize::new(0);
+static ANY_CHAR: AtomicUsize = AtomicUsize::new(0);
+static DIFF: AtomicUsize = AtomicUsize::new(0);
+static UNION: AtomicUsize = AtomicUsize::new(0);
+static EPSILON: AtomicUsize = AtomicUsize::new(0);
+static PREDICATE: AtomicUsize = AtomicUsize::new(0);
+static TRANSITION_LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+static TRANSITION_HITS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn record_test(cs: &CharSet) {
+    let counter = match cs {
+        &CharSet::SingleChar(_) => &SINGLE_CHAR,
+        &CharSet::Range { .. } => &RANGE,
+        &CharSet::AnyChar => &ANY_CHAR,
+        &CharSet::Diff { .. } => &DIFF,
+        &CharSet::Union(_) => &UNION,
+        &CharSet::Epsilon => &EPSILON,
+        &CharSet::Predicate(_) => &PREDICATE,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_lookup(hit: bool) {
+    TRANSITION_LOOKUPS.fetch_add(1, Ordering::Relaxed);
+    if hit {
+        TRANSITION_HITS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl CharSet {
+    pub fn test_counts() -> TestCounts {
+        TestCounts {
+            single_char: SINGLE_CHAR.load(Ordering::Relaxed),
+            range: RANGE.load(Ordering::Relaxed),
+            any_char: ANY_CHAR.load(Ordering::Relaxed),
+            diff: DIFF.load(Ordering::Relaxed),
+            union: UNION.load(Ordering::Relaxed),
+            epsilon: EPSILON.load(Ordering::Relaxed),
+            predicate: PREDICATE.load(Ordering::Relaxed),
+            transition_lookups: TRANSITION_LOOKUPS.load(Ordering::Relaxed),
+            transition_hits: TRANSITION_HITS.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset_test_counts() {
+        for counter in &[&SINGLE_CHAR, &RANGE, &ANY_CHAR, &DIFF, &UNION, &EPSILON, &PREDICATE,
+                         &TRANSITION_LOOKUPS, &TRANSITION_HITS] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+
+use CharSet;
+
+/// Char transitions of a single state, split into disjoint ranges sorted by their start, so that
+/// the targets for a char can be found with a binary search instead of testing every charset.
+#[derive(Clone, Debug)]
+pub struct RangeIndex {
+    // (lo, hi, targets) with inclusive bounds as `u32`s, so that `hi + 1` doesn't need to skip
+    // surrogates. Targets are sorted and deduplicated.
+    ranges: Vec<(u32, u32, Vec<usize>)>,
+}
+
+impl RangeIndex {
+    /// Epsilon transitions are ignored.
+    pub fn new(transitions: &[(CharSet, usize)]) -> RangeIndex {
+        // Sweep over range starts and ends, maintaining the targets of the ranges that cover the
+        // current position (with counts, as ranges of different transitions to the same target
+        // can overlap)
+        let mut events: Vec<(u32, bool, usize)> = vec![];
+        for &(ref cs, target) in transitions {
+            if *cs == CharSet::Epsilon {
+                continue;
+            }
+            for (lo, hi) in cs.to_ranges() {
+                events.push((lo as u32, true, target));
+                if hi != ::std::char::MAX {
+                    events.push((hi as u32 + 1, false, target));
+                }
+            }
+        }
+        events.sort();
+
+        let mut ranges: Vec<(u32, u32, Vec<usize>)> = vec![];
+        let mut active: BTreeMap<usize, usize> = BTreeMap::new();
+        let mut i = 0;
+        while i < events.len() {
+            let pos = events[i].0;
+            while i < events.len() && events[i].0 == pos {
+                let (_, start, target) = events[i];
+                if start {
+                    *active.entry(target).or_insert(0) += 1;
+                } else {
+                    let remove = {
+                        let count = active.get_mut(&target).unwrap();
+                        *count -= 1;
+                        *count == 0
+                    };
+                    if remove {
+                        active.remove(&target);
+                    }
+                }
+                i += 1;
+            }
+
+            // The range ends right before the next event, or at the last char
+            let hi = if i < events.len() { events[i].0 - 1 } else { ::std::char::MAX as u32 };
+            if active.is_empty() {
+                continue;
+            }
+            let targets: Vec<usize> = active.keys().cloned().collect();
+            let extends_last = match ranges.last() {
+                Some(&(_, last_hi, ref last_targets)) => last_hi + 1 == pos && *last_targets == targets,
+                None => false,
+            };
+            if extends_last {
+                ranges.last_mut().unwrap().1 = hi;
+            } else {
+                ranges.push((pos, hi, targets));
+            }
+        }
+
+        RangeIndex { ranges: ranges }
+    }
+
+    /// Returns the targets of transitions on `c`.
+    pub fn targets(&self, c: char) -> &[usize] {
+        let targets: &[usize] = match find_range(&self.ranges, c as u32, |&(lo, hi, _)| (lo, hi)) {
+            Some(&(_, _, ref targets)) => targets,
+            None => &[],
+        };
+        #[cfg(feature = "profile")]
+        ::profile::record_lookup(!targets.is_empty());
+        targets
+    }
+}
+
+/// Returns the range containing `c` in `ranges`, which are disjoint and sorted by their start.
+/// `bounds` returns the inclusive bounds of a range.
+pub fn find_range<T, F: Fn(&T) -> (u32, u32)>(ranges: &[T], c: u32, bounds: F) -> Option<&T> {
+    // Index of the first range that starts after `c`
+    let idx = match ranges.binary_search_by(|range| bounds(range).0.cmp(&c)) {
+        Ok(idx) => idx + 1,
+        Err(idx) => idx,
+    };
+    if idx == 0 {
+        return None;
+    }
+    let range = &ranges[idx - 1];
+    if c <= bounds(range).1 {
+        Some(range)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::{BTreeMap, HashSet};
+use std::str::Chars;
+
+use NFA;
+
+/// Kinds of epsilon edges added by `NFABuilder` for `Star` and `Plus` loops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopEdge {
+    /// From a state before a `Star` loop to the loop state. Starts a new run of the loop.
+    Entry,
+
+    /// From a state before a `Plus` loop to the loop state. The states after a `Plus` are the
+    /// ends of the loop body rather than the loop state, so the last repetition doesn't take a
+    /// `Back` edge. This edge counts the first repetition instead.
+    PlusEntry,
+
+    /// From the end of the loop body back to the loop state. Taken once per repetition.
+    Back,
+}
+
+// A state, and the number of times each loop (identified by its loop state) was repeated on the
+// way to it
+type Config = (usize, BTreeMap<usize, usize>);
+
+impl<D> NFA<D> {
+    /// Like `run`, but rejects the input if matching it needs a `Star` or `Plus` loop to be
+    /// repeated more than `cap` times in a row. A loop nested in another loop is counted from
+    /// zero in each iteration of the outer loop.
+    ///
+    /// Runs from the start state and doesn't update the current states. Loops are only known for
+    /// automata built with `NFABuilder`, other automata run without a cap.
+    pub fn run_with_repeat_cap(&self, chars: Chars, cap: usize) -> bool {
+        let mut configs = HashSet::new();
+        configs.insert((self.start, BTreeMap::new()));
+        configs = self.capped_closure(configs, cap);
+
+        for c in chars {
+            let mut next_configs = HashSet::new();
+            for (state, counts) in configs {
+                for target in self.char_targets(state, c) {
+                    next_configs.insert((*target, counts.clone()));
+                }
+            }
+            if next_configs.is_empty() {
+                return false;
+            }
+            configs = self.capped_closure(next_configs, cap);
+        }
+
+        configs.iter().any(|&(state, _)| self.accepting.contains_key(&state))
+    }
+
+    // Epsilon closure of `configs`, dropping the configurations that repeat a loop more than `cap`
+    // times. Terminates as the counts are bounded by `cap`.
+    fn capped_closure(&self, mut closure: HashSet<Config>, cap: usize) -> HashSet<Config> {
+        let work = closure.iter().cloned().collect();
+        self.follow_epsilons(work, |&(state, _)| state, |&(state, ref counts), target| {
+            let mut counts = counts.clone();
+            match self.loop_edges.get(&(state, target)) {
+                None => {}
+                Some(&LoopEdge::Entry) => {
+                    counts.remove(&target);
+                }
+                Some(&LoopEdge::PlusEntry) => {
+                    if cap == 0 {
+                        return None;
+                    }
+                    counts.insert(target, 1);
+                }
+                Some(&LoopEdge::Back) => {
+                    let count = counts.entry(target).or_insert(0);
+                    *count += 1;
+                    if *count > cap {
+                        return None;
+                    }
+                }
+            }
+            let config = (target, counts);
+            if closure.insert(config.clone()) { Some(config) } else { None }
+        });
+        closure
+    }
+}
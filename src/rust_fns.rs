@@ -0,0 +1,71 @@
+fn ab(input: &str) -> bool {
+    let mut state: usize = 0;
+    for c in input.chars() {
+        state = match (state, c) {
+            (0, 'a') => 1,
+            (1, 'b') => 2,
+            _ => return false,
+        };
+    }
+    match state {
+        1 | 2 => true,
+        _ => false,
+    }
+}
+fn no_quotes(input: &str) -> bool {
+    let mut state: usize = 0;
+    for c in input.chars() {
+        state = match (state, c) {
+            (0, '\0' ..= '&') | (0, '(' ..= '\u{10ffff}') => 1,
+            (1, '\0' ..= '&') | (1, '(' ..= '\u{10ffff}') => 1,
+            _ => return false,
+        };
+    }
+    match state {
+        1 => true,
+        _ => false,
+    }
+}
+fn ends_with_abb(input: &str) -> bool {
+    let mut state: usize = 0;
+    for c in input.chars() {
+        state = match (state, c) {
+            (0, 'a') => 1,
+            (0, 'b') => 2,
+            (1, 'a') => 1,
+            (1, 'b') => 3,
+            (2, 'a') => 1,
+            (2, 'b') => 2,
+            (3, 'a') => 1,
+            (3, 'b') => 4,
+            (4, 'a') => 1,
+            (4, 'b') => 2,
+            _ => return false,
+        };
+    }
+    match state {
+        4 => true,
+        _ => false,
+    }
+}
+fn address(input: &str) -> bool {
+    let mut state: usize = 0;
+    for c in input.chars() {
+        state = match (state, c) {
+            (0, '0' ..= '9') | (0, 'a' ..= 'z') => 1,
+            (1, '0' ..= '9') | (1, 'a' ..= 'z') => 1,
+            (1, '@') => 2,
+            (2, '0' ..= '9') | (2, 'a' ..= 'z') => 3,
+            (3, '0' ..= '9') | (3, 'a' ..= 'z') => 3,
+            (3, '.') => 4,
+            (4, '0' ..= '9') | (4, 'a' ..= 'z') => 5,
+            (5, '.') => 4,
+            (5, '0' ..= '9') | (5, 'a' ..= 'z') => 5,
+            _ => return false,
+        };
+    }
+    match state {
+        5 => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use NFA;
+
+/// Which match `NFA::search` and `NFA::find_iter` report when several matches start at the
+/// leftmost position.
+///
+/// With `a|ab` over `"ab"`, `LeftmostLongest` matches `"ab"` and `LeftmostShortest` matches `"a"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchPolicy {
+    /// The longest match, as in POSIX.
+    LeftmostLongest,
+
+    /// The shortest match. This is not Perl's leftmost-first semantics, the order of alternatives
+    /// (see `NFA::accepting_priority`) is not considered: `ab|a` also matches `"a"`.
+    LeftmostShortest,
+}
+
+impl<D> NFA<D> {
+    /// Returns the byte range of the leftmost match in `input`, using `policy` to pick among the
+    /// matches that start there. Doesn't update the current states.
+    ///
+    /// The input is scanned once: a match attempt is started at each position until a match is
+    /// found, and the attempts are run together, each state remembering the leftmost attempt that
+    /// reached it. So a search is O(n * m) (n being the input length and m the number of states),
+    /// rather than running the automaton again from every position.
+    pub fn search(&self, input: &str, policy: MatchPolicy) -> Option<Range<usize>> {
+        // Active states, mapped to the start of the leftmost attempt that reached them
+        let mut threads: HashMap<usize, usize> = HashMap::new();
+        let mut best: Option<Range<usize>> = None;
+
+        let positions = input.char_indices().map(Some).chain(Some(None));
+        for next in positions {
+            let pos = next.map(|(i, _)| i).unwrap_or(input.len());
+
+            if best.is_none() {
+                for state in self.initial_states.iter() {
+                    threads.entry(*state).or_insert(pos);
+                }
+            }
+
+            let accepted = threads.iter()
+                .filter(|&(state, _)| self.accepting.contains_key(state))
+                .map(|(_, start)| *start)
+                .min();
+            if let Some(start) = accepted {
+                let better = match best {
+                    None => true,
+                    Some(ref best) => start < best.start ||
+                        (start == best.start && policy == MatchPolicy::LeftmostLongest),
+                };
+                if better {
+                    best = Some(start .. pos);
+                }
+            }
+
+            // Only the attempts that can still give a better match are kept
+            if let Some(ref best) = best {
+                threads.retain(|_, start| *start < best.start ||
+                               (*start == best.start && policy == MatchPolicy::LeftmostLongest));
+            }
+
+            let c = match next {
+                None => break,
+                Some((_, c)) => c,
+            };
+            if threads.is_empty() && best.is_some() {
+                break;
+            }
+
+            let mut next_threads: HashMap<usize, usize> = HashMap::with_capacity(threads.len());
+            for (state, start) in threads.iter() {
+                for target in self.char_targets(*state, c) {
+                    keep_leftmost(&mut next_threads, *target, *start);
+                }
+            }
+            let work = next_threads.iter().map(|(state, start)| (*state, *start)).collect();
+            self.follow_epsilons(work, |&(state, _)| state, |&(_, start), target| {
+                if keep_leftmost(&mut next_threads, target, start) { Some((target, start)) } else { None }
+            });
+            threads = next_threads;
+        }
+
+        best
+    }
+
+    /// Returns an iterator over the non-overlapping matches in `input`, from left to right. After
+    /// an empty match the search continues from the next char.
+    pub fn find_iter<'a>(&'a mut self, input: &'a str, policy: MatchPolicy) -> FindIter<'a, D> {
+        FindIter {
+            nfa: self,
+            input: input,
+            pos: 0,
+            policy: policy,
+        }
+    }
+}
+
+// Records that an attempt starting at `start` reached `state`. Returns whether it's the leftmost
+// one so far.
+fn keep_leftmost(threads: &mut HashMap<usize, usize>, state: usize, start: usize) -> bool {
+    match threads.entry(state) {
+        Entry::Occupied(mut ent) => {
+            if *ent.get() <= start {
+                return false;
+            }
+            ent.insert(start);
+        }
+        Entry::Vacant(ent) => {
+            ent.insert(start);
+        }
+    }
+    true
+}
+
+/// Iterator returned by `NFA::find_iter`.
+pub struct FindIter<'a, D: 'a = ()> {
+    nfa: &'a mut NFA<D>,
+    input: &'a str,
+    pos: usize,
+    policy: MatchPolicy,
+}
+
+impl<'a, D> Iterator for FindIter<'a, D> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Range<usize>> {
+        if self.pos > self.input.len() {
+            return None;
+        }
+        let m = match self.nfa.search(&self.input[self.pos ..], self.policy) {
+            None => {
+                self.pos = self.input.len() + 1;
+                return None;
+            }
+            Some(m) => self.pos + m.start .. self.pos + m.end,
+        };
+        self.pos = if m.start == m.end {
+            // Skip a char to make progress after an empty match
+            match self.input[m.end ..].chars().next() {
+                Some(c) => m.end + c.len_utf8(),
+                None => self.input.len() + 1,
+            }
+        } else {
+            m.end
+        };
+        Some(m)
+    }
+}
@@ -0,0 +1,143 @@
+use visitor::RegexFolder;
+use {CharSet, Regex};
+
+impl Regex {
+    /// Returns an equivalent regex with redundant structure removed: charsets are simplified,
+    /// `Eps`s in sequences are dropped, nested repetitions are collapsed (e.g. `(a*)?` becomes
+    /// `a*`), duplicate alternatives are removed, and sequences that can't match anything become
+    /// the empty charset. Alternatives that can't match anything are removed.
+    pub fn simplify(&self) -> Regex {
+        self.fold(&mut Simplifier)
+    }
+
+    /// Returns true if the regexes are structurally equal after `simplify`, which means they
+    /// match the same language. This is much cheaper than comparing the languages, but only
+    /// catches the differences that `simplify` removes: `false` doesn't mean the languages are
+    /// different (e.g. `a|b` and `b|a`).
+    pub fn same_after_simplify(&self, other: &Regex) -> bool {
+        self.simplify() == other.simplify()
+    }
+
+    /// Returns the regex with all nested `Seq`s flattened into right-nested chains, without
+    /// `Eps`s. E.g. `Seq(Seq(a, b), c)` becomes `Seq(a, Seq(b, c))`.
+    pub fn normalize_seq(&self) -> Regex {
+        self.fold(&mut SeqNormalizer)
+    }
+}
+
+struct Simplifier;
+
+// The canonical regex that doesn't match anything
+fn never() -> Regex {
+    Regex::CharSet(CharSet::Union(vec![]))
+}
+
+impl RegexFolder for Simplifier {
+    fn fold_charset(&mut self, cs: &CharSet) -> Regex {
+        if cs.is_empty() {
+            never()
+        } else {
+            Regex::CharSet(cs.simplify())
+        }
+    }
+
+    fn fold_seq(&mut self, r1: &Regex, r2: &Regex) -> Regex {
+        match (r1.fold(self), r2.fold(self)) {
+            (ref r1, ref r2) if *r1 == never() || *r2 == never() => never(),
+            (Regex::Eps, r) | (r, Regex::Eps) => r,
+            (r1, r2) => Regex::seq(r1, r2),
+        }
+    }
+
+    fn fold_or(&mut self, r1: &Regex, r2: &Regex) -> Regex {
+        // Flatten the alternation so that duplicates are found across nested `Or`s
+        let mut alts = vec![];
+        flatten_or(r1.fold(self), &mut alts);
+        flatten_or(r2.fold(self), &mut alts);
+
+        let mut unique: Vec<Regex> = vec![];
+        for alt in alts {
+            if alt != never() && !unique.contains(&alt) {
+                unique.push(alt);
+            }
+        }
+
+        let mut ret = match unique.pop() {
+            None => { return never(); }
+            Some(alt) => alt,
+        };
+        while let Some(alt) = unique.pop() {
+            ret = Regex::or(alt, ret);
+        }
+        ret
+    }
+
+    fn fold_star(&mut self, r: &Regex) -> Regex {
+        match r.fold(self) {
+            Regex::Eps => Regex::Eps,
+            // (r*)*, (r+)*, (r?)* = r*
+            Regex::Star(r) | Regex::Plus(r) | Regex::Ques(r) => Regex::Star(r),
+            r => Regex::star(r),
+        }
+    }
+
+    fn fold_plus(&mut self, r: &Regex) -> Regex {
+        match r.fold(self) {
+            Regex::Eps => Regex::Eps,
+            // (r*)+ = r*, (r+)+ = r+, (r?)+ = r*
+            Regex::Star(r) | Regex::Ques(r) => Regex::Star(r),
+            r @ Regex::Plus(_) => r,
+            r => Regex::plus(r),
+        }
+    }
+
+    fn fold_ques(&mut self, r: &Regex) -> Regex {
+        match r.fold(self) {
+            Regex::Eps => Regex::Eps,
+            // (r*)? = r*, (r+)? = r*, (r?)? = r?
+            Regex::Star(r) | Regex::Plus(r) => Regex::Star(r),
+            r @ Regex::Ques(_) => r,
+            r => Regex::ques(r),
+        }
+    }
+}
+
+fn flatten_or(regex: Regex, alts: &mut Vec<Regex>) {
+    match regex {
+        Regex::Or(r1, r2) => {
+            flatten_or(*r1, alts);
+            flatten_or(*r2, alts);
+        }
+        r => alts.push(r),
+    }
+}
+
+struct SeqNormalizer;
+
+impl RegexFolder for SeqNormalizer {
+    fn fold_seq(&mut self, r1: &Regex, r2: &Regex) -> Regex {
+        let mut elems = vec![];
+        flatten_seq(r1.fold(self), &mut elems);
+        flatten_seq(r2.fold(self), &mut elems);
+
+        let mut ret = match elems.pop() {
+            None => { return Regex::Eps; }
+            Some(r) => r,
+        };
+        while let Some(r) = elems.pop() {
+            ret = Regex::seq(r, ret);
+        }
+        ret
+    }
+}
+
+fn flatten_seq(regex: Regex, elems: &mut Vec<Regex>) {
+    match regex {
+        Regex::Seq(r1, r2) => {
+            flatten_seq(*r1, elems);
+            flatten_seq(*r2, elems);
+        }
+        Regex::Eps => {}
+        r => elems.push(r),
+    }
+}
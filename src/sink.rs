@@ -0,0 +1,74 @@
+use std::collections::{HashMap, HashSet};
+
+use range_index::RangeIndex;
+use {CharSet, NFA};
+
+impl<D> NFA<D> {
+    /// Makes the automaton complete over `alphabet`: adds a single non-accepting sink state that
+    /// loops on `alphabet`, and routes the chars of `alphabet` that have no transition from a
+    /// state to it. Dead states (the ones that can't reach an accepting state) are collapsed into
+    /// the sink. Returns the sink state. Accepted strings don't change.
+    ///
+    /// This is meant for deterministic automata (see `determinize`): epsilon transitions are kept,
+    /// but a state whose chars are only handled after an epsilon transition is still routed to the
+    /// sink on them. Resets the automaton.
+    pub fn add_sink_and_complete(&mut self, alphabet: &CharSet) -> usize {
+        // States that can reach an accepting state
+        let mut reverse: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (state, ts) in self.transitions.iter() {
+            for &(_, target) in ts {
+                reverse.entry(target).or_default().push(*state);
+            }
+        }
+        let mut live: HashSet<usize> = self.accepting.keys().cloned().collect();
+        let mut work: Vec<usize> = live.iter().cloned().collect();
+        while let Some(state) = work.pop() {
+            if let Some(sources) = reverse.get(&state) {
+                for source in sources {
+                    if live.insert(*source) {
+                        work.push(*source);
+                    }
+                }
+            }
+        }
+
+        // Drop the dead states, so that their chars go to the sink
+        self.transitions.retain(|state, _| live.contains(state));
+        for ts in self.transitions.values_mut() {
+            ts.retain(|&(_, target)| live.contains(&target));
+        }
+
+        let sink = self.transitions.keys().chain(self.accepting.keys()).chain(Some(&self.start))
+            .cloned().max().unwrap() + 1;
+
+        // States reachable from the start state
+        let mut reachable: HashSet<usize> = HashSet::new();
+        reachable.insert(self.start);
+        let mut work = vec![self.start];
+        while let Some(state) = work.pop() {
+            if let Some(ts) = self.transitions.get(&state) {
+                for &(_, target) in ts {
+                    if reachable.insert(target) {
+                        work.push(target);
+                    }
+                }
+            }
+        }
+
+        for state in reachable {
+            let ts = self.transitions.entry(state).or_default();
+            let covered = CharSet::Union(ts.iter().filter(|&&(ref cs, _)| *cs != CharSet::Epsilon)
+                                         .map(|&(ref cs, _)| cs.clone()).collect());
+            let missing = CharSet::Diff { include: Box::new(alphabet.clone()), exclude: Box::new(covered) }.canonical();
+            if !missing.is_empty() {
+                ts.push((missing, sink));
+            }
+        }
+        self.transitions.insert(sink, vec![(alphabet.clone(), sink)]);
+
+        self.range_index = self.transitions.iter().map(|(s, ts)| (*s, RangeIndex::new(ts))).collect();
+        self.class_table = None;
+        self.compute_initial_states();
+        sink
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use NFA;
+
+/// Finds matches of an NFA in an endless stream of chars, fed one at a time, that are not longer
+/// than a window of the last `window` chars. A match can start at any position, so each char
+/// starts a new run of the automaton ("thread") from its start state. Threads that reach the same
+/// state are merged, keeping the one that started later, so the work per char is bounded by the
+/// number of states.
+///
+/// Empty matches are not reported.
+pub struct SlidingMatcher {
+    nfa: NFA,
+    window: usize,
+    // Number of chars fed so far
+    pos: usize,
+    // Active states, mapped to the position where the latest thread in the state started
+    threads: HashMap<usize, usize>,
+}
+
+impl SlidingMatcher {
+    pub fn new(nfa: NFA, window: usize) -> SlidingMatcher {
+        SlidingMatcher {
+            nfa: nfa,
+            window: window,
+            pos: 0,
+            threads: HashMap::new(),
+        }
+    }
+
+    /// Feeds the next char. If a match ends with this char, returns the shortest one as a range of
+    /// char positions in the stream (counting from 0).
+    pub fn push(&mut self, c: char) -> Option<Range<usize>> {
+        // Start a new thread at this position
+        for state in self.nfa.initial_states.iter() {
+            self.threads.insert(*state, self.pos);
+        }
+
+        let mut threads: HashMap<usize, usize> = HashMap::with_capacity(self.threads.len());
+        for (state, start) in self.threads.iter() {
+            for target in self.nfa.char_targets(*state, c) {
+                add_thread(&mut threads, *target, *start);
+            }
+        }
+        self.pos += 1;
+
+        // Drop the threads that can't end in the window anymore
+        let window_start = self.pos.saturating_sub(self.window);
+        threads.retain(|_, start| *start >= window_start);
+        self.threads = threads;
+        self.take_epsilons();
+
+        let pos = self.pos;
+        self.threads.iter()
+            .filter(|&(state, _)| self.nfa.accepting.contains_key(state))
+            .map(|(_, start)| *start)
+            .max()
+            .map(|start| start .. pos)
+    }
+
+    /// Forgets the chars fed so far, and starts counting positions from 0 again.
+    pub fn reset(&mut self) {
+        self.pos = 0;
+        self.threads.clear();
+    }
+
+    fn take_epsilons(&mut self) {
+        let work = self.threads.iter().map(|(state, start)| (*state, *start)).collect();
+        let threads = &mut self.threads;
+        self.nfa.follow_epsilons(work, |&(state, _)| state, |&(_, start), target| {
+            if add_thread(threads, target, start) { Some((target, start)) } else { None }
+        });
+    }
+}
+
+// Adds a thread that started at `start` to `state`, unless there's already a thread there that
+// started at the same position or later. Returns whether the thread was added.
+fn add_thread(threads: &mut HashMap<usize, usize>, state: usize, start: usize) -> bool {
+    match threads.get(&state) {
+        Some(&existing) if existing >= start => false,
+        _ => {
+            threads.insert(state, start);
+            true
+        }
+    }
+}
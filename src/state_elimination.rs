@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use {CharSet, NFA, Regex};
+
+impl<D> NFA<D> {
+    /// Returns a regex that accepts the same strings as the automaton, using the state
+    /// elimination method. The result is equivalent to the automaton, but it's not minimal, and
+    /// it can be exponentially large in the number of states.
+    ///
+    /// Accepting states of all rules are treated the same, so rule tags are lost.
+    pub fn to_regex(&self) -> Regex {
+        // Collect all states, then add a new start state and a new final state, so that the start
+        // state has no incoming edges and there's a single accepting state without outgoing edges
+        let mut states: BTreeSet<usize> = BTreeSet::new();
+        states.insert(self.start);
+        for (state, ts) in self.transitions.iter() {
+            states.insert(*state);
+            for &(_, target) in ts {
+                states.insert(target);
+            }
+        }
+        states.extend(self.accepting.keys());
+        let start = states.iter().next_back().unwrap() + 1;
+        let end = start + 1;
+
+        // Edges between states, labeled with regexes. Parallel edges are combined with `Or`.
+        let mut edges: BTreeMap<(usize, usize), Regex> = BTreeMap::new();
+        add_edge(&mut edges, start, self.start, Regex::Eps);
+        for state in self.accepting.keys() {
+            add_edge(&mut edges, *state, end, Regex::Eps);
+        }
+        for (state, ts) in self.transitions.iter() {
+            for &(ref cs, target) in ts {
+                let label = if *cs == CharSet::Epsilon { Regex::Eps } else { Regex::CharSet(cs.clone()) };
+                add_edge(&mut edges, *state, target, label);
+            }
+        }
+
+        // Eliminate the states one by one, replacing each path p -> q -> r through an eliminated
+        // state q with an edge p -> r labeled R(p, q) R(q, q)* R(q, r)
+        for q in states {
+            let self_loop = edges.remove(&(q, q)).map(Regex::star);
+
+            let mut incoming = vec![];
+            let mut outgoing = vec![];
+            let keys: Vec<(usize, usize)> = edges.keys().cloned().collect();
+            for (from, to) in keys {
+                if to == q {
+                    incoming.push((from, edges.remove(&(from, to)).unwrap()));
+                } else if from == q {
+                    outgoing.push((to, edges.remove(&(from, to)).unwrap()));
+                }
+            }
+
+            for &(p, ref r_pq) in &incoming {
+                for &(r, ref r_qr) in &outgoing {
+                    let mut label = r_pq.clone();
+                    if let Some(ref r_qq) = self_loop {
+                        label = seq(label, r_qq.clone());
+                    }
+                    label = seq(label, r_qr.clone());
+                    add_edge(&mut edges, p, r, label);
+                }
+            }
+        }
+
+        match edges.remove(&(start, end)) {
+            Some(regex) => regex.simplify(),
+            // No accepting state is reachable
+            None => Regex::CharSet(CharSet::Union(vec![])),
+        }
+    }
+}
+
+fn add_edge(edges: &mut BTreeMap<(usize, usize), Regex>, from: usize, to: usize, label: Regex) {
+    let label = match edges.remove(&(from, to)) {
+        None => label,
+        Some(old) => Regex::or(old, label),
+    };
+    edges.insert((from, to), label);
+}
+
+fn seq(r1: Regex, r2: Regex) -> Regex {
+    match (r1, r2) {
+        (Regex::Eps, r) | (r, Regex::Eps) => r,
+        (r1, r2) => Regex::seq(r1, r2),
+    }
+}
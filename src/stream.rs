@@ -0,0 +1,42 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+
+use NFA;
+
+impl<D> NFA<D> {
+    /// Returns a future that feeds the chars of `stream` to the automaton as they arrive, and
+    /// resolves to whether the automaton accepts when the stream ends. Like `run`, starts from the
+    /// current states.
+    ///
+    /// The future resolves to `false` as soon as the automaton dies, without polling the rest of
+    /// the stream.
+    pub fn run_stream<S: Stream<Item = char> + Unpin>(&mut self, stream: S) -> RunStream<S, D> {
+        RunStream { nfa: self, stream: stream }
+    }
+}
+
+/// Future returned by `NFA::run_stream`.
+pub struct RunStream<'a, S, D: 'a = ()> {
+    nfa: &'a mut NFA<D>,
+    stream: S,
+}
+
+impl<'a, S: Stream<Item = char> + Unpin, D> ::std::future::Future for RunStream<'a, S, D> {
+    type Output = bool;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<bool> {
+        let this = self.get_mut();
+        loop {
+            if this.nfa.cur_states.is_empty() {
+                return Poll::Ready(false);
+            }
+            match Pin::new(&mut this.stream).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(this.nfa.check_accepting()),
+                Poll::Ready(Some(c)) => this.nfa.step(c),
+            }
+        }
+    }
+}
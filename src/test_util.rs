@@ -0,0 +1,24 @@
+use NFA;
+
+/// Checks that the automata agree on all strings over `alphabet` of length up to `max_len`.
+pub fn assert_accepts_same(a: &NFA, b: &NFA, alphabet: &[char], max_len: usize) {
+    let mut a = a.clone();
+    let mut b = b.clone();
+    let mut strings = vec![String::new()];
+    for len in 0 .. max_len + 1 {
+        let mut next = vec![];
+        for s in strings {
+            a.reset();
+            b.reset();
+            assert_eq!(a.run(s.chars()), b.run(s.chars()), "{:?}", s);
+            if len < max_len {
+                for c in alphabet {
+                    let mut s = s.clone();
+                    s.push(*c);
+                    next.push(s);
+                }
+            }
+        }
+        strings = next;
+    }
+}
@@ -0,0 +1,100 @@
+use {CharSet, Regex};
+
+/// A traversal over a `Regex`. The default methods visit the children (left to right), so an
+/// implementation only needs to override the methods for the nodes it's interested in. An
+/// overriding method that still wants to visit the children should call `accept` on them.
+pub trait RegexVisitor {
+    fn visit_eps(&mut self) {}
+
+    fn visit_charset(&mut self, _cs: &CharSet) {}
+
+    fn visit_seq(&mut self, r1: &Regex, r2: &Regex) {
+        r1.accept(self);
+        r2.accept(self);
+    }
+
+    fn visit_or(&mut self, r1: &Regex, r2: &Regex) {
+        r1.accept(self);
+        r2.accept(self);
+    }
+
+    fn visit_star(&mut self, r: &Regex) {
+        r.accept(self);
+    }
+
+    fn visit_plus(&mut self, r: &Regex) {
+        r.accept(self);
+    }
+
+    fn visit_ques(&mut self, r: &Regex) {
+        r.accept(self);
+    }
+
+    fn visit_count_group(&mut self, _group: usize, r: &Regex) {
+        r.accept(self);
+    }
+}
+
+/// A transformation of a `Regex` into a new one, built bottom-up. The default methods fold the
+/// children and rebuild the same node, so by default a folder copies the regex.
+pub trait RegexFolder {
+    fn fold_eps(&mut self) -> Regex {
+        Regex::Eps
+    }
+
+    fn fold_charset(&mut self, cs: &CharSet) -> Regex {
+        Regex::CharSet(cs.clone())
+    }
+
+    fn fold_seq(&mut self, r1: &Regex, r2: &Regex) -> Regex {
+        Regex::seq(r1.fold(self), r2.fold(self))
+    }
+
+    fn fold_or(&mut self, r1: &Regex, r2: &Regex) -> Regex {
+        Regex::or(r1.fold(self), r2.fold(self))
+    }
+
+    fn fold_star(&mut self, r: &Regex) -> Regex {
+        Regex::star(r.fold(self))
+    }
+
+    fn fold_plus(&mut self, r: &Regex) -> Regex {
+        Regex::plus(r.fold(self))
+    }
+
+    fn fold_ques(&mut self, r: &Regex) -> Regex {
+        Regex::ques(r.fold(self))
+    }
+
+    fn fold_count_group(&mut self, group: usize, r: &Regex) -> Regex {
+        Regex::count_group(group, r.fold(self))
+    }
+}
+
+impl Regex {
+    pub fn accept<V: RegexVisitor + ?Sized>(&self, v: &mut V) {
+        match self {
+            &Regex::Eps => v.visit_eps(),
+            &Regex::CharSet(ref cs) => v.visit_charset(cs),
+            &Regex::Seq(ref r1, ref r2) => v.visit_seq(r1, r2),
+            &Regex::Or(ref r1, ref r2) => v.visit_or(r1, r2),
+            &Regex::Star(ref r) => v.visit_star(r),
+            &Regex::Plus(ref r) => v.visit_plus(r),
+            &Regex::Ques(ref r) => v.visit_ques(r),
+            &Regex::CountGroup(group, ref r) => v.visit_count_group(group, r),
+        }
+    }
+
+    pub fn fold<F: RegexFolder + ?Sized>(&self, f: &mut F) -> Regex {
+        match self {
+            &Regex::Eps => f.fold_eps(),
+            &Regex::CharSet(ref cs) => f.fold_charset(cs),
+            &Regex::Seq(ref r1, ref r2) => f.fold_seq(r1, r2),
+            &Regex::Or(ref r1, ref r2) => f.fold_or(r1, r2),
+            &Regex::Star(ref r) => f.fold_star(r),
+            &Regex::Plus(ref r) => f.fold_plus(r),
+            &Regex::Ques(ref r) => f.fold_ques(r),
+            &Regex::CountGroup(group, ref r) => f.fold_count_group(group, r),
+        }
+    }
+}